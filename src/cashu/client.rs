@@ -0,0 +1,180 @@
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use url::Url;
+
+use super::dhke::hash_to_curve;
+use super::error::CashuError;
+use super::model::{
+    BlindSignature, BlindedMessage, CheckStateResponse, KeySetsResponse, KeysResponse, MeltQuote,
+    MintQuote, Proof, ProofState, SignaturesResponse,
+};
+
+/// Client for the NUT v1 mint API
+#[derive(Clone)]
+pub struct CashuMintClient {
+    mint_url: Url,
+    reqwest_client: reqwest::Client,
+}
+
+impl CashuMintClient {
+    pub fn new(mint_url: Url) -> Result<CashuMintClient, CashuError> {
+        let reqwest_client = reqwest::Client::builder().build()?;
+
+        Ok(CashuMintClient {
+            mint_url,
+            reqwest_client,
+        })
+    }
+
+    fn endpoint(&self, endpoint: &str) -> Result<Url, CashuError> {
+        // keep any path of the mint url, e.g. https://mint.host/cashu/
+        let mut url = self.mint_url.clone();
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Ok(url.join(endpoint)?)
+    }
+
+    async fn parse_response<T: DeserializeOwned>(
+        response: reqwest::Response,
+    ) -> Result<T, CashuError> {
+        let status = response.status();
+        let body = response.text().await?;
+
+        if status != StatusCode::OK {
+            // mints send `{"detail": "...", "code": ...}` on errors
+            let detail = serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|error| error["detail"].as_str().map(str::to_owned))
+                .unwrap_or(body);
+            return Err(CashuError::MintError(format!("{status}: {detail}")));
+        }
+
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    pub async fn make_get<T: DeserializeOwned>(&self, endpoint: &str) -> Result<T, CashuError> {
+        let response = self
+            .reqwest_client
+            .get(self.endpoint(endpoint)?)
+            .send()
+            .await?;
+        Self::parse_response(response).await
+    }
+
+    pub async fn make_post<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        body: &serde_json::Value,
+    ) -> Result<T, CashuError> {
+        let response = self
+            .reqwest_client
+            .post(self.endpoint(endpoint)?)
+            .json(body)
+            .send()
+            .await?;
+        Self::parse_response(response).await
+    }
+}
+
+impl CashuMintClient {
+    /// Keys of the active keysets
+    pub async fn get_keys(&self) -> Result<KeysResponse, CashuError> {
+        self.make_get("v1/keys").await
+    }
+
+    /// Keys of a single keyset, which may be inactive
+    pub async fn get_keyset_keys(&self, id: &str) -> Result<KeysResponse, CashuError> {
+        self.make_get(&format!("v1/keys/{id}")).await
+    }
+
+    pub async fn get_keysets(&self) -> Result<KeySetsResponse, CashuError> {
+        self.make_get("v1/keysets").await
+    }
+
+    pub async fn mint_quote(&self, amount: u64, unit: &str) -> Result<MintQuote, CashuError> {
+        self.make_post(
+            "v1/mint/quote/bolt11",
+            &serde_json::json!({ "amount": amount, "unit": unit }),
+        )
+        .await
+    }
+
+    pub async fn mint_quote_state(&self, quote: &str) -> Result<MintQuote, CashuError> {
+        self.make_get(&format!("v1/mint/quote/bolt11/{quote}"))
+            .await
+    }
+
+    pub async fn mint(
+        &self,
+        quote: &str,
+        outputs: &[BlindedMessage],
+    ) -> Result<Vec<BlindSignature>, CashuError> {
+        let response: SignaturesResponse = self
+            .make_post(
+                "v1/mint/bolt11",
+                &serde_json::json!({ "quote": quote, "outputs": outputs }),
+            )
+            .await?;
+        Ok(response.signatures)
+    }
+
+    pub async fn melt_quote(&self, bolt11: &str, unit: &str) -> Result<MeltQuote, CashuError> {
+        self.make_post(
+            "v1/melt/quote/bolt11",
+            &serde_json::json!({ "request": bolt11, "unit": unit }),
+        )
+        .await
+    }
+
+    pub async fn melt(
+        &self,
+        quote: &str,
+        inputs: &[Proof],
+        outputs: &[BlindedMessage],
+    ) -> Result<MeltQuote, CashuError> {
+        self.make_post(
+            "v1/melt/bolt11",
+            &serde_json::json!({ "quote": quote, "inputs": inputs, "outputs": outputs }),
+        )
+        .await
+    }
+
+    pub async fn swap(
+        &self,
+        inputs: &[Proof],
+        outputs: &[BlindedMessage],
+    ) -> Result<Vec<BlindSignature>, CashuError> {
+        let response: SignaturesResponse = self
+            .make_post(
+                "v1/swap",
+                &serde_json::json!({ "inputs": inputs, "outputs": outputs }),
+            )
+            .await?;
+        Ok(response.signatures)
+    }
+
+    /// Spend states of `proofs`, in the same order
+    pub async fn check_state(&self, proofs: &[Proof]) -> Result<Vec<ProofState>, CashuError> {
+        let ys = proofs
+            .iter()
+            .map(|proof| Ok(hash_to_curve(proof.secret.as_bytes())?.to_string()))
+            .collect::<Result<Vec<_>, CashuError>>()?;
+        let response: CheckStateResponse = self
+            .make_post("v1/checkstate", &serde_json::json!({ "Ys": ys }))
+            .await?;
+        if response.states.len() != ys.len()
+            || response
+                .states
+                .iter()
+                .zip(&ys)
+                .any(|(state, y)| &state.y != y)
+        {
+            return Err(CashuError::MintError(
+                "checkstate answered for other proofs".to_owned(),
+            ));
+        }
+
+        Ok(response.states)
+    }
+}
@@ -0,0 +1,151 @@
+use secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+
+use super::error::CashuError;
+
+const DOMAIN_SEPARATOR: &[u8] = b"Secp256k1_HashToCurve_Cashu_";
+
+/// Maps a secret to the curve point `Y` the mint signs, per NUT-00
+pub fn hash_to_curve(message: &[u8]) -> Result<PublicKey, CashuError> {
+    let message_hash = Sha256::new()
+        .chain_update(DOMAIN_SEPARATOR)
+        .chain_update(message)
+        .finalize();
+
+    for counter in 0..u32::MAX {
+        let hash = Sha256::new()
+            .chain_update(message_hash)
+            .chain_update(counter.to_le_bytes())
+            .finalize();
+        let mut point = [0x02; 33];
+        point[1..].copy_from_slice(&hash);
+        if let Ok(point) = PublicKey::from_slice(&point) {
+            return Ok(point);
+        }
+    }
+
+    // a valid x coordinate is found in a couple of tries, this is unreachable
+    Err(CashuError::Secp256k1Error(
+        secp256k1::Error::InvalidPublicKey,
+    ))
+}
+
+/// Blinds a secret as `B_ = Y + rG`, returning `B_` and the blinding factor
+pub fn blind_message(
+    secret: &[u8],
+    blinding_factor: Option<SecretKey>,
+) -> Result<(PublicKey, SecretKey), CashuError> {
+    let secp = Secp256k1::new();
+    let y = hash_to_curve(secret)?;
+    let r = blinding_factor.unwrap_or_else(|| SecretKey::new(&mut secp256k1::rand::thread_rng()));
+
+    Ok((y.combine(&r.public_key(&secp))?, r))
+}
+
+/// Unblinds the mint's signature as `C = C_ - rK`
+pub fn unblind_signature(
+    blinded_signature: &PublicKey,
+    blinding_factor: &SecretKey,
+    mint_key: &PublicKey,
+) -> Result<PublicKey, CashuError> {
+    let secp = Secp256k1::new();
+    let r_k = mint_key.mul_tweak(&secp, &Scalar::from(*blinding_factor))?;
+
+    Ok(blinded_signature.combine(&r_k.negate(&secp))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NUT-00 test vectors
+
+    fn public_key(hex: &str) -> PublicKey {
+        PublicKey::from_slice(&hex::decode(hex).unwrap()).unwrap()
+    }
+
+    fn secret_key(hex: &str) -> SecretKey {
+        SecretKey::from_slice(&hex::decode(hex).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn hashes_to_curve() {
+        let vectors = [
+            (
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "024cce997d3b518f739663b757deaec95bcd9473c30a14ac2fd04023a739d1a725",
+            ),
+            (
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "022e7158e11c9506f1aa4248bf531298daa7febd6194f003edcd9b93ade6253acf",
+            ),
+            // takes a few tries to find a point
+            (
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "026cdbe15362df59cd1dd3c9c11de8aedac2106eca69236ecd9fbe117af897be4f",
+            ),
+        ];
+        for (message, point) in vectors {
+            let y = hash_to_curve(&hex::decode(message).unwrap()).unwrap();
+            assert_eq!(y.to_string(), point, "{message}");
+        }
+    }
+
+    #[test]
+    fn blinds_messages() {
+        let vectors = [
+            (
+                "d341ee4871f1f889041e63cf0d3823c713eea6aff01e80f1719f08f9e5be98f6",
+                "99fce58439fc37412ab3468b73db0569322588f62fb3a49182d67e23d877824a",
+                "033b1a9737a40cc3fd9b6af4b723632b76a67a36782596304612a6c2bfb5197e6d",
+            ),
+            (
+                "f1aaf16c2239746f369572c0784d9dd3d032d952c2d992175873fb58fae31a60",
+                "f78476ea7cc9ade20f9e05e58a804cf19533f03ea805ece5fee88c8e2874ba50",
+                "029bdf2d716ee366eddf599ba252786c1033f47e230248a4612a5670ab931f1763",
+            ),
+        ];
+        for (secret, blinding_factor, blinded) in vectors {
+            let (b_, r) = blind_message(
+                &hex::decode(secret).unwrap(),
+                Some(secret_key(blinding_factor)),
+            )
+            .unwrap();
+            assert_eq!(b_.to_string(), blinded, "{secret}");
+            assert_eq!(r, secret_key(blinding_factor));
+        }
+
+        let one = secret_key(&format!("{:064x}", 1));
+        let (b_, _) = blind_message(b"test_message", Some(one)).unwrap();
+        assert_eq!(
+            b_.to_string(),
+            "025cc16fe33b953e2ace39653efb3e7a7049711ae1d8a2f7a9108753f1cdea742b"
+        );
+    }
+
+    #[test]
+    fn unblinds_signatures() {
+        let c = unblind_signature(
+            &public_key("02a9acc1e48c25eeeb9289b5031cc57da9fe72f3fe2861d264bdc074209b107ba2"),
+            &secret_key(&format!("{:064x}", 1)),
+            &public_key("020000000000000000000000000000000000000000000000000000000000000001"),
+        )
+        .unwrap();
+        assert_eq!(
+            c.to_string(),
+            "03c724d7e6a5443b39ac8acf11f40420adc4f99a02e7cc1b57703d9391f6d129cd"
+        );
+    }
+
+    #[test]
+    fn unblinded_signature_is_the_mint_key_times_y() {
+        let secp = Secp256k1::new();
+        let mint_key = secret_key(&format!("{:064x}", 7));
+        let (b_, r) = blind_message(b"secret", None).unwrap();
+        let c_ = b_.mul_tweak(&secp, &Scalar::from(mint_key)).unwrap();
+
+        let c = unblind_signature(&c_, &r, &mint_key.public_key(&secp)).unwrap();
+        let y = hash_to_curve(b"secret").unwrap();
+        assert_eq!(c, y.mul_tweak(&secp, &Scalar::from(mint_key)).unwrap());
+    }
+}
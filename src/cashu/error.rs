@@ -0,0 +1,52 @@
+#[derive(Debug, thiserror::Error)]
+pub enum CashuError {
+    #[error("reqwest error: {0}")]
+    ReqwestError(#[from] reqwest::Error),
+
+    #[error("url error: {0}")]
+    UrlError(#[from] url::ParseError),
+
+    #[error("serde error: {0}")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("hex error: {0}")]
+    HexError(#[from] hex::FromHexError),
+
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("secp256k1 error: {0}")]
+    Secp256k1Error(#[from] secp256k1::Error),
+
+    #[error("mint error: {0}")]
+    MintError(String),
+
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+
+    #[error("invalid payment request: {0}")]
+    InvalidPaymentRequest(String),
+
+    #[error("mint has no active keyset for unit {0}")]
+    NoActiveKeyset(String),
+
+    #[error("mint has no key for amount {0}")]
+    UnknownAmount(u64),
+
+    #[error("token is for mint {0}")]
+    WrongMint(String),
+
+    #[error("insufficient funds: need {needed}, have {available}")]
+    InsufficientFunds { needed: u64, available: u64 },
+
+    #[error("Quote not paid")]
+    QuoteNotPaid,
+
+    #[error("Payment failed")]
+    PaymentFailed,
+
+    #[error(
+        "Payment is still pending, its proofs are held until reclaim_pending finds them unspent"
+    )]
+    PaymentPending,
+}
@@ -0,0 +1,14 @@
+pub mod client;
+pub mod dhke;
+pub mod error;
+pub mod model;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod token;
+pub mod wallet;
+
+pub use client::CashuMintClient;
+pub use error::CashuError;
+pub use model::{MeltResult, MintQuote, Proof};
+pub use token::{PaymentRequest, Token};
+pub use wallet::CashuWallet;
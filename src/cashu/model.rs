@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// An unspent ecash note: the mint's unblinded signature `C` on `secret`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Proof {
+    pub amount: u64,
+    /// Keyset id
+    pub id: String,
+    pub secret: String,
+    #[serde(rename = "C")]
+    pub c: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlindedMessage {
+    pub amount: u64,
+    pub id: String,
+    #[serde(rename = "B_")]
+    pub b: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlindSignature {
+    pub amount: u64,
+    pub id: String,
+    #[serde(rename = "C_")]
+    pub c: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeySet {
+    pub id: String,
+    pub unit: String,
+    /// Mint public key per amount
+    pub keys: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeysResponse {
+    pub keysets: Vec<KeySet>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeySetInfo {
+    pub id: String,
+    pub unit: String,
+    pub active: bool,
+    /// Fee per 1000 inputs, in the keyset unit
+    #[serde(default)]
+    pub input_fee_ppk: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeySetsResponse {
+    pub keysets: Vec<KeySetInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MintQuote {
+    pub quote: String,
+    /// The bolt11 invoice to pay
+    pub request: String,
+    // older mints only send `paid`
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default)]
+    pub paid: Option<bool>,
+    #[serde(default)]
+    pub expiry: Option<u64>,
+}
+
+impl MintQuote {
+    pub fn is_paid(&self) -> bool {
+        matches!(self.state.as_deref(), Some("PAID") | Some("ISSUED")) || self.paid == Some(true)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MeltQuote {
+    pub quote: String,
+    pub amount: u64,
+    pub fee_reserve: u64,
+    #[serde(default)]
+    pub state: Option<String>,
+    #[serde(default)]
+    pub paid: Option<bool>,
+    #[serde(default)]
+    pub payment_preimage: Option<String>,
+    /// Signatures on our blank outputs for the unused fee reserve
+    #[serde(default)]
+    pub change: Option<Vec<BlindSignature>>,
+}
+
+impl MeltQuote {
+    pub fn is_paid(&self) -> bool {
+        self.state.as_deref() == Some("PAID") || self.paid == Some(true)
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.state.as_deref() == Some("PENDING")
+    }
+}
+
+/// Whether the mint has seen a proof spent, per NUT-07
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SpendState {
+    Unspent,
+    Pending,
+    Spent,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProofState {
+    /// Hex `hash_to_curve(secret)` of the proof
+    #[serde(rename = "Y")]
+    pub y: String,
+    pub state: SpendState,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CheckStateResponse {
+    pub states: Vec<ProofState>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignaturesResponse {
+    pub signatures: Vec<BlindSignature>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MeltResult {
+    pub preimage: Option<String>,
+    pub amount: u64,
+    /// Lightning and input fees paid, after the change for the unused reserve
+    pub fee: u64,
+}
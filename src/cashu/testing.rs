@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use hyper::{Body, Method, Response};
+use secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use super::dhke::hash_to_curve;
+use super::wallet::split_amount;
+use crate::lightning::testing::{respond, serve, FakeRequest};
+
+/// What `FakeMint` does when asked to melt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Melt {
+    Paid,
+    /// The payment is in flight, the inputs are pending
+    Pending,
+    /// The payment failed, the inputs stay unspent
+    Unpaid,
+    /// The mint errors, e.g. a dropped connection, holding the inputs pending
+    Error,
+}
+
+/// A mint for the `sat` unit signing with real keys, standing in for the
+/// NUT v1 api. Quotes are always paid.
+pub struct FakeMint {
+    /// Keyset ids, the last one is active
+    pub keysets: Mutex<Vec<String>>,
+    pub melt: Mutex<Melt>,
+    /// Makes swaps sign the last output for an amount no key exists for
+    pub bad_signature: Mutex<bool>,
+    spent: Mutex<HashSet<String>>,
+    pending: Mutex<HashSet<String>>,
+}
+
+impl FakeMint {
+    pub async fn serve() -> (Arc<Self>, Url) {
+        let mint = Arc::new(Self {
+            keysets: Mutex::new(vec!["00aa000000000001".to_owned()]),
+            melt: Mutex::new(Melt::Paid),
+            bad_signature: Mutex::new(false),
+            spent: Mutex::new(HashSet::new()),
+            pending: Mutex::new(HashSet::new()),
+        });
+        let url = serve({
+            let mint = mint.clone();
+            move |request| mint.handle(request)
+        })
+        .await;
+
+        (mint, url)
+    }
+
+    /// Makes a new keyset active
+    pub fn rotate(&self) {
+        let mut keysets = self.keysets.lock().unwrap();
+        let id = format!("00aa{:012x}", keysets.len() + 1);
+        keysets.push(id);
+    }
+
+    /// Settles pending proofs as spent or, if the payment failed, unspent
+    pub fn settle_pending(&self, spent: bool) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if spent {
+            self.spent.lock().unwrap().extend(pending);
+        }
+    }
+
+    fn active(&self) -> String {
+        self.keysets.lock().unwrap().last().unwrap().clone()
+    }
+
+    fn key(id: &str, amount: u64) -> SecretKey {
+        SecretKey::from_slice(&Sha256::digest(format!("{id}/{amount}"))).unwrap()
+    }
+
+    fn keys(id: &str) -> Value {
+        let secp = Secp256k1::new();
+        let keys: BTreeMap<String, String> = (0..16)
+            .map(|bit| 1 << bit)
+            .map(|amount| {
+                let key = Self::key(id, amount).public_key(&secp);
+                (amount.to_string(), key.to_string())
+            })
+            .collect();
+        json!({"id": id, "unit": "sat", "keys": keys})
+    }
+
+    fn sign(&self, outputs: &Value, amounts: Option<&[u64]>) -> Result<Vec<Value>, String> {
+        let active = self.active();
+        let outputs = outputs.as_array().cloned().unwrap_or_default();
+        outputs
+            .iter()
+            .enumerate()
+            .map(|(index, output)| {
+                let id = output["id"].as_str().unwrap_or_default();
+                if id != active {
+                    return Err(format!("keyset {id} is inactive"));
+                }
+                let amount = amounts.map_or_else(
+                    || output["amount"].as_u64().unwrap(),
+                    |amounts| amounts[index],
+                );
+                let blinded = PublicKey::from_str(output["B_"].as_str().unwrap()).unwrap();
+                let signature = blinded
+                    .mul_tweak(&Secp256k1::new(), &Scalar::from(Self::key(id, amount)))
+                    .unwrap();
+                Ok(json!({"amount": amount, "id": id, "C_": signature.to_string()}))
+            })
+            .collect()
+    }
+
+    // Checks the inputs are ours and unspent, returning their `Y`s
+    fn verify(&self, inputs: &Value) -> Result<Vec<String>, String> {
+        let secp = Secp256k1::new();
+        let mut ys = vec![];
+        for input in inputs.as_array().cloned().unwrap_or_default() {
+            let secret = input["secret"].as_str().unwrap();
+            let y = hash_to_curve(secret.as_bytes()).unwrap();
+            let key = Self::key(
+                input["id"].as_str().unwrap(),
+                input["amount"].as_u64().unwrap(),
+            );
+            let c = y.mul_tweak(&secp, &Scalar::from(key)).unwrap();
+            if input["C"].as_str() != Some(c.to_string().as_str()) {
+                return Err("invalid proof".to_owned());
+            }
+            let y = y.to_string();
+            if self.spent.lock().unwrap().contains(&y) || self.pending.lock().unwrap().contains(&y)
+            {
+                return Err("proof already spent".to_owned());
+            }
+            ys.push(y);
+        }
+
+        Ok(ys)
+    }
+
+    fn handle(&self, request: FakeRequest) -> Response<Body> {
+        let body = request.json();
+        let result = match (&request.method, request.path.as_str()) {
+            (&Method::GET, "/v1/keysets") => {
+                let active = self.active();
+                let keysets: Vec<Value> = self
+                    .keysets
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|id| json!({"id": id, "unit": "sat", "active": *id == active}))
+                    .collect();
+                Ok(json!({ "keysets": keysets }))
+            }
+            (&Method::GET, "/v1/keys") => Ok(json!({"keysets": [Self::keys(&self.active())]})),
+            (&Method::GET, path) if path.starts_with("/v1/keys/") => {
+                Ok(json!({"keysets": [Self::keys(&path["/v1/keys/".len()..])]}))
+            }
+            (&Method::POST, "/v1/mint/quote/bolt11")
+            | (&Method::GET, "/v1/mint/quote/bolt11/q") => {
+                Ok(json!({"quote": "q", "request": "lnbc1", "state": "PAID"}))
+            }
+            (&Method::POST, "/v1/mint/bolt11") => self
+                .sign(&body["outputs"], None)
+                .map(|signatures| json!({ "signatures": signatures })),
+            (&Method::POST, "/v1/swap") => self.swap(&body),
+            (&Method::POST, "/v1/melt/quote/bolt11") => {
+                Ok(json!({"quote": "m", "amount": 10, "fee_reserve": 2, "state": "UNPAID"}))
+            }
+            (&Method::POST, "/v1/melt/bolt11") => self.melt(&body),
+            (&Method::POST, "/v1/checkstate") => {
+                let states: Vec<Value> = body["Ys"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|y| {
+                        let y = y.as_str().unwrap();
+                        let state = if self.spent.lock().unwrap().contains(y) {
+                            "SPENT"
+                        } else if self.pending.lock().unwrap().contains(y) {
+                            "PENDING"
+                        } else {
+                            "UNSPENT"
+                        };
+                        json!({"Y": y, "state": state, "witness": null})
+                    })
+                    .collect();
+                Ok(json!({ "states": states }))
+            }
+            _ => return respond(404, ""),
+        };
+
+        match result {
+            Ok(body) => respond(200, body.to_string()),
+            Err(detail) => respond(400, json!({"detail": detail, "code": 0}).to_string()),
+        }
+    }
+
+    fn swap(&self, body: &Value) -> Result<Value, String> {
+        let ys = self.verify(&body["inputs"])?;
+        let mut signatures = self.sign(&body["outputs"], None)?;
+        self.spent.lock().unwrap().extend(ys);
+
+        if *self.bad_signature.lock().unwrap() {
+            if let Some(last) = signatures.last_mut() {
+                last["amount"] = json!(1u64 << 40);
+            }
+        }
+        Ok(json!({ "signatures": signatures }))
+    }
+
+    fn melt(&self, body: &Value) -> Result<Value, String> {
+        let ys = self.verify(&body["inputs"])?;
+        let melt = *self.melt.lock().unwrap();
+        match melt {
+            Melt::Paid => {
+                self.spent.lock().unwrap().extend(ys);
+                // routing is free, everything over the amount comes back
+                let total: u64 = body["inputs"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|input| input["amount"].as_u64().unwrap())
+                    .sum();
+                let amounts = split_amount(total - 10);
+                let outputs = body["outputs"].as_array().cloned().unwrap_or_default();
+                let change = self.sign(&json!(outputs[..amounts.len()]), Some(&amounts))?;
+                Ok(json!({
+                    "quote": "m",
+                    "amount": 10,
+                    "fee_reserve": 2,
+                    "state": "PAID",
+                    "payment_preimage": "00".repeat(32),
+                    "change": change,
+                }))
+            }
+            Melt::Pending => {
+                self.pending.lock().unwrap().extend(ys);
+                Ok(json!({"quote": "m", "amount": 10, "fee_reserve": 2, "state": "PENDING"}))
+            }
+            Melt::Unpaid => {
+                Ok(json!({"quote": "m", "amount": 10, "fee_reserve": 2, "state": "UNPAID"}))
+            }
+            Melt::Error => {
+                self.pending.lock().unwrap().extend(ys);
+                Err("lightning backend unreachable".to_owned())
+            }
+        }
+    }
+}
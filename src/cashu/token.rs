@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+
+use base64::engine::general_purpose::{URL_SAFE, URL_SAFE_NO_PAD};
+use base64::Engine;
+use ciborium::Value;
+use serde::{Deserialize, Serialize};
+
+use super::error::CashuError;
+use super::model::Proof;
+
+/// Proofs from one mint, serialized to hand to someone else
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub mint: String,
+    pub unit: Option<String>,
+    pub memo: Option<String>,
+    pub proofs: Vec<Proof>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenV3 {
+    token: Vec<TokenV3Entry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memo: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenV3Entry {
+    mint: String,
+    proofs: Vec<Proof>,
+}
+
+impl Token {
+    pub fn amount(&self) -> u64 {
+        self.proofs.iter().map(|proof| proof.amount).sum()
+    }
+
+    /// Parses a `cashuA` (JSON) or `cashuB` (CBOR) token
+    pub fn decode(token: &str) -> Result<Self, CashuError> {
+        let token = token.trim();
+        if let Some(encoded) = token.strip_prefix("cashuA") {
+            Self::decode_v3(&decode_base64(encoded)?)
+        } else if let Some(encoded) = token.strip_prefix("cashuB") {
+            Self::decode_v4(&decode_base64(encoded)?)
+        } else {
+            Err(CashuError::InvalidToken("unknown token version".to_owned()))
+        }
+    }
+
+    fn decode_v3(bytes: &[u8]) -> Result<Self, CashuError> {
+        let token: TokenV3 = serde_json::from_slice(bytes)?;
+
+        let mut entries = token.token.into_iter();
+        let first = entries
+            .next()
+            .ok_or_else(|| CashuError::InvalidToken("token has no proofs".to_owned()))?;
+        let mut proofs = first.proofs;
+        for entry in entries {
+            if entry.mint != first.mint {
+                return Err(CashuError::InvalidToken(
+                    "tokens from several mints are not supported".to_owned(),
+                ));
+            }
+            proofs.extend(entry.proofs);
+        }
+
+        Ok(Self {
+            mint: first.mint,
+            unit: token.unit,
+            memo: token.memo,
+            proofs,
+        })
+    }
+
+    fn decode_v4(bytes: &[u8]) -> Result<Self, CashuError> {
+        let invalid = |reason: &str| CashuError::InvalidToken(reason.to_owned());
+        let token: Value = ciborium::from_reader(bytes).map_err(|err| invalid(&err.to_string()))?;
+        let token = token
+            .as_map()
+            .ok_or_else(|| invalid("token is not a map"))?;
+
+        let mint = cbor_get(token, "m")
+            .and_then(Value::as_text)
+            .ok_or_else(|| invalid("missing mint"))?;
+        let unit = cbor_get(token, "u").and_then(Value::as_text);
+        let memo = cbor_get(token, "d").and_then(Value::as_text);
+
+        let mut proofs = vec![];
+        let keysets = cbor_get(token, "t")
+            .and_then(Value::as_array)
+            .ok_or_else(|| invalid("missing proofs"))?;
+        for keyset in keysets {
+            let keyset = keyset
+                .as_map()
+                .ok_or_else(|| invalid("keyset is not a map"))?;
+            let id = cbor_get(keyset, "i")
+                .and_then(Value::as_bytes)
+                .ok_or_else(|| invalid("missing keyset id"))?;
+            let keyset_proofs = cbor_get(keyset, "p")
+                .and_then(Value::as_array)
+                .ok_or_else(|| invalid("missing keyset proofs"))?;
+
+            for proof in keyset_proofs {
+                let proof = proof
+                    .as_map()
+                    .ok_or_else(|| invalid("proof is not a map"))?;
+                let amount = cbor_get(proof, "a")
+                    .and_then(Value::as_integer)
+                    .and_then(|amount| u64::try_from(amount).ok())
+                    .ok_or_else(|| invalid("missing proof amount"))?;
+                let secret = cbor_get(proof, "s")
+                    .and_then(Value::as_text)
+                    .ok_or_else(|| invalid("missing proof secret"))?;
+                let c = cbor_get(proof, "c")
+                    .and_then(Value::as_bytes)
+                    .ok_or_else(|| invalid("missing proof signature"))?;
+
+                proofs.push(Proof {
+                    amount,
+                    id: hex::encode(id),
+                    secret: secret.to_owned(),
+                    c: hex::encode(c),
+                });
+            }
+        }
+
+        Ok(Self {
+            mint: mint.to_owned(),
+            unit: unit.map(str::to_owned),
+            memo: memo.map(str::to_owned),
+            proofs,
+        })
+    }
+
+    pub fn encode_v3(&self) -> Result<String, CashuError> {
+        let token = TokenV3 {
+            token: vec![TokenV3Entry {
+                mint: self.mint.clone(),
+                proofs: self.proofs.clone(),
+            }],
+            unit: self.unit.clone(),
+            memo: self.memo.clone(),
+        };
+
+        Ok(format!(
+            "cashuA{}",
+            URL_SAFE.encode(serde_json::to_vec(&token)?)
+        ))
+    }
+
+    /// Encodes a `cashuB` token, which needs hex keyset ids
+    pub fn encode_v4(&self) -> Result<String, CashuError> {
+        let mut keysets: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
+        for proof in &self.proofs {
+            keysets.entry(&proof.id).or_default().push(Value::Map(vec![
+                (Value::from("a"), Value::from(proof.amount)),
+                (Value::from("s"), Value::from(proof.secret.as_str())),
+                (Value::from("c"), Value::Bytes(hex::decode(&proof.c)?)),
+            ]));
+        }
+
+        let mut keyset_values = vec![];
+        for (id, proofs) in keysets {
+            keyset_values.push(Value::Map(vec![
+                (Value::from("i"), Value::Bytes(hex::decode(id)?)),
+                (Value::from("p"), Value::Array(proofs)),
+            ]));
+        }
+
+        let mut token = vec![
+            (Value::from("m"), Value::from(self.mint.as_str())),
+            (
+                Value::from("u"),
+                Value::from(self.unit.as_deref().unwrap_or("sat")),
+            ),
+        ];
+        if let Some(memo) = &self.memo {
+            token.push((Value::from("d"), Value::from(memo.as_str())));
+        }
+        token.push((Value::from("t"), Value::Array(keyset_values)));
+
+        let mut bytes = vec![];
+        ciborium::into_writer(&Value::Map(token), &mut bytes)
+            .map_err(|err| CashuError::InvalidToken(err.to_string()))?;
+        Ok(format!("cashuB{}", URL_SAFE_NO_PAD.encode(bytes)))
+    }
+}
+
+/// A NUT-18 payment request, as sent in the `X-Cashu` header of a 402
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct PaymentRequest {
+    #[serde(rename = "i", default)]
+    pub id: Option<String>,
+    #[serde(rename = "a", default)]
+    pub amount: Option<u64>,
+    #[serde(rename = "u", default)]
+    pub unit: Option<String>,
+    #[serde(rename = "s", default)]
+    pub single_use: Option<bool>,
+    /// Mints the payee accepts, any if unset
+    #[serde(rename = "m", default)]
+    pub mints: Option<Vec<String>>,
+    #[serde(rename = "d", default)]
+    pub description: Option<String>,
+}
+
+impl PaymentRequest {
+    pub fn decode(request: &str) -> Result<Self, CashuError> {
+        let encoded = request.trim().strip_prefix("creqA").ok_or_else(|| {
+            CashuError::InvalidPaymentRequest("unknown payment request version".to_owned())
+        })?;
+        let bytes = decode_base64(encoded)
+            .map_err(|_| CashuError::InvalidPaymentRequest("invalid base64".to_owned()))?;
+
+        ciborium::from_reader(bytes.as_slice())
+            .map_err(|err| CashuError::InvalidPaymentRequest(err.to_string()))
+    }
+
+    pub fn accepts_mint(&self, mint: &str) -> bool {
+        self.mints.as_ref().is_none_or(|mints| {
+            mints
+                .iter()
+                .any(|accepted| accepted.trim_end_matches('/') == mint.trim_end_matches('/'))
+        })
+    }
+}
+
+fn decode_base64(encoded: &str) -> Result<Vec<u8>, CashuError> {
+    URL_SAFE
+        .decode(encoded)
+        .or_else(|_| URL_SAFE_NO_PAD.decode(encoded))
+        .map_err(|_| CashuError::InvalidToken("invalid base64".to_owned()))
+}
+
+fn cbor_get<'a>(map: &'a [(Value, Value)], key: &str) -> Option<&'a Value> {
+    map.iter()
+        .find(|(map_key, _)| map_key.as_text() == Some(key))
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NUT-00 test vectors
+
+    const TOKEN_V3: &str = "cashuAeyJ0b2tlbiI6W3sibWludCI6Imh0dHBzOi8vODMzMy5zcGFjZTozMzM4IiwicHJvb2ZzIjpbeyJhbW91bnQiOjIsImlkIjoiMDA5YTFmMjkzMjUzZTQxZSIsInNlY3JldCI6IjQwNzkxNWJjMjEyYmU2MWE3N2UzZTZkMmFlYjRjNzI3OTgwYmRhNTFjZDA2YTZhZmMyOWUyODYxNzY4YTc4MzciLCJDIjoiMDJiYzkwOTc5OTdkODFhZmIyY2M3MzQ2YjVlNDM0NWE5MzQ2YmQyYTUwNmViNzk1ODU5OGE3MmYwY2Y4NTE2M2VhIn0seyJhbW91bnQiOjgsImlkIjoiMDA5YTFmMjkzMjUzZTQxZSIsInNlY3JldCI6ImZlMTUxMDkzMTRlNjFkNzc1NmIwZjhlZTBmMjNhNjI0YWNhYTNmNGUwNDJmNjE0MzNjNzI4YzcwNTdiOTMxYmUiLCJDIjoiMDI5ZThlNTA1MGI4OTBhN2Q2YzA5NjhkYjE2YmMxZDVkNWZhMDQwZWExZGUyODRmNmVjNjlkNjEyOTlmNjcxMDU5In1dfV0sInVuaXQiOiJzYXQiLCJtZW1vIjoiVGhhbmsgeW91LiJ9";
+
+    const TOKEN_V4: &str = "cashuBpGF0gaJhaUgArSaMTR9YJmFwgaNhYQFhc3hAOWE2ZGJiODQ3YmQyMzJiYTc2ZGIwZGYxOTcyMTZiMjlkM2I4Y2MxNDU1M2NkMjc4MjdmYzFjYzk0MmZlZGI0ZWFjWCEDhhhUP_trhpXfStS6vN6So0qWvc2X3O4NfM-Y1HISZ5JhZGlUaGFuayB5b3VhbXVodHRwOi8vbG9jYWxob3N0OjMzMzhhdWNzYXQ";
+
+    fn proof(amount: u64, id: &str, secret: &str, c: &str) -> Proof {
+        Proof {
+            amount,
+            id: id.to_owned(),
+            secret: secret.to_owned(),
+            c: c.to_owned(),
+        }
+    }
+
+    #[test]
+    fn decodes_v3_tokens() {
+        let token = Token::decode(TOKEN_V3).unwrap();
+        assert_eq!(
+            token,
+            Token {
+                mint: "https://8333.space:3338".to_owned(),
+                unit: Some("sat".to_owned()),
+                memo: Some("Thank you.".to_owned()),
+                proofs: vec![
+                    proof(
+                        2,
+                        "009a1f293253e41e",
+                        "407915bc212be61a77e3e6d2aeb4c727980bda51cd06a6afc29e2861768a7837",
+                        "02bc9097997d81afb2cc7346b5e4345a9346bd2a506eb7958598a72f0cf85163ea",
+                    ),
+                    proof(
+                        8,
+                        "009a1f293253e41e",
+                        "fe15109314e61d7756b0f8ee0f23a624acaa3f4e042f61433c728c7057b931be",
+                        "029e8e5050b890a7d6c0968db16bc1d5d5fa040ea1de284f6ec69d61299f671059",
+                    ),
+                ],
+            }
+        );
+        assert_eq!(token.amount(), 10);
+    }
+
+    #[test]
+    fn decodes_v4_tokens() {
+        let token = Token::decode(TOKEN_V4).unwrap();
+        assert_eq!(
+            token,
+            Token {
+                mint: "http://localhost:3338".to_owned(),
+                unit: Some("sat".to_owned()),
+                memo: Some("Thank you".to_owned()),
+                proofs: vec![proof(
+                    1,
+                    "00ad268c4d1f5826",
+                    "9a6dbb847bd232ba76db0df197216b29d3b8cc14553cd27827fc1cc942fedb4e",
+                    "038618543ffb6b8695df4ad4babcde92a34a96bdcd97dcee0d7ccf98d472126792",
+                )],
+            }
+        );
+    }
+
+    #[test]
+    fn encoded_tokens_decode_to_the_same_token() {
+        let token = Token::decode(TOKEN_V3).unwrap();
+
+        let v3 = token.encode_v3().unwrap();
+        assert!(v3.starts_with("cashuA"));
+        assert_eq!(Token::decode(&v3).unwrap(), token);
+
+        let v4 = token.encode_v4().unwrap();
+        assert!(v4.starts_with("cashuB"));
+        assert_eq!(Token::decode(&v4).unwrap(), token);
+    }
+
+    #[test]
+    fn rejects_unknown_tokens() {
+        for token in ["cashuCabc", "cashuA!!!", "cashuAe30", "cashuBpGF0", ""] {
+            assert!(Token::decode(token).is_err(), "{token}");
+        }
+    }
+}
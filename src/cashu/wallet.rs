@@ -0,0 +1,672 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use secp256k1::{PublicKey, SecretKey};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::client::CashuMintClient;
+use super::dhke::{blind_message, unblind_signature};
+use super::error::CashuError;
+use super::model::{
+    BlindSignature, BlindedMessage, KeySet, MeltResult, MintQuote, Proof, SpendState,
+};
+use super::token::Token;
+
+// Mints rotate keysets, so the active one is looked up again after a while
+// and whenever the mint rejects a request
+const KEYSET_TTL: Duration = Duration::from_secs(300);
+
+struct ActiveKeyset {
+    id: String,
+    keys: BTreeMap<u64, PublicKey>,
+    // input fee per 1000 proofs, for every keyset of the mint
+    input_fees: HashMap<String, u64>,
+    fetched_at: Instant,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct WalletFile {
+    proofs: Vec<Proof>,
+    /// Proofs of payments the mint may still settle, see `reclaim_pending`
+    #[serde(default)]
+    pending: Vec<Proof>,
+}
+
+// An output we sent for signing, kept to unblind the signature
+struct PreMint {
+    secret: String,
+    blinding_factor: SecretKey,
+}
+
+/// Ecash wallet for a single mint and unit. Proofs are kept in memory, or in
+/// a JSON file if opened with a path.
+pub struct CashuWallet {
+    client: CashuMintClient,
+    mint_url: Url,
+    unit: String,
+    path: Option<PathBuf>,
+    proofs: Mutex<WalletFile>,
+    keyset: tokio::sync::Mutex<Option<Arc<ActiveKeyset>>>,
+}
+
+impl CashuWallet {
+    pub fn new(mint_url: Url, unit: &str) -> Result<Self, CashuError> {
+        Ok(Self {
+            client: CashuMintClient::new(mint_url.clone())?,
+            mint_url,
+            unit: unit.to_owned(),
+            path: None,
+            proofs: Mutex::new(WalletFile::default()),
+            keyset: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// A wallet persisting its proofs to `path`
+    pub fn open(mint_url: Url, unit: &str, path: impl AsRef<Path>) -> Result<Self, CashuError> {
+        let path = path.as_ref().to_path_buf();
+        let proofs = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            WalletFile::default()
+        };
+
+        Ok(Self {
+            path: Some(path),
+            proofs: Mutex::new(proofs),
+            ..Self::new(mint_url, unit)?
+        })
+    }
+
+    pub fn mint_url(&self) -> &Url {
+        &self.mint_url
+    }
+
+    pub fn unit(&self) -> &str {
+        &self.unit
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.proofs
+            .lock()
+            .unwrap()
+            .proofs
+            .iter()
+            .map(|p| p.amount)
+            .sum()
+    }
+
+    /// Held back from melts the mint hasn't settled yet
+    pub fn pending_balance(&self) -> u64 {
+        self.proofs
+            .lock()
+            .unwrap()
+            .pending
+            .iter()
+            .map(|p| p.amount)
+            .sum()
+    }
+
+    /// Asks the mint for an invoice to pay for `amount` of ecash
+    pub async fn mint_quote(&self, amount: u64) -> Result<MintQuote, CashuError> {
+        self.client.mint_quote(amount, &self.unit).await
+    }
+
+    /// Mints `amount` of ecash once the quote's invoice has been paid
+    pub async fn mint(&self, quote: &str, amount: u64) -> Result<u64, CashuError> {
+        if !self.client.mint_quote_state(quote).await?.is_paid() {
+            return Err(CashuError::QuoteNotPaid);
+        }
+
+        let keyset = self.keyset().await?;
+        let (outputs, pre_mints) = blind_outputs(&keyset, &split_amount(amount))?;
+        let signatures = self
+            .rejectable(self.client.mint(quote, &outputs).await)
+            .await?;
+        let (proofs, unblinded) = self.unblind_proofs(&keyset, signatures, pre_mints).await;
+
+        self.add_proofs(proofs)?;
+        unblinded?;
+        Ok(amount)
+    }
+
+    /// Pays a bolt11 invoice with ecash through the mint
+    pub async fn melt(&self, bolt11: &str) -> Result<MeltResult, CashuError> {
+        let quote = self.client.melt_quote(bolt11, &self.unit).await?;
+        let keyset = self.keyset().await?;
+        let needed = quote.amount + quote.fee_reserve;
+        let inputs = self.select_proofs(needed)?;
+        let total: u64 = inputs.iter().map(|proof| proof.amount).sum();
+        let input_fee = input_fee(&keyset, &inputs);
+        if total < needed + input_fee {
+            let available = self.balance() + total;
+            self.restore_proofs(inputs)?;
+            return Err(CashuError::InsufficientFunds {
+                needed: needed + input_fee,
+                available,
+            });
+        }
+
+        // blank outputs for the mint to return what isn't spent on fees in
+        // (NUT-08), enough to represent any amount up to the overpayment
+        let max_change = total - quote.amount - input_fee;
+        let blank_count = (u64::BITS - max_change.leading_zeros()) as usize;
+        let (outputs, pre_mints) = blind_outputs(&keyset, &vec![1; blank_count])?;
+
+        let melted = self
+            .rejectable(self.client.melt(&quote.quote, &inputs, &outputs).await)
+            .await;
+        let result = match melted {
+            Ok(result) if result.is_paid() => result,
+            Ok(result) if result.is_pending() => {
+                self.hold_pending(inputs)?;
+                return Err(CashuError::PaymentPending);
+            }
+            Ok(_) => {
+                self.reclaim(inputs).await?;
+                return Err(CashuError::PaymentFailed);
+            }
+            Err(err) => {
+                self.reclaim(inputs).await?;
+                return Err(err);
+            }
+        };
+
+        // the invoice is paid, so a change signature that doesn't unblind
+        // only costs us that change
+        let (change, unblinded) = self
+            .unblind_proofs(&keyset, result.change.unwrap_or_default(), pre_mints)
+            .await;
+        if let Err(err) = unblinded {
+            warn!("Can not unblind all melt change: {err}");
+        }
+        let change_amount: u64 = change.iter().map(|proof| proof.amount).sum();
+        self.add_proofs(change)?;
+
+        Ok(MeltResult {
+            preimage: result.payment_preimage,
+            amount: quote.amount,
+            fee: (total - quote.amount).saturating_sub(change_amount),
+        })
+    }
+
+    /// Takes `amount` out of the wallet as a token, swapping proofs if
+    /// they don't add up to the exact amount
+    pub async fn send(&self, amount: u64) -> Result<Token, CashuError> {
+        let keyset = self.keyset().await?;
+        let inputs = self.select_proofs(amount)?;
+        let total: u64 = inputs.iter().map(|proof| proof.amount).sum();
+
+        let proofs = if total == amount {
+            inputs
+        } else {
+            let fee = input_fee(&keyset, &inputs);
+            if total < amount + fee {
+                let available = self.balance() + total;
+                self.restore_proofs(inputs)?;
+                return Err(CashuError::InsufficientFunds {
+                    needed: amount + fee,
+                    available,
+                });
+            }
+
+            let send_amounts = split_amount(amount);
+            let mut amounts = send_amounts.clone();
+            amounts.extend(split_amount(total - amount - fee));
+            let (outputs, pre_mints) = blind_outputs(&keyset, &amounts)?;
+
+            let signatures = match self
+                .rejectable(self.client.swap(&inputs, &outputs).await)
+                .await
+            {
+                Ok(signatures) => signatures,
+                Err(err) => {
+                    self.reclaim(inputs).await?;
+                    return Err(err);
+                }
+            };
+            let signed = signatures.len();
+            let (mut proofs, unblinded) = self.unblind_proofs(&keyset, signatures, pre_mints).await;
+            let unblinded = unblinded.and_then(|_| {
+                if signed == amounts.len() {
+                    Ok(())
+                } else {
+                    Err(CashuError::MintError(format!(
+                        "mint signed {signed} of {} outputs",
+                        amounts.len()
+                    )))
+                }
+            });
+            if let Err(err) = unblinded {
+                self.add_proofs(proofs)?;
+                return Err(err);
+            }
+            let keep = proofs.split_off(send_amounts.len());
+            self.add_proofs(keep)?;
+            proofs
+        };
+
+        Ok(Token {
+            // `Url` adds a trailing slash payees don't list mints with
+            mint: self.mint_url.as_str().trim_end_matches('/').to_owned(),
+            unit: Some(self.unit.clone()),
+            memo: None,
+            proofs,
+        })
+    }
+
+    /// Swaps the proofs of a token we received for fresh ones, so the sender
+    /// can't spend them again
+    pub async fn receive(&self, token: &Token) -> Result<u64, CashuError> {
+        if token.mint.trim_end_matches('/') != self.mint_url.as_str().trim_end_matches('/') {
+            return Err(CashuError::WrongMint(token.mint.clone()));
+        }
+
+        let keyset = self.keyset().await?;
+        let fee = input_fee(&keyset, &token.proofs);
+        let amount = token
+            .amount()
+            .checked_sub(fee)
+            .ok_or(CashuError::InsufficientFunds {
+                needed: fee,
+                available: token.amount(),
+            })?;
+
+        let (outputs, pre_mints) = blind_outputs(&keyset, &split_amount(amount))?;
+        let signatures = self
+            .rejectable(self.client.swap(&token.proofs, &outputs).await)
+            .await?;
+        let (proofs, unblinded) = self.unblind_proofs(&keyset, signatures, pre_mints).await;
+        self.add_proofs(proofs)?;
+        unblinded?;
+
+        Ok(amount)
+    }
+
+    /// Asks the mint about proofs held back from melts it hadn't settled,
+    /// putting those it reports unspent back into the wallet. Returns the
+    /// amount put back.
+    pub async fn reclaim_pending(&self) -> Result<u64, CashuError> {
+        let pending = std::mem::take(&mut self.proofs.lock().unwrap().pending);
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        let before = self.balance();
+        self.save()?;
+        self.reclaim(pending).await?;
+        Ok(self.balance().saturating_sub(before))
+    }
+
+    async fn keyset(&self) -> Result<Arc<ActiveKeyset>, CashuError> {
+        let mut cached = self.keyset.lock().await;
+        if let Some(keyset) = cached
+            .as_ref()
+            .filter(|keyset| keyset.fetched_at.elapsed() < KEYSET_TTL)
+        {
+            return Ok(keyset.clone());
+        }
+
+        let keysets = self.client.get_keysets().await?;
+        let keys = self.client.get_keys().await?;
+
+        let active = keysets
+            .keysets
+            .iter()
+            .find(|keyset| keyset.active && keyset.unit == self.unit)
+            .ok_or_else(|| CashuError::NoActiveKeyset(self.unit.clone()))?;
+        let active_keys = keys
+            .keysets
+            .into_iter()
+            .find(|keyset| keyset.id == active.id)
+            .ok_or_else(|| CashuError::NoActiveKeyset(self.unit.clone()))?;
+
+        let keyset = Arc::new(ActiveKeyset {
+            id: active.id.clone(),
+            keys: parse_keys(active_keys)?,
+            input_fees: keysets
+                .keysets
+                .iter()
+                .map(|keyset| (keyset.id.clone(), keyset.input_fee_ppk))
+                .collect(),
+            fetched_at: Instant::now(),
+        });
+        *cached = Some(keyset.clone());
+        Ok(keyset)
+    }
+
+    // A rejection may be down to a keyset the mint rotated out, so look it
+    // up again next time
+    async fn rejectable<T>(&self, result: Result<T, CashuError>) -> Result<T, CashuError> {
+        if let Err(CashuError::MintError(_)) = &result {
+            *self.keyset.lock().await = None;
+        }
+        result
+    }
+
+    // Sorts proofs whose fate is unknown, e.g. after a request errored, by
+    // what the mint says: unspent ones go back into the wallet, pending ones
+    // are held for `reclaim_pending` and spent ones are gone. If the mint
+    // can't be asked they are all held.
+    async fn reclaim(&self, proofs: Vec<Proof>) -> Result<(), CashuError> {
+        let states = match self.client.check_state(&proofs).await {
+            Ok(states) => states,
+            Err(err) => {
+                warn!("Can not check the state of ecash proofs, holding them: {err}");
+                return self.hold_pending(proofs);
+            }
+        };
+
+        let mut wallet = self.proofs.lock().unwrap();
+        for (proof, state) in proofs.into_iter().zip(states) {
+            match state.state {
+                SpendState::Unspent => wallet.proofs.push(proof),
+                SpendState::Pending => wallet.pending.push(proof),
+                SpendState::Spent => {}
+            }
+        }
+        drop(wallet);
+        self.save()
+    }
+
+    fn hold_pending(&self, proofs: Vec<Proof>) -> Result<(), CashuError> {
+        self.proofs.lock().unwrap().pending.extend(proofs);
+        self.save()
+    }
+
+    // Signatures come back in the order of the outputs. The mint spent our
+    // inputs already, so every proof that unblinds is returned along with the
+    // first failure, for the caller to keep.
+    async fn unblind_proofs(
+        &self,
+        keyset: &ActiveKeyset,
+        signatures: Vec<BlindSignature>,
+        pre_mints: Vec<PreMint>,
+    ) -> (Vec<Proof>, Result<(), CashuError>) {
+        let mut other_keys = HashMap::new();
+        let mut proofs = vec![];
+        let mut failure = None;
+        for (signature, pre_mint) in signatures.into_iter().zip(pre_mints) {
+            if signature.id != keyset.id && !other_keys.contains_key(&signature.id) {
+                let keys = self.keyset_keys(&signature.id).await;
+                other_keys.insert(signature.id.clone(), keys);
+            }
+            let keys = match other_keys.get(&signature.id) {
+                Some(Ok(keys)) => keys,
+                Some(Err(err)) => {
+                    failure.get_or_insert(CashuError::MintError(err.to_string()));
+                    continue;
+                }
+                None => &keyset.keys,
+            };
+
+            match unblind_proof(keys, signature, pre_mint) {
+                Ok(proof) => proofs.push(proof),
+                Err(err) => {
+                    failure.get_or_insert(err);
+                }
+            }
+        }
+
+        (proofs, failure.map_or(Ok(()), Err))
+    }
+
+    async fn keyset_keys(&self, id: &str) -> Result<BTreeMap<u64, PublicKey>, CashuError> {
+        let keys = self.client.get_keyset_keys(id).await?;
+        let keyset = keys
+            .keysets
+            .into_iter()
+            .find(|keyset| keyset.id == id)
+            .ok_or_else(|| CashuError::MintError(format!("mint has no keyset {id}")))?;
+        parse_keys(keyset)
+    }
+
+    // Takes proofs worth at least `amount` out of the wallet, largest first.
+    // Put them back with `restore_proofs` if they never reached the mint, and
+    // `reclaim` them if they did.
+    fn select_proofs(&self, amount: u64) -> Result<Vec<Proof>, CashuError> {
+        let mut wallet = self.proofs.lock().unwrap();
+        let proofs = &mut wallet.proofs;
+        let available: u64 = proofs.iter().map(|proof| proof.amount).sum();
+        if available < amount {
+            return Err(CashuError::InsufficientFunds {
+                needed: amount,
+                available,
+            });
+        }
+
+        proofs.sort_by_key(|proof| proof.amount);
+        let mut selected = vec![];
+        let mut total = 0;
+        while total < amount {
+            let proof = proofs.pop().expect("balance checked above");
+            total += proof.amount;
+            selected.push(proof);
+        }
+        drop(wallet);
+
+        self.save()?;
+        Ok(selected)
+    }
+
+    fn restore_proofs(&self, proofs: Vec<Proof>) -> Result<(), CashuError> {
+        self.add_proofs(proofs)
+    }
+
+    fn add_proofs(&self, proofs: Vec<Proof>) -> Result<(), CashuError> {
+        self.proofs.lock().unwrap().proofs.extend(proofs);
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), CashuError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents = serde_json::to_string_pretty(&*self.proofs.lock().unwrap())?;
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // write then rename so a crash never leaves a truncated wallet behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, contents)?;
+        // proofs are bearer money
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        std::fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+/// Splits an amount into powers of two, the denominations mints sign
+pub fn split_amount(amount: u64) -> Vec<u64> {
+    (0..u64::BITS)
+        .map(|bit| 1 << bit)
+        .filter(|denomination| amount & denomination != 0)
+        .collect()
+}
+
+fn input_fee(keyset: &ActiveKeyset, proofs: &[Proof]) -> u64 {
+    let fee_ppk: u64 = proofs
+        .iter()
+        .map(|proof| keyset.input_fees.get(&proof.id).copied().unwrap_or(0))
+        .sum();
+    fee_ppk.div_ceil(1000)
+}
+
+fn blind_outputs(
+    keyset: &ActiveKeyset,
+    amounts: &[u64],
+) -> Result<(Vec<BlindedMessage>, Vec<PreMint>), CashuError> {
+    let mut outputs = vec![];
+    let mut pre_mints = vec![];
+    for amount in amounts {
+        let secret = hex::encode(secp256k1::rand::random::<[u8; 32]>());
+        let (blinded, blinding_factor) = blind_message(secret.as_bytes(), None)?;
+
+        outputs.push(BlindedMessage {
+            amount: *amount,
+            id: keyset.id.clone(),
+            b: blinded.to_string(),
+        });
+        pre_mints.push(PreMint {
+            secret,
+            blinding_factor,
+        });
+    }
+
+    Ok((outputs, pre_mints))
+}
+
+fn parse_keys(keyset: KeySet) -> Result<BTreeMap<u64, PublicKey>, CashuError> {
+    let mut keys = BTreeMap::new();
+    for (amount, key) in keyset.keys {
+        let amount = amount
+            .parse::<u64>()
+            .map_err(|_| CashuError::MintError(format!("invalid amount {amount}")))?;
+        keys.insert(amount, PublicKey::from_str(&key)?);
+    }
+    Ok(keys)
+}
+
+// The mint sets the amount, which for blank change outputs differs from the
+// one we sent
+fn unblind_proof(
+    keys: &BTreeMap<u64, PublicKey>,
+    signature: BlindSignature,
+    pre_mint: PreMint,
+) -> Result<Proof, CashuError> {
+    let mint_key = keys
+        .get(&signature.amount)
+        .ok_or(CashuError::UnknownAmount(signature.amount))?;
+    let c = unblind_signature(
+        &PublicKey::from_str(&signature.c)?,
+        &pre_mint.blinding_factor,
+        mint_key,
+    )?;
+
+    Ok(Proof {
+        amount: signature.amount,
+        id: signature.id,
+        secret: pre_mint.secret,
+        c: c.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cashu::testing::{FakeMint, Melt};
+
+    async fn funded(amount: u64) -> (Arc<FakeMint>, CashuWallet) {
+        let (mint, url) = FakeMint::serve().await;
+        let wallet = CashuWallet::new(url, "sat").unwrap();
+        wallet.mint("q", amount).await.unwrap();
+        (mint, wallet)
+    }
+
+    #[tokio::test]
+    async fn sends_and_receives_through_the_mint() {
+        let (_mint, wallet) = funded(100).await;
+
+        let token = wallet.send(30).await.unwrap();
+        assert_eq!(token.amount(), 30);
+        assert_eq!(wallet.balance(), 70);
+
+        let receiver = CashuWallet::new(wallet.mint_url().clone(), "sat").unwrap();
+        assert_eq!(receiver.receive(&token).await.unwrap(), 30);
+        assert_eq!(receiver.balance(), 30);
+        // the proofs were swapped, so they can't be received again
+        assert!(wallet.receive(&token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn melt_pays_and_keeps_the_change() {
+        let (_mint, wallet) = funded(100).await;
+
+        let result = wallet.melt("lnbc1").await.unwrap();
+        assert_eq!(result.amount, 10);
+        assert_eq!(result.fee, 0);
+        assert!(result.preimage.is_some());
+        assert_eq!(wallet.balance(), 90);
+    }
+
+    #[tokio::test]
+    async fn melt_restores_proofs_of_a_failed_payment() {
+        let (mint, wallet) = funded(12).await;
+        *mint.melt.lock().unwrap() = Melt::Unpaid;
+
+        let err = wallet.melt("lnbc1").await.unwrap_err();
+        assert!(matches!(err, CashuError::PaymentFailed));
+        assert_eq!(wallet.balance(), 12);
+        assert_eq!(wallet.pending_balance(), 0);
+    }
+
+    #[tokio::test]
+    async fn melt_holds_proofs_of_a_pending_payment() {
+        let (mint, wallet) = funded(12).await;
+        *mint.melt.lock().unwrap() = Melt::Pending;
+
+        let err = wallet.melt("lnbc1").await.unwrap_err();
+        assert!(matches!(err, CashuError::PaymentPending));
+        assert_eq!(wallet.balance(), 0);
+        assert_eq!(wallet.pending_balance(), 12);
+
+        // the payment went through after all
+        mint.settle_pending(true);
+        assert_eq!(wallet.reclaim_pending().await.unwrap(), 0);
+        assert_eq!(wallet.balance(), 0);
+        assert_eq!(wallet.pending_balance(), 0);
+    }
+
+    #[tokio::test]
+    async fn melt_only_restores_proofs_once_the_mint_reports_them_unspent() {
+        let (mint, wallet) = funded(12).await;
+        *mint.melt.lock().unwrap() = Melt::Error;
+
+        assert!(wallet.melt("lnbc1").await.is_err());
+        assert_eq!(wallet.balance(), 0);
+        assert_eq!(wallet.pending_balance(), 12);
+
+        assert_eq!(wallet.reclaim_pending().await.unwrap(), 0);
+        assert_eq!(wallet.pending_balance(), 12);
+
+        mint.settle_pending(false);
+        assert_eq!(wallet.reclaim_pending().await.unwrap(), 12);
+        assert_eq!(wallet.balance(), 12);
+        assert_eq!(wallet.pending_balance(), 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_proofs_that_unblind_after_a_swap() {
+        let (mint, wallet) = funded(100).await;
+        *mint.bad_signature.lock().unwrap() = true;
+
+        // the 64 proof is swapped for 2, 4, 8 and 16 to send and 2 and 32 in
+        // change, of which the 32 can't be unblinded
+        assert!(wallet.send(30).await.is_err());
+        assert_eq!(wallet.balance(), 68);
+    }
+
+    #[tokio::test]
+    async fn refreshes_the_keyset_after_the_mint_rotates_it() {
+        let (mint, wallet) = funded(100).await;
+        mint.rotate();
+
+        // outputs for the old keyset are rejected, leaving the inputs unspent
+        assert!(wallet.send(30).await.is_err());
+        assert_eq!(wallet.balance(), 100);
+
+        let token = wallet.send(30).await.unwrap();
+        let active = mint.keysets.lock().unwrap().last().unwrap().clone();
+        assert!(token.proofs.iter().all(|proof| proof.id == active));
+        assert_eq!(wallet.balance(), 70);
+    }
+}
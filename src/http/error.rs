@@ -0,0 +1,29 @@
+#[derive(Debug, thiserror::Error)]
+pub enum L402Error {
+    #[error("request body can not be replayed after an L402 payment")]
+    RequestNotReplayable,
+
+    #[error("{url} still demands payment after {payments} accepted payment(s)")]
+    PaymentNotAccepted { url: String, payments: u32 },
+
+    #[error("L402 payment declined: {0}")]
+    PaymentDeclined(String),
+
+    #[error("L402 invoice expired")]
+    InvoiceExpired,
+
+    #[error("L402 invoice is for {actual:?}, expected {expected:?}")]
+    WrongNetwork {
+        expected: lightning_invoice::Currency,
+        actual: lightning_invoice::Currency,
+    },
+
+    #[error("invalid preimage returned by the lightning backend: {0}")]
+    InvalidPreimage(String),
+
+    #[error("preimage {preimage} does not match invoice payment hash {payment_hash}")]
+    PreimageMismatch {
+        preimage: String,
+        payment_hash: String,
+    },
+}
@@ -0,0 +1,638 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use lightning_invoice::Bolt11Invoice;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum L402ChallengeError {
+    #[error("malformed WWW-Authenticate header at byte {0}: {1}")]
+    Malformed(usize, &'static str),
+
+    #[error("no L402 or LSAT challenge in WWW-Authenticate header")]
+    NoL402Challenge,
+
+    #[error("L402 challenge is missing the {0} parameter")]
+    MissingParam(&'static str),
+
+    #[error("L402 challenge has a duplicate {0} parameter")]
+    DuplicateParam(String),
+
+    #[error("L402 challenge has an invalid invoice: {0}")]
+    InvalidInvoice(String),
+}
+
+/// A single challenge from a `WWW-Authenticate` header (RFC 7235 section 2.1)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthChallenge {
+    pub scheme: String,
+    pub token68: Option<String>,
+    /// Auth params in header order, names lowercased
+    pub params: Vec<(String, String)>,
+}
+
+impl AuthChallenge {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum L402Scheme {
+    L402,
+    /// The scheme name used before L402 was renamed
+    Lsat,
+}
+
+impl fmt::Display for L402Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            L402Scheme::L402 => write!(f, "L402"),
+            L402Scheme::Lsat => write!(f, "LSAT"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct L402Challenge {
+    pub scheme: L402Scheme,
+    /// The base64 macaroon, sent as `macaroon=` or the older `token=`
+    pub macaroon: String,
+    pub invoice: Bolt11Invoice,
+    /// Any other auth params the server sent, names lowercased
+    pub params: HashMap<String, String>,
+}
+
+impl L402Challenge {
+    /// Parses the L402 challenge out of a `WWW-Authenticate` header value,
+    /// preferring an `L402` challenge over a legacy `LSAT` one. Malformed
+    /// challenges of other schemes are skipped.
+    pub fn parse(header: &str) -> Result<Self, L402ChallengeError> {
+        Self::from_challenges(&parse_l402_challenges(header)?)
+    }
+
+    /// Picks the L402 challenge out of several `WWW-Authenticate` header
+    /// values, as servers may send one header per challenge.
+    pub fn parse_all<'a>(
+        headers: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, L402ChallengeError> {
+        let mut challenges = vec![];
+        for header in headers {
+            challenges.extend(parse_l402_challenges(header)?);
+        }
+        Self::from_challenges(&challenges)
+    }
+
+    fn from_challenges(challenges: &[AuthChallenge]) -> Result<Self, L402ChallengeError> {
+        let challenge = challenges
+            .iter()
+            .find(|challenge| challenge.scheme.eq_ignore_ascii_case("L402"))
+            .or_else(|| {
+                challenges
+                    .iter()
+                    .find(|challenge| challenge.scheme.eq_ignore_ascii_case("LSAT"))
+            })
+            .ok_or(L402ChallengeError::NoL402Challenge)?;
+
+        let scheme = if challenge.scheme.eq_ignore_ascii_case("L402") {
+            L402Scheme::L402
+        } else {
+            L402Scheme::Lsat
+        };
+
+        let mut params = HashMap::new();
+        for (key, value) in &challenge.params {
+            if params.insert(key.clone(), value.clone()).is_some() {
+                return Err(L402ChallengeError::DuplicateParam(key.clone()));
+            }
+        }
+
+        if params.contains_key("macaroon") && params.contains_key("token") {
+            return Err(L402ChallengeError::DuplicateParam("macaroon".to_owned()));
+        }
+        let macaroon = params
+            .remove("macaroon")
+            .or_else(|| params.remove("token"))
+            .filter(|macaroon| !macaroon.is_empty())
+            .ok_or(L402ChallengeError::MissingParam("macaroon"))?;
+        let invoice = params
+            .remove("invoice")
+            .filter(|invoice| !invoice.is_empty())
+            .ok_or(L402ChallengeError::MissingParam("invoice"))?;
+        let invoice = invoice
+            .parse::<Bolt11Invoice>()
+            .map_err(|err| L402ChallengeError::InvalidInvoice(err.to_string()))?;
+
+        Ok(Self {
+            scheme,
+            macaroon,
+            invoice,
+            params,
+        })
+    }
+}
+
+// tchar from RFC 7230 section 3.2.6
+fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+fn is_token68_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"-._~+/".contains(&c)
+}
+
+fn is_whitespace(c: u8) -> bool {
+    c == b' ' || c == b'\t'
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    // skips whitespace and empty list elements
+    fn skip_separators(&mut self) {
+        while self.peek().is_some_and(|c| is_whitespace(c) || c == b',') {
+            self.pos += 1;
+        }
+    }
+
+    fn token(&mut self) -> Option<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_tchar) {
+            self.pos += 1;
+        }
+        (self.pos > start).then(|| self.slice(start))
+    }
+
+    fn slice(&self, start: usize) -> String {
+        String::from_utf8_lossy(&self.input[start..self.pos]).into_owned()
+    }
+
+    fn quoted_string(&mut self) -> Result<String, L402ChallengeError> {
+        // opening quote
+        self.pos += 1;
+        let mut value = vec![];
+        loop {
+            match self.peek() {
+                None => {
+                    return Err(L402ChallengeError::Malformed(
+                        self.pos,
+                        "unterminated quoted string",
+                    ))
+                }
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(String::from_utf8_lossy(&value).into_owned());
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = self.peek().ok_or(L402ChallengeError::Malformed(
+                        self.pos,
+                        "unterminated quoted string",
+                    ))?;
+                    value.push(escaped);
+                    self.pos += 1;
+                }
+                Some(c) => {
+                    value.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    // Tries to read `token BWS "=" BWS ( token / quoted-string )`, rewinding
+    // and returning None if the input isn't an auth-param
+    fn auth_param(&mut self) -> Result<Option<(String, String)>, L402ChallengeError> {
+        let start = self.pos;
+        let Some(name) = self.token() else {
+            return Ok(None);
+        };
+        self.skip_whitespace();
+        if self.peek() != Some(b'=') {
+            self.pos = start;
+            return Ok(None);
+        }
+        self.pos += 1;
+        self.skip_whitespace();
+
+        let value = match self.peek() {
+            Some(b'"') => self.quoted_string()?,
+            Some(c) if is_tchar(c) => self.unquoted_value(),
+            // `name=` followed by nothing is the padding of a token68
+            _ => {
+                self.pos = start;
+                return Ok(None);
+            }
+        };
+
+        Ok(Some((name.to_ascii_lowercase(), value)))
+    }
+
+    // a token, leniently also allowing the `/` and `=` of unquoted base64 values
+    fn unquoted_value(&mut self) -> String {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| is_tchar(c) || c == b'/' || c == b'=')
+        {
+            self.pos += 1;
+        }
+        self.slice(start)
+    }
+
+    fn token68(&mut self) -> Option<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_token68_char) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return None;
+        }
+        while self.peek() == Some(b'=') {
+            self.pos += 1;
+        }
+        Some(self.slice(start))
+    }
+
+    fn at_list_end(&mut self) -> bool {
+        self.skip_whitespace();
+        matches!(self.peek(), None | Some(b','))
+    }
+
+    fn challenge(&mut self) -> Result<Option<AuthChallenge>, L402ChallengeError> {
+        self.skip_separators();
+        if self.peek().is_none() {
+            return Ok(None);
+        }
+
+        let scheme = self.token().ok_or(L402ChallengeError::Malformed(
+            self.pos,
+            "expected auth scheme",
+        ))?;
+        self.challenge_params(scheme).map(Some)
+    }
+
+    fn challenge_params(&mut self, scheme: String) -> Result<AuthChallenge, L402ChallengeError> {
+        let mut challenge = AuthChallenge {
+            scheme,
+            token68: None,
+            params: vec![],
+        };
+
+        if !self.at_list_end() {
+            if let Some(param) = self.auth_param()? {
+                challenge.params.push(param);
+            } else {
+                let start = self.pos;
+                match self.token68() {
+                    Some(token68) if self.at_list_end() => challenge.token68 = Some(token68),
+                    // a bare token is the scheme of the next challenge
+                    _ => self.pos = start,
+                }
+                return Ok(challenge);
+            }
+        }
+
+        // the remaining params of this challenge, up to the next scheme
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None => break,
+                Some(b',') => {
+                    self.skip_separators();
+                    match self.auth_param()? {
+                        Some(param) => challenge.params.push(param),
+                        None => break,
+                    }
+                }
+                // some servers separate params with whitespace only
+                Some(_) => match self.auth_param()? {
+                    Some(param) => challenge.params.push(param),
+                    None => {
+                        return Err(L402ChallengeError::Malformed(
+                            self.pos,
+                            "expected ',' between auth params",
+                        ))
+                    }
+                },
+            }
+        }
+
+        Ok(challenge)
+    }
+
+    // Skips the rest of a malformed challenge: its list element and any auth
+    // params following it, up to what looks like the next challenge
+    fn skip_challenge(&mut self) {
+        loop {
+            let mut quoted = false;
+            while let Some(c) = self.peek() {
+                match c {
+                    b'"' => quoted = !quoted,
+                    b'\\' if quoted => self.pos += 1,
+                    b',' if !quoted => break,
+                    _ => {}
+                }
+                self.pos += 1;
+            }
+
+            self.skip_separators();
+            let start = self.pos;
+            if !matches!(self.auth_param(), Ok(Some(_))) {
+                self.pos = start;
+                return;
+            }
+        }
+    }
+}
+
+/// Parses every challenge in a `WWW-Authenticate` header value
+pub fn parse_www_authenticate(header: &str) -> Result<Vec<AuthChallenge>, L402ChallengeError> {
+    let mut parser = Parser {
+        input: header.as_bytes(),
+        pos: 0,
+    };
+
+    let mut challenges = vec![];
+    while let Some(challenge) = parser.challenge()? {
+        challenges.push(challenge);
+    }
+
+    Ok(challenges)
+}
+
+fn is_l402_scheme(scheme: &str) -> bool {
+    scheme.eq_ignore_ascii_case("L402") || scheme.eq_ignore_ascii_case("LSAT")
+}
+
+// Like `parse_www_authenticate`, but skips malformed challenges of other
+// schemes so they can't hide the L402 one
+fn parse_l402_challenges(header: &str) -> Result<Vec<AuthChallenge>, L402ChallengeError> {
+    let mut parser = Parser {
+        input: header.as_bytes(),
+        pos: 0,
+    };
+
+    let mut challenges = vec![];
+    loop {
+        parser.skip_separators();
+        if parser.peek().is_none() {
+            return Ok(challenges);
+        }
+
+        let scheme = parser.token();
+        let challenge = match &scheme {
+            Some(scheme) => parser.challenge_params(scheme.clone()),
+            None => Err(L402ChallengeError::Malformed(
+                parser.pos,
+                "expected auth scheme",
+            )),
+        };
+        match challenge {
+            Ok(challenge) => challenges.push(challenge),
+            Err(err) if scheme.as_deref().is_some_and(is_l402_scheme) => return Err(err),
+            Err(_) => parser.skip_challenge(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use L402ChallengeError::*;
+
+    use super::*;
+    use crate::lightning::testing::invoice;
+
+    // the scheme and macaroon a header parses to
+    type Expected = Result<(L402Scheme, &'static str), L402ChallengeError>;
+
+    // INVOICE in a header is replaced by a valid invoice
+    const VECTORS: &[(&str, Expected)] = &[
+        (
+            r#"L402 macaroon="AgEEbHNhdA==", invoice="INVOICE""#,
+            Ok((L402Scheme::L402, "AgEEbHNhdA==")),
+        ),
+        (
+            r#"L402 token="AgEEbHNhdA==", invoice="INVOICE""#,
+            Ok((L402Scheme::L402, "AgEEbHNhdA==")),
+        ),
+        (
+            r#"LSAT macaroon="AgEEbHNhdA==", invoice="INVOICE""#,
+            Ok((L402Scheme::Lsat, "AgEEbHNhdA==")),
+        ),
+        (
+            r#"LSAT token="AgEEbHNhdA==", invoice="INVOICE""#,
+            Ok((L402Scheme::Lsat, "AgEEbHNhdA==")),
+        ),
+        // reordered params
+        (
+            r#"L402 invoice="INVOICE", macaroon="AgEEbHNhdA==""#,
+            Ok((L402Scheme::L402, "AgEEbHNhdA==")),
+        ),
+        // case insensitive scheme and param names
+        (
+            r#"l402 MACAROON="m", Invoice="INVOICE""#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        // unquoted base64 values
+        (
+            r#"L402 macaroon=AgEE/bHN+hdA==, invoice=INVOICE"#,
+            Ok((L402Scheme::L402, "AgEE/bHN+hdA==")),
+        ),
+        // whitespace variants
+        (
+            "  L402   macaroon = \"m\" ,   invoice=\"INVOICE\"  ",
+            Ok((L402Scheme::L402, "m")),
+        ),
+        (
+            "L402\tmacaroon=\"m\",\tinvoice=\"INVOICE\"",
+            Ok((L402Scheme::L402, "m")),
+        ),
+        (
+            r#"L402 macaroon="m" invoice="INVOICE""#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        (
+            r#"L402 macaroon="m",, ,invoice="INVOICE",  "#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        // several challenges in one header
+        (
+            r#"Basic realm="api", L402 macaroon="m", invoice="INVOICE""#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        (
+            r#"L402 macaroon="m", invoice="INVOICE", Basic realm="api""#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        (
+            r#"Bearer abc==, L402 macaroon="m", invoice="INVOICE""#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        (
+            r#"Digest realm="a \"quoted\", realm", nonce="n", L402 macaroon="m", invoice="INVOICE""#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        (
+            r#"LSAT macaroon="old", invoice="INVOICE", L402 macaroon="m", invoice="INVOICE""#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        // malformed challenges of other schemes are skipped
+        (
+            r#"Foo a="b" c d, L402 macaroon="m", invoice="INVOICE""#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        (
+            r#"Foo a="b" c d, realm="x", L402 macaroon="m", invoice="INVOICE""#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        (
+            r#"=broken, L402 macaroon="m", invoice="INVOICE""#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        (
+            r#"L402 macaroon="m", invoice="INVOICE", Foo a="unterminated"#,
+            Ok((L402Scheme::L402, "m")),
+        ),
+        // errors
+        ("", Err(NoL402Challenge)),
+        (r#"Basic realm="api""#, Err(NoL402Challenge)),
+        (r#"L402 macaroon="m""#, Err(MissingParam("invoice"))),
+        (r#"L402 invoice="INVOICE""#, Err(MissingParam("macaroon"))),
+        (
+            r#"L402 macaroon="", invoice="INVOICE""#,
+            Err(MissingParam("macaroon")),
+        ),
+        (
+            r#"L402 macaroon="m", invoice="""#,
+            Err(MissingParam("invoice")),
+        ),
+        (
+            r#"L402 macaroon="a", macaroon="b", invoice="INVOICE""#,
+            Err(DuplicateParam(String::new())),
+        ),
+        (
+            r#"L402 macaroon="m", token="m", invoice="INVOICE""#,
+            Err(DuplicateParam(String::new())),
+        ),
+        (
+            r#"L402 macaroon="m", invoice="lnbc1notaninvoice""#,
+            Err(InvalidInvoice(String::new())),
+        ),
+        (
+            r#"L402 macaroon="m, invoice="INVOICE"#,
+            Err(Malformed(0, "")),
+        ),
+        (
+            r#"L402 macaroon="m" junk, invoice="INVOICE""#,
+            Err(Malformed(0, "")),
+        ),
+        (
+            r#"Basic realm="api", LSAT macaroon="m" junk, invoice="INVOICE""#,
+            Err(Malformed(0, "")),
+        ),
+    ];
+
+    #[test]
+    fn parses_test_vectors() {
+        let invoice = invoice(Some(1_000)).payment_request;
+        for (header, expected) in VECTORS {
+            let header = header.replace("INVOICE", &invoice);
+            let parsed = L402Challenge::parse(&header);
+            match (parsed, expected) {
+                (Ok(challenge), Ok((scheme, macaroon))) => {
+                    assert_eq!(challenge.scheme, *scheme, "{header}");
+                    assert_eq!(challenge.macaroon, *macaroon, "{header}");
+                    assert_eq!(challenge.invoice.to_string(), invoice, "{header}");
+                }
+                // only the kind of error is checked, not its details
+                (Err(err), Err(expected)) => assert_eq!(
+                    std::mem::discriminant(&err),
+                    std::mem::discriminant(expected),
+                    "{header}: {err}"
+                ),
+                (parsed, expected) => panic!("{header}: got {parsed:?}, expected {expected:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn missing_params_are_named() {
+        assert_eq!(
+            L402Challenge::parse(r#"L402 macaroon="m""#),
+            Err(MissingParam("invoice"))
+        );
+    }
+
+    #[test]
+    fn keeps_extra_params() {
+        let invoice = invoice(Some(1_000)).payment_request;
+        let challenge = L402Challenge::parse(&format!(
+            r#"L402 macaroon="m", invoice="{invoice}", Version="0""#
+        ))
+        .unwrap();
+        assert_eq!(
+            challenge.params,
+            HashMap::from([("version".to_owned(), "0".to_owned())])
+        );
+    }
+
+    #[test]
+    fn picks_challenge_out_of_several_headers() {
+        let invoice = invoice(Some(1_000)).payment_request;
+        let l402 = format!(r#"L402 macaroon="m", invoice="{invoice}""#);
+        let challenge =
+            L402Challenge::parse_all([r#"Basic realm="api""#, "Foo =", l402.as_str()]).unwrap();
+        assert_eq!(challenge.macaroon, "m");
+
+        assert_eq!(
+            L402Challenge::parse_all([r#"Basic realm="api""#]),
+            Err(NoL402Challenge)
+        );
+    }
+
+    #[test]
+    fn parses_generic_challenges() {
+        let challenges =
+            parse_www_authenticate(r#"Bearer abc==, Basic realm="a \"b\"", charset=UTF-8"#)
+                .unwrap();
+        assert_eq!(
+            challenges,
+            vec![
+                AuthChallenge {
+                    scheme: "Bearer".to_owned(),
+                    token68: Some("abc==".to_owned()),
+                    params: vec![],
+                },
+                AuthChallenge {
+                    scheme: "Basic".to_owned(),
+                    token68: None,
+                    params: vec![
+                        ("realm".to_owned(), "a \"b\"".to_owned()),
+                        ("charset".to_owned(), "UTF-8".to_owned()),
+                    ],
+                },
+            ]
+        );
+        assert!(matches!(
+            parse_www_authenticate(r#"Foo a="b" c d"#),
+            Err(Malformed(..))
+        ));
+    }
+}
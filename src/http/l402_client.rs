@@ -1,16 +1,40 @@
-use std::io::Error;
+use std::sync::Arc;
 
+use async_trait::async_trait;
 use bytes::Bytes;
-use lightning_invoice::{Bolt11Invoice, SignedRawBolt11Invoice};
-use log::info;
+use futures_util::stream::{self, TryStreamExt};
+use lightning_invoice::{Bolt11Invoice, Currency};
+use log::{info, warn};
 use reqwest::header::HeaderValue;
 use reqwest::{Client, Method, Request, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
 
+use super::error::L402Error;
+use super::l402_challenge::{L402Challenge, L402ChallengeError, L402Scheme};
+use super::macaroon::{Macaroon, MacaroonError};
+use super::payment_policy::{PaymentApproval, PaymentPolicy};
+use super::prepaid::PrepaidCredit;
+use super::receipts::{default_receipt_store, Receipt, ReceiptStore};
+use super::token_store::{
+    default_token_store, L402Token, MemoryTokenStore, TokenScope, TokenStore,
+};
 use super::{HttpClient, PinBoxStream};
+use crate::cashu::{CashuWallet, PaymentRequest};
+use crate::lightning::error::LightningError;
+use crate::lightning::invoice::{InvoicePolicy, InvoicePolicyLightning};
+use crate::lightning::manual::ManualLightning;
+use crate::lightning::model::{
+    BalanceResult, CreateInvoiceParams, CreateInvoiceResult, InvoiceStatus, PayInvoiceResult,
+    PayOptions, PaymentStatus,
+};
+use crate::lightning::utils::{decode_invoice, rejected_before_paying, unix_timestamp, whole_sats};
+use crate::lightning::Lightning;
 
 #[derive(Debug, Clone)]
 pub struct L402 {
+    scheme: L402Scheme,
     token: String,
     invoice: Option<Bolt11Invoice>,
     preimage: Option<String>,
@@ -19,6 +43,7 @@ pub struct L402 {
 impl L402 {
     pub fn new(token: String, invoice: Bolt11Invoice) -> Self {
         Self {
+            scheme: L402Scheme::L402,
             token,
             invoice: Some(invoice),
             preimage: None,
@@ -28,32 +53,194 @@ impl L402 {
     pub fn set_preimage(&mut self, preimage: String) {
         self.preimage = Some(preimage);
     }
+
+    pub fn macaroon(&self) -> Result<Macaroon, MacaroonError> {
+        Macaroon::decode(&self.token)
+    }
 }
 
-#[derive(Debug, Clone)]
+impl From<L402Challenge> for L402 {
+    fn from(challenge: L402Challenge) -> Self {
+        Self {
+            scheme: challenge.scheme,
+            token: challenge.macaroon,
+            invoice: Some(challenge.invoice),
+            preimage: None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct L402Client {
     pub client: Client,
-    pub bolt11_endpoint: String,
-    pub api_key: String,
+    pub lightning: Arc<dyn Lightning>,
     pub l402_token: Option<String>,
+    pub token_store: Arc<dyn TokenStore>,
+    pub policy: Arc<PaymentPolicy>,
+    /// Network challenge invoices must be for
+    pub network: Currency,
+    /// Pays challenges offering ecash in an `X-Cashu` header
+    pub cashu: Option<Arc<CashuWallet>>,
+    pub receipts: Arc<dyn ReceiptStore>,
+    /// Payments made for a single call to `execute` before giving up
+    pub max_payments_per_request: u32,
+    pub prepaid: Option<Arc<PrepaidCredit>>,
+    /// Fee and timeout limits for challenge payments
+    pub pay_options: PayOptions,
 }
 
 impl L402Client {
-    pub fn new() -> Self {
-        let (bolt11_endpoint, api_key, l402_token) = load_env_vars();
-        // let client = ClientBuilder::new()
-        //     .connection_verbose(true)
-        //     .build()
-        //     .unwrap();
-        let client = Client::new();
+    /// Pays through the `LIGHTNING_API_ENDPOINT` and `LIGHTNING_API_KEY` env
+    /// vars, erroring if they aren't set. Use `with_lightning` to pay with
+    /// another backend, or `manual` to pay invoices by hand.
+    pub fn new() -> Result<Self, LightningError> {
+        let (bolt11_endpoint, api_key) = load_env_vars()?;
+        Ok(Self::with_lightning(Arc::new(InvoicePolicyLightning::new(
+            Arc::new(Bolt11ApiLightning {
+                client: Client::new(),
+                bolt11_endpoint,
+                api_key,
+            }),
+            InvoicePolicy::default(),
+        ))))
+    }
+
+    /// Asks the user to pay each invoice by hand, showing it as a QR code and
+    /// reading the preimage from stdin
+    pub fn manual() -> Self {
+        Self::with_lightning(Arc::new(InvoicePolicyLightning::new(
+            Arc::new(ManualLightning::new()),
+            InvoicePolicy::default(),
+        )))
+    }
+
+    pub fn with_lightning(lightning: Arc<dyn Lightning>) -> Self {
+        dotenv::dotenv().ok();
         Self {
-            client: client,
-            bolt11_endpoint,
-            api_key,
-            l402_token,
+            client: Client::new(),
+            lightning,
+            l402_token: std::env::var("L402_TOKEN").ok(),
+            token_store: default_token_store(),
+            policy: Arc::new(PaymentPolicy::default()),
+            network: Currency::Bitcoin,
+            cashu: None,
+            receipts: default_receipt_store(),
+            max_payments_per_request: 1,
+            prepaid: None,
+            pay_options: PayOptions::default(),
         }
     }
 
+    /// Replaces the default file backed token store, e.g. with a
+    /// `MemoryTokenStore`
+    pub fn with_token_store(mut self, token_store: Arc<dyn TokenStore>) -> Self {
+        self.token_store = token_store;
+        self
+    }
+
+    /// Replaces the default file backed receipt log
+    pub fn with_receipt_store(mut self, receipts: Arc<dyn ReceiptStore>) -> Self {
+        self.receipts = receipts;
+        self
+    }
+
+    /// Allows paying again if the server answers a paid request with another
+    /// challenge. At least one payment is always allowed.
+    pub fn with_max_payments_per_request(mut self, max_payments: u32) -> Self {
+        self.max_payments_per_request = max_payments.max(1);
+        self
+    }
+
+    pub fn with_pay_options(mut self, pay_options: PayOptions) -> Self {
+        self.pay_options = pay_options;
+        self
+    }
+
+    /// Pays for requests to the credit's origin from a prepaid balance,
+    /// buying the first `amount_sats` right away and topping up when it runs
+    /// low
+    pub async fn with_prepaid_credit(
+        mut self,
+        prepaid: PrepaidCredit,
+    ) -> Result<Self, anyhow::Error> {
+        self.prepaid = Some(Arc::new(prepaid));
+        self.top_up().await?;
+        Ok(self)
+    }
+
+    /// Buys `amount_sats` of prepaid credit. The current token is sent along
+    /// so the server can carry what is left on it over to the new one, and
+    /// it is only replaced once the server accepts the new token.
+    pub async fn top_up(&self) -> Result<(), anyhow::Error> {
+        let prepaid = self
+            .prepaid
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No prepaid credit configured"))?;
+        info!("Buying {} sats of prepaid L402 credit", prepaid.amount_sats);
+
+        let mut request = self.client.get(prepaid.request_url()).build()?;
+        let req_clone = request.try_clone();
+        let stored_token = self.stored_token(request.url())?;
+        if let Some((_, token)) = &stored_token {
+            request
+                .headers_mut()
+                .insert("AUTHORIZATION", token.authorization().parse()?);
+        }
+        let mut response = self.client.execute(request).await?;
+        if response.status() == StatusCode::PAYMENT_REQUIRED {
+            let (paid_response, scope) = self.handle_l402(replay(&req_clone)?, response).await?;
+            if !paid_response.status().is_success() {
+                info!("L402 top up token was rejected, keeping the current one");
+                self.token_store.remove(&scope)?;
+                if let Some((scope, token)) = stored_token {
+                    self.token_store.insert(scope, token)?;
+                }
+            }
+            response = paid_response;
+        }
+
+        let response = response.error_for_status()?;
+        prepaid.update(response.headers());
+
+        Ok(())
+    }
+
+    /// Limits what the client pays for 402 challenges
+    pub fn with_policy(mut self, policy: PaymentPolicy) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    pub fn with_network(mut self, network: Currency) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Pays with ecash from `wallet` instead of Lightning when a 402 offers
+    /// both
+    pub fn with_cashu_wallet(mut self, wallet: Arc<CashuWallet>) -> Self {
+        self.cashu = Some(wallet);
+        self
+    }
+
+    /// A client for a worker, holding only a copy of our token for `url`
+    /// restricted by `caveats`. It pays through the same backend and policy if
+    /// the server rejects the restricted token.
+    pub fn delegate(&self, url: &Url, caveats: &[&str]) -> Result<Self, anyhow::Error> {
+        let (scope, token) = self
+            .token_store
+            .issue(url, caveats)?
+            .ok_or_else(|| anyhow::anyhow!("No L402 token stored for {url}"))?;
+        let token_store = MemoryTokenStore::new();
+        token_store.insert(scope, token)?;
+
+        Ok(Self {
+            l402_token: None,
+            token_store: Arc::new(token_store),
+            ..self.clone()
+        })
+    }
+
     pub fn request(&self, method: Method, url: &str) -> RequestBuilder {
         self.client.request(method, url)
     }
@@ -64,57 +251,275 @@ impl L402Client {
             .unwrap()
     }
 
+    // The stored token for the url, evicting any whose macaroon has expired
+    fn stored_token(&self, url: &Url) -> Result<Option<(TokenScope, L402Token)>, anyhow::Error> {
+        while let Some((scope, token)) = self.token_store.get(url) {
+            if !token.is_expired(unix_timestamp()) {
+                return Ok(Some((scope, token)));
+            }
+            info!("Stored L402 token expired, evicting it");
+            self.token_store.remove(&scope)?;
+        }
+
+        Ok(None)
+    }
+
+    // Pays the challenge and retries with the new token, returning the scope
+    // it is stored under
     async fn handle_l402(
         &self,
         req_clone: Request,
         response: Response,
-    ) -> Result<Response, reqwest::Error> {
+    ) -> Result<(Response, TokenScope), anyhow::Error> {
         info!("L402 Payment Required");
         info!(
             "www-authenticate header: {:?}",
             response.headers().get("www-authenticate")
         );
-        let mut l402 = parse_l402_header(
-            response
-                .headers()
-                .get("www-authenticate")
-                .unwrap()
-                .to_str()
-                .unwrap(),
-        )
-        .unwrap();
-        let preimage = self
-            .pay_invoice(l402.clone().invoice.unwrap())
-            .await
-            .unwrap();
+        let headers = response
+            .headers()
+            .get_all("www-authenticate")
+            .iter()
+            .map(|header| header.to_str())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut l402 = L402::from(L402Challenge::parse_all(headers)?);
+        let invoice = l402
+            .invoice
+            .clone()
+            .ok_or(L402ChallengeError::MissingParam("invoice"))?;
+        validate_invoice(&invoice, &self.network)?;
+        let approval = PaymentApproval::new(req_clone.url().clone(), invoice.clone())
+            .with_pay_options(&self.pay_options);
+        let reservation = self.policy.authorize(&approval).await?;
+        let payment = match self.pay_invoice(invoice.clone()).await {
+            Ok(payment) => payment,
+            Err(err) => {
+                // the payment may still have gone through, e.g. on a dropped
+                // connection, so it keeps counting against the budget
+                if rejected_before_paying(&err) {
+                    self.policy.release(reservation);
+                }
+                return Err(err);
+            }
+        };
+        // the invoice is paid even if the preimage turns out to be unusable
+        let preimage = payment.preimage.clone().unwrap_or_default();
+        self.record_receipt(req_clone.url(), &invoice, &payment, &l402.token, &preimage);
+        payment.require_preimage()?;
+        verify_preimage(&invoice, &preimage)?;
+
+        // keep the paid token for later requests to the paths it covers
+        let token = L402Token {
+            scheme: l402.scheme,
+            macaroon: l402.token.clone(),
+            preimage: preimage.clone(),
+        };
+        let scope = TokenScope::for_token(req_clone.url(), &token);
+        self.token_store.insert(scope.clone(), token)?;
+
         l402.set_preimage(preimage);
         let request = add_l402_header(req_clone, l402);
         info!("Retrying with L402 Header");
-        self.client.execute(request).await
+        Ok((self.client.execute(request).await?, scope))
     }
 
-    pub async fn pay_invoice(&self, invoice: Bolt11Invoice) -> Result<String, Error> {
-        info!("Paying invoice: {}", invoice.to_string());
-        let request = self
-            .client
-            .post(self.bolt11_endpoint.as_str())
-            .header("Authorization", self.api_key.clone())
-            .json(&AlbyBolt11Request {
-                invoice: invoice.to_string(),
-                amount: None,
-            })
-            .build()
-            .unwrap();
+    // The ecash payment request of a 402, if it is one our wallet can pay
+    fn cashu_payment_request(&self, response: &Response) -> Option<PaymentRequest> {
+        let wallet = self.cashu.as_ref()?;
+        let header = response.headers().get("x-cashu")?.to_str().ok()?;
+        let payment_request = match PaymentRequest::decode(header) {
+            Ok(payment_request) => payment_request,
+            Err(err) => {
+                info!("Ignoring X-Cashu header: {err}");
+                return None;
+            }
+        };
 
-        let response = self.client.execute(request).await.unwrap();
+        let unit = payment_request.unit.as_deref().unwrap_or("sat");
+        (payment_request.amount.is_some()
+            && unit == wallet.unit()
+            && payment_request.accepts_mint(wallet.mint_url().as_str()))
+        .then_some(payment_request)
+    }
 
-        info!("Response: {:?}", response);
+    async fn handle_cashu(
+        &self,
+        mut req_clone: Request,
+        payment_request: PaymentRequest,
+    ) -> Result<Response, anyhow::Error> {
+        info!("Paying 402 with ecash");
+        let wallet = self
+            .cashu
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No cashu wallet configured"))?;
+        let amount = payment_request
+            .amount
+            .ok_or(L402ChallengeError::MissingParam("amount"))?;
+        let amount_msat = match wallet.unit() {
+            "sat" => amount * 1000,
+            "msat" => amount,
+            unit => {
+                return Err(L402Error::PaymentDeclined(format!(
+                    "can not check {unit} payments against the policy"
+                ))
+                .into())
+            }
+        };
 
-        let response: AlbyBolt11Response = response.json().await.unwrap();
+        let approval = PaymentApproval::ecash(
+            req_clone.url().clone(),
+            amount_msat,
+            payment_request.description.clone(),
+            wallet.mint_url().as_str(),
+        );
+        let reservation = self.policy.authorize(&approval).await?;
+        let token = match wallet.send(amount).await {
+            Ok(token) => token,
+            Err(err) => {
+                self.policy.release(reservation);
+                return Err(err.into());
+            }
+        };
 
-        info!("Payment Preimage: {}", response.payment_preimage);
+        let response = match token.encode_v4() {
+            Ok(encoded) => match encoded.parse() {
+                Ok(header) => {
+                    req_clone.headers_mut().insert("X-Cashu", header);
+                    info!("Retrying with X-Cashu Header");
+                    self.client.execute(req_clone).await.map_err(Into::into)
+                }
+                Err(err) => Err(anyhow::Error::from(err)),
+            },
+            Err(err) => Err(err.into()),
+        };
 
-        Ok(response.payment_preimage)
+        match response {
+            Ok(response) if response.status().is_success() => Ok(response),
+            response => {
+                // the server didn't take the token, so swap it back into the
+                // wallet before it is lost. Fails if the server redeemed it.
+                match wallet.receive(&token).await {
+                    Ok(_) => {
+                        info!("Took back the ecash token the server didn't accept");
+                        self.policy.release(reservation);
+                    }
+                    Err(err) => warn!("Can not take back the ecash token: {err}"),
+                }
+                response
+            }
+        }
+    }
+
+    // Sends the request, paying for 402 challenges
+    async fn execute_paying(&self, mut request: Request) -> Result<Response, anyhow::Error> {
+        let req_clone = request.try_clone();
+        let stored_token = self.stored_token(request.url())?;
+        if let Some((_, token)) = &stored_token {
+            request
+                .headers_mut()
+                .insert("AUTHORIZATION", token.authorization().parse()?);
+        } else if self.l402_token.is_some() {
+            request
+                .headers_mut()
+                .insert("AUTHORIZATION", self.get_auth_header());
+        }
+        let mut response = self.client.execute(request).await?;
+        let mut stored_scope = stored_token.map(|(scope, _)| scope);
+        let mut paid_scope = None;
+        let mut payments = 0;
+
+        loop {
+            let status = response.status();
+            if status != StatusCode::UNAUTHORIZED && status != StatusCode::PAYMENT_REQUIRED {
+                return Ok(response);
+            }
+
+            if let Some(scope) = stored_scope.take() {
+                info!("Stored L402 token rejected, evicting it");
+                self.token_store.remove(&scope)?;
+                if status == StatusCode::UNAUTHORIZED {
+                    // a 401 carries no challenge, so ask once more without the
+                    // token for a fresh one
+                    info!("Requesting a fresh L402 challenge");
+                    response = self.client.execute(replay(&req_clone)?).await?;
+                    continue;
+                }
+            }
+
+            if let Some(scope) = paid_scope.take() {
+                info!("L402 token we just paid for was rejected, evicting it");
+                self.token_store.remove(&scope)?;
+            }
+
+            // we paid and verified the preimage, but the server still won't
+            // serve us. Paying again is only allowed up to the cap.
+            if payments > 0
+                && (status == StatusCode::UNAUTHORIZED || payments >= self.max_payments_per_request)
+            {
+                return Err(L402Error::PaymentNotAccepted {
+                    url: response.url().to_string(),
+                    payments,
+                }
+                .into());
+            }
+            if status == StatusCode::UNAUTHORIZED {
+                return Ok(response);
+            }
+
+            payments += 1;
+            let request = replay(&req_clone)?;
+            response = match self.cashu_payment_request(&response) {
+                Some(payment_request) => self.handle_cashu(request, payment_request).await?,
+                None => {
+                    let (response, scope) = self.handle_l402(request, response).await?;
+                    paid_scope = Some(scope);
+                    response
+                }
+            };
+        }
+    }
+
+    pub async fn pay_invoice(
+        &self,
+        invoice: Bolt11Invoice,
+    ) -> Result<PayInvoiceResult, anyhow::Error> {
+        info!("Paying invoice: {}", invoice.to_string());
+        let payment = self
+            .lightning
+            .pay_invoice_with_options(invoice.to_string(), self.pay_options.clone())
+            .await?;
+
+        info!("Payment Preimage: {:?}", payment.preimage);
+
+        Ok(payment)
+    }
+
+    // A failure to record is only logged, the payment went through and the
+    // token is still good
+    fn record_receipt(
+        &self,
+        url: &Url,
+        invoice: &Bolt11Invoice,
+        payment: &PayInvoiceResult,
+        macaroon: &str,
+        preimage: &str,
+    ) {
+        let receipt = Receipt {
+            url: url.to_string(),
+            invoice: invoice.to_string(),
+            amount_msat: payment.amount_msat.or(invoice.amount_milli_satoshis()),
+            fee_msat: payment.fee_msat,
+            payment_hash: invoice.payment_hash().to_string(),
+            preimage: preimage.to_owned(),
+            macaroon_id: Macaroon::decode(macaroon)
+                .ok()
+                .map(|macaroon| hex::encode(macaroon.identifier)),
+            paid_at: payment.settled_at,
+        };
+
+        if let Err(err) = self.receipts.append(receipt) {
+            warn!("Can not record L402 receipt for {url}: {err}");
+        }
     }
 }
 
@@ -129,36 +534,53 @@ impl HttpClient for L402Client {
     }
 
     // Execute with L402 Handling
-    async fn execute(&self, mut request: Request) -> Result<Response, reqwest::Error> {
-        let req_clone = request.try_clone().unwrap();
-        if self.l402_token.is_some() {
-            request
-                .headers_mut()
-                .insert("AUTHORIZATION", self.get_auth_header());
+    async fn execute(&self, request: Request) -> Result<Response, anyhow::Error> {
+        let prepaid = self
+            .prepaid
+            .as_ref()
+            .filter(|prepaid| prepaid.covers(request.url()));
+        if prepaid.is_some_and(|prepaid| prepaid.claim_topup()) {
+            // the request may still go through on what is left
+            if let Err(err) = self.top_up().await {
+                warn!("Can not top up prepaid L402 credit: {err}");
+            }
         }
-        let mut response = self.client.execute(request).await?;
-        if response.status() == StatusCode::PAYMENT_REQUIRED {
-            response = self.handle_l402(req_clone, response).await?;
+
+        let response = self.execute_paying(request).await?;
+        if let Some(prepaid) = prepaid {
+            prepaid.update(response.headers());
         }
 
         Ok(response)
     }
 
-    async fn execute_stream(&self, mut request: Request) -> PinBoxStream<Bytes> {
-        if self.l402_token.is_some() {
-            request
-                .headers_mut()
-                .insert("AUTHORIZATION", self.get_auth_header());
+    // Pays and retries like `execute`, then streams the body. Failures are
+    // yielded as stream items.
+    async fn execute_stream(&self, request: Request) -> PinBoxStream<Bytes> {
+        let response = match self.execute(request).await {
+            Ok(response) => response,
+            Err(err) => return Box::pin(stream::once(async move { Err(err) })),
         };
 
-        Box::pin(self.execute(request).await.unwrap().bytes_stream())
+        match response.error_for_status() {
+            Ok(response) => Box::pin(response.bytes_stream().map_err(anyhow::Error::from)),
+            Err(err) => Box::pin(stream::once(async move { Err(err.into()) })),
+        }
     }
 }
 
+// Streaming bodies can't be cloned, so those requests can't be sent again
+fn replay(request: &Option<Request>) -> Result<Request, L402Error> {
+    request
+        .as_ref()
+        .and_then(Request::try_clone)
+        .ok_or(L402Error::RequestNotReplayable)
+}
+
 pub fn add_l402_header(mut request: Request, l402: L402) -> Request {
     request.headers_mut().insert(
         "AUTHORIZATION",
-        format!("L402 {}:{}", l402.token, l402.preimage.unwrap())
+        format!("{} {}:{}", l402.scheme, l402.token, l402.preimage.unwrap())
             .parse()
             .unwrap(),
     );
@@ -166,45 +588,134 @@ pub fn add_l402_header(mut request: Request, l402: L402) -> Request {
     request
 }
 
-pub fn parse_l402_header(header: &str) -> Result<L402, Error> {
-    let mut parts = header.split(' ');
-    // ignore L402
-    parts.next();
-    let token = parts
-        .next()
-        .unwrap_or_default()
-        .replace("token=\"", "")
-        .replace("\"", "")
-        .replace(",", "");
-    let invoice = parts
-        .next()
-        .unwrap_or_default()
-        .replace("invoice=\"", "")
-        .replace("\"", "");
-    if token.is_empty() || invoice.is_empty() {
-        return Err(Error::new(
-            std::io::ErrorKind::InvalidData,
-            "Invalid L402 Header",
-        ));
+/// Rejects challenge invoices that can't or shouldn't be paid
+fn validate_invoice(invoice: &Bolt11Invoice, network: &Currency) -> Result<(), L402Error> {
+    if invoice.currency() != *network {
+        return Err(L402Error::WrongNetwork {
+            expected: network.clone(),
+            actual: invoice.currency(),
+        });
+    }
+
+    if invoice.is_expired() {
+        return Err(L402Error::InvoiceExpired);
+    }
+
+    Ok(())
+}
+
+/// Checks the backend's preimage actually hashes to the invoice payment hash,
+/// so a bad wallet response doesn't surface as a 401 from the server
+fn verify_preimage(invoice: &Bolt11Invoice, preimage: &str) -> Result<(), L402Error> {
+    let preimage_bytes =
+        hex::decode(preimage).map_err(|err| L402Error::InvalidPreimage(err.to_string()))?;
+    if preimage_bytes.len() != 32 {
+        return Err(L402Error::InvalidPreimage(format!(
+            "expected 32 bytes, got {}",
+            preimage_bytes.len()
+        )));
     }
 
-    info!("Token: {}", token);
+    let payment_hash = invoice.payment_hash().to_string();
+    if hex::encode(Sha256::digest(&preimage_bytes)) != payment_hash {
+        return Err(L402Error::PreimageMismatch {
+            preimage: preimage.to_owned(),
+            payment_hash,
+        });
+    }
+
+    Ok(())
+}
+
+pub fn parse_l402_header(header: &str) -> Result<L402, L402ChallengeError> {
+    let challenge = L402Challenge::parse(header)?;
 
-    let invoice =
-        Bolt11Invoice::from_signed(invoice.parse::<SignedRawBolt11Invoice>().unwrap()).unwrap();
-    Ok(L402 {
-        token: token.to_string(),
-        invoice: Some(invoice),
-        preimage: None,
-    })
+    info!("Token: {}", challenge.macaroon);
+
+    Ok(L402::from(challenge))
 }
 
-fn load_env_vars() -> (String, String, Option<String>) {
+fn load_env_vars() -> Result<(String, String), LightningError> {
     dotenv::dotenv().ok();
-    let bolt11_endpoint = std::env::var("LIGHTNING_API_ENDPOINT").unwrap();
-    let api_key = std::env::var("LIGHTNING_API_KEY").unwrap();
-    let l402_token = std::env::var("L402_TOKEN").ok();
-    (bolt11_endpoint, api_key, l402_token)
+    let bolt11_endpoint = std::env::var("LIGHTNING_API_ENDPOINT")
+        .map_err(|_| LightningError::MissingSetting("LIGHTNING_API_ENDPOINT"))?;
+    let api_key = std::env::var("LIGHTNING_API_KEY")
+        .map_err(|_| LightningError::MissingSetting("LIGHTNING_API_KEY"))?;
+    Ok((bolt11_endpoint, api_key))
+}
+
+/// Pays through an Alby-style `payments/bolt11` endpoint configured with the
+/// `LIGHTNING_API_ENDPOINT` env var. Only paying is supported.
+struct Bolt11ApiLightning {
+    client: Client,
+    bolt11_endpoint: String,
+    api_key: String,
+}
+
+#[async_trait]
+impl Lightning for Bolt11ApiLightning {
+    async fn pay_invoice_with_options(
+        &self,
+        payment_request: String,
+        options: PayOptions,
+    ) -> Result<PayInvoiceResult, anyhow::Error> {
+        options.ensure_supported("LIGHTNING_API_ENDPOINT", &["amount_msat_for_zero_amount"])?;
+        let invoice = decode_invoice(&payment_request)?.invoice;
+        let amount = whole_sats(
+            options.amount_override_msat(&invoice),
+            "LIGHTNING_API_ENDPOINT",
+        )?;
+        let request = self
+            .client
+            .post(self.bolt11_endpoint.as_str())
+            .header("Authorization", self.api_key.clone())
+            .json(&AlbyBolt11Request {
+                invoice: payment_request,
+                amount,
+            })
+            .build()?;
+
+        let response = self.client.execute(request).await?.error_for_status()?;
+
+        info!("Response: {:?}", response);
+
+        let response: AlbyBolt11Response = response.json().await?;
+
+        Ok(PayInvoiceResult {
+            payment_hash: response.payment_hash,
+            preimage: Some(response.payment_preimage),
+            fee_msat: response.fee.map(|fee| fee * 1000),
+            amount_msat: response.amount.map(|amount| amount * 1000),
+            settled_at: unix_timestamp(),
+        })
+    }
+
+    async fn create_invoice(
+        &self,
+        _params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        Err(anyhow::anyhow!(
+            "LIGHTNING_API_ENDPOINT backend can only pay invoices"
+        ))
+    }
+
+    async fn invoice_status(&self, _payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error> {
+        Err(anyhow::anyhow!(
+            "LIGHTNING_API_ENDPOINT backend can only pay invoices"
+        ))
+    }
+
+    async fn payment_status(&self, _payment_hash: &str) -> Result<PaymentStatus, anyhow::Error> {
+        Err(anyhow::anyhow!(
+            "LIGHTNING_API_ENDPOINT backend can only pay invoices"
+        ))
+    }
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error> {
+        Err(anyhow::anyhow!(
+            "LIGHTNING_API_ENDPOINT backend can only pay invoices"
+        ))
+    }
 }
 
 #[derive(Serialize)]
@@ -216,5 +727,272 @@ struct AlbyBolt11Request {
 
 #[derive(Deserialize, Debug)]
 struct AlbyBolt11Response {
+    payment_hash: String,
     payment_preimage: String,
+    fee: Option<u64>,
+    amount: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    use super::*;
+    use crate::cashu::testing::FakeMint;
+    use crate::http::receipts::{MemoryReceiptStore, ReceiptQuery};
+    use crate::http::token_store::MemoryTokenStore;
+    use crate::lightning::testing::{respond, serve, FakeLightning, FakeRequest, Pay};
+    use crate::server::{L402Minter, MintedChallenge};
+
+    // A server answering every request, paid or not, with the same challenge
+    async fn rejecting_server(lightning: &Arc<FakeLightning>) -> (Url, String) {
+        let challenge = minter(lightning).mint(10, "/").await.unwrap();
+        let payment_hash = hex::encode(challenge.macaroon.payment_hash().unwrap());
+        let header = challenge.www_authenticate();
+        let url = serve(move |_| {
+            let mut response = respond(402, "");
+            response
+                .headers_mut()
+                .insert("www-authenticate", header.parse().unwrap());
+            response
+        })
+        .await;
+
+        (url, payment_hash)
+    }
+
+    fn client(
+        lightning: &Arc<FakeLightning>,
+    ) -> (L402Client, Arc<MemoryTokenStore>, Arc<MemoryReceiptStore>) {
+        let tokens = Arc::new(MemoryTokenStore::new());
+        let receipts = Arc::new(MemoryReceiptStore::new());
+        let mut client = L402Client::with_lightning(lightning.clone())
+            .with_token_store(tokens.clone())
+            .with_receipt_store(receipts.clone());
+        client.l402_token = None;
+        (client, tokens, receipts)
+    }
+
+    #[tokio::test]
+    async fn evicts_a_paid_token_the_server_rejects() {
+        let lightning = Arc::new(FakeLightning::new());
+        let (url, _) = rejecting_server(&lightning).await;
+        let (client, tokens, receipts) = client(&lightning);
+
+        let err = client
+            .execute(client.get(url.as_str()).build().unwrap())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<L402Error>(),
+            Some(L402Error::PaymentNotAccepted { payments: 1, .. })
+        ));
+        assert_eq!(lightning.paid().len(), 1);
+        assert!(tokens.get(&url).is_none());
+        assert_eq!(receipts.query(&ReceiptQuery::default()).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_budget_reserved_when_a_payment_may_have_gone_through() {
+        let lightning = Arc::new(FakeLightning::new());
+        let (url, _) = rejecting_server(&lightning).await;
+        let (client, _, _) = client(&lightning);
+        let client = client.with_policy(
+            PaymentPolicy::new().with_max_sats_per_window(15, Duration::from_secs(3600)),
+        );
+
+        // the connection drops after the payment went out
+        *lightning.pay.lock().unwrap() = Pay::Lose;
+        client
+            .execute(client.get(url.as_str()).build().unwrap())
+            .await
+            .unwrap_err();
+
+        *lightning.pay.lock().unwrap() = Pay::Succeed { fee_msat: 0 };
+        let err = client
+            .execute(client.get(url.as_str()).build().unwrap())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<L402Error>(),
+                Some(L402Error::PaymentDeclined(_))
+            ),
+            "{err}"
+        );
+        assert_eq!(lightning.paid().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn records_a_receipt_when_the_preimage_does_not_match() {
+        let lightning = Arc::new(FakeLightning::new());
+        let (url, payment_hash) = rejecting_server(&lightning).await;
+        lightning.set_preimage(&payment_hash, &hex::encode([7; 32]));
+        let (client, tokens, receipts) = client(&lightning);
+
+        let result = client
+            .execute(client.get(url.as_str()).build().unwrap())
+            .await;
+
+        assert!(result.is_err());
+        assert!(tokens.get(&url).is_none());
+        let receipts = receipts.query(&ReceiptQuery::default()).unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].payment_hash, payment_hash);
+    }
+
+    // A prepaid server whose top up endpoint answers with `challenge` and
+    // only accepts tokens for `accepted`, recording the authorization top up
+    // requests arrive with
+    fn prepaid_server(
+        challenge: &MintedChallenge,
+        accepted: &Macaroon,
+        seen: &Arc<Mutex<Vec<Option<String>>>>,
+    ) -> impl Fn(FakeRequest) -> hyper::Response<hyper::Body> {
+        let header = challenge.www_authenticate();
+        let paid = format!("L402 {}:", accepted.encode());
+        let seen = seen.clone();
+        move |request| {
+            let authorization = request
+                .headers
+                .get("authorization")
+                .map(|authorization| authorization.to_str().unwrap().to_owned());
+            seen.lock().unwrap().push(authorization.clone());
+            if authorization.is_some_and(|authorization| authorization.starts_with(&paid)) {
+                let mut response = respond(200, "");
+                response
+                    .headers_mut()
+                    .insert("x-l402-balance-msat", "100000".parse().unwrap());
+                return response;
+            }
+
+            let mut response = respond(402, "");
+            response
+                .headers_mut()
+                .insert("www-authenticate", header.parse().unwrap());
+            response
+        }
+    }
+
+    async fn prepaid_client(
+        lightning: &Arc<FakeLightning>,
+        url: &Url,
+    ) -> (L402Client, Arc<MemoryTokenStore>, L402Token) {
+        let (mut client, tokens, _) = client(lightning);
+        client.prepaid = Some(Arc::new(PrepaidCredit::new(
+            url.join("topup").unwrap(),
+            100,
+            10,
+        )));
+        let current = minter(lightning).mint(100, "/").await.unwrap();
+        let token = L402Token {
+            scheme: L402Scheme::L402,
+            macaroon: current.macaroon.encode(),
+            preimage: hex::encode([1; 32]),
+        };
+        tokens.insert(TokenScope::host(url), token.clone()).unwrap();
+        (client, tokens, token)
+    }
+
+    fn minter(lightning: &Arc<FakeLightning>) -> L402Minter {
+        L402Minter::new(b"root key".to_vec(), "bullpen", lightning.clone())
+    }
+
+    #[tokio::test]
+    async fn buys_prepaid_credit_up_front() {
+        let lightning = Arc::new(FakeLightning::new());
+        let challenge = minter(&lightning).mint(100, "/").await.unwrap();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let url = serve(prepaid_server(&challenge, &challenge.macaroon, &seen)).await;
+        let (client, tokens, _) = client(&lightning);
+
+        let client = client
+            .with_prepaid_credit(PrepaidCredit::new(url.join("topup").unwrap(), 100, 10))
+            .await
+            .unwrap();
+
+        assert_eq!(lightning.paid().len(), 1);
+        assert_eq!(client.prepaid.unwrap().balance_msat(), Some(100_000));
+        let (_, token) = tokens.get(&url).unwrap();
+        assert_eq!(token.macaroon, challenge.macaroon.encode());
+    }
+
+    #[tokio::test]
+    async fn top_up_sends_the_current_token_along() {
+        let lightning = Arc::new(FakeLightning::new());
+        let challenge = minter(&lightning).mint(100, "/").await.unwrap();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let url = serve(prepaid_server(&challenge, &challenge.macaroon, &seen)).await;
+        let (client, tokens, current) = prepaid_client(&lightning, &url).await;
+
+        client.top_up().await.unwrap();
+
+        assert_eq!(seen.lock().unwrap()[0], Some(current.authorization()));
+        let (_, token) = tokens.get(&url).unwrap();
+        assert_eq!(token.macaroon, challenge.macaroon.encode());
+    }
+
+    #[tokio::test]
+    async fn top_up_keeps_the_current_token_if_the_new_one_is_rejected() {
+        let lightning = Arc::new(FakeLightning::new());
+        let challenge = minter(&lightning).mint(100, "/").await.unwrap();
+        let other = minter(&lightning).mint(100, "/").await.unwrap();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let url = serve(prepaid_server(&challenge, &other.macaroon, &seen)).await;
+        let (client, tokens, current) = prepaid_client(&lightning, &url).await;
+
+        assert!(client.top_up().await.is_err());
+
+        assert_eq!(lightning.paid().len(), 1);
+        assert_eq!(tokens.get(&url).unwrap().1, current);
+    }
+
+    #[tokio::test]
+    async fn takes_back_ecash_the_server_does_not_accept() {
+        let (_mint, mint_url) = FakeMint::serve().await;
+        let wallet = Arc::new(CashuWallet::new(mint_url.clone(), "sat").unwrap());
+        wallet.mint("q", 64).await.unwrap();
+
+        let mut request = vec![];
+        ciborium::into_writer(
+            &ciborium::Value::Map(vec![
+                ("a".into(), 10.into()),
+                ("u".into(), "sat".into()),
+                (
+                    "m".into(),
+                    ciborium::Value::Array(vec![mint_url.as_str().into()]),
+                ),
+            ]),
+            &mut request,
+        )
+        .unwrap();
+        let header = format!("creqA{}", URL_SAFE_NO_PAD.encode(request));
+        let url = serve(move |request| {
+            if request.headers.contains_key("x-cashu") {
+                return respond(500, "");
+            }
+            let mut response = respond(402, "");
+            response
+                .headers_mut()
+                .insert("x-cashu", header.parse().unwrap());
+            response
+        })
+        .await;
+        let lightning = Arc::new(FakeLightning::new());
+        let (client, _, _) = client(&lightning);
+        let client = client.with_cashu_wallet(wallet.clone());
+
+        let response = client
+            .execute(client.get(url.as_str()).build().unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(wallet.balance(), 64);
+    }
 }
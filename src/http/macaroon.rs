@@ -0,0 +1,567 @@
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum MacaroonError {
+    #[error("macaroon is not valid base64")]
+    InvalidBase64,
+
+    #[error("unsupported macaroon version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("malformed macaroon at byte {0}: {1}")]
+    Malformed(usize, &'static str),
+}
+
+// field types of the v2 binary format
+const FIELD_EOS: u64 = 0;
+const FIELD_LOCATION: u64 = 1;
+const FIELD_IDENTIFIER: u64 = 2;
+const FIELD_VID: u64 = 4;
+const FIELD_SIGNATURE: u64 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caveat {
+    pub location: Option<String>,
+    pub identifier: Vec<u8>,
+    /// Only set on third party caveats
+    pub verification_id: Option<Vec<u8>>,
+}
+
+impl Caveat {
+    pub fn is_first_party(&self) -> bool {
+        self.verification_id.is_none()
+    }
+
+    /// The caveat condition, if it is utf8
+    pub fn condition(&self) -> Option<&str> {
+        std::str::from_utf8(&self.identifier).ok()
+    }
+
+    /// Splits `key=value` (L402) or `key value` (lnd) conditions
+    pub fn key_value(&self) -> Option<(&str, &str)> {
+        let condition = self.condition()?;
+        let (key, value) = condition
+            .split_once('=')
+            .or_else(|| condition.split_once(' '))?;
+        Some((key.trim(), value.trim()))
+    }
+}
+
+/// A decoded macaroon. The signature is kept as is, it can only be checked by
+/// the service holding the root key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macaroon {
+    pub location: Option<String>,
+    pub identifier: Vec<u8>,
+    pub caveats: Vec<Caveat>,
+    pub signature: Vec<u8>,
+}
+
+impl Macaroon {
+    /// Mints a macaroon signed with `root_key`, deriving the signing key as
+    /// libmacaroons does so other implementations can verify it
+    pub fn new(root_key: &[u8], identifier: Vec<u8>, location: Option<String>) -> Self {
+        Self {
+            location,
+            signature: hmac_sha256(&derive_key(root_key), &identifier),
+            identifier,
+            caveats: vec![],
+        }
+    }
+
+    /// Recomputes the signature chain from the root key. Fails for macaroons
+    /// with third party caveats, which we never mint.
+    pub fn verify_signature(&self, root_key: &[u8]) -> bool {
+        let mut key = derive_key(root_key);
+        let mut data = &self.identifier;
+        for caveat in &self.caveats {
+            if !caveat.is_first_party() {
+                return false;
+            }
+            key = hmac_sha256(&key, data);
+            data = &caveat.identifier;
+        }
+
+        // verify_slice compares in constant time
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&key).expect("HMAC accepts keys of any length");
+        mac.update(data);
+        mac.verify_slice(&self.signature).is_ok()
+    }
+
+    /// Decodes a base64 macaroon as sent in L402 challenges and tokens
+    pub fn decode(encoded: &str) -> Result<Self, MacaroonError> {
+        let encoded = encoded.trim();
+        let bytes = [STANDARD, URL_SAFE, STANDARD_NO_PAD, URL_SAFE_NO_PAD]
+            .iter()
+            .find_map(|engine| engine.decode(encoded).ok())
+            .ok_or(MacaroonError::InvalidBase64)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Base64 encodes the macaroon in the v2 binary format
+    pub fn encode(&self) -> String {
+        STANDARD.encode(self.to_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![2];
+
+        if let Some(location) = &self.location {
+            write_field(&mut bytes, FIELD_LOCATION, location.as_bytes());
+        }
+        write_field(&mut bytes, FIELD_IDENTIFIER, &self.identifier);
+        write_varint(&mut bytes, FIELD_EOS);
+
+        for caveat in &self.caveats {
+            if let Some(location) = &caveat.location {
+                write_field(&mut bytes, FIELD_LOCATION, location.as_bytes());
+            }
+            write_field(&mut bytes, FIELD_IDENTIFIER, &caveat.identifier);
+            if let Some(verification_id) = &caveat.verification_id {
+                write_field(&mut bytes, FIELD_VID, verification_id);
+            }
+            write_varint(&mut bytes, FIELD_EOS);
+        }
+        write_varint(&mut bytes, FIELD_EOS);
+
+        write_field(&mut bytes, FIELD_SIGNATURE, &self.signature);
+        bytes
+    }
+
+    /// Appends a first party caveat, chaining the signature as
+    /// `HMAC-SHA256(signature, condition)`. Anyone holding the macaroon can
+    /// restrict it further this way, but no one can remove a caveat without
+    /// the root key.
+    pub fn add_first_party_caveat(&mut self, condition: &str) {
+        self.signature = hmac_sha256(&self.signature, condition.as_bytes());
+
+        self.caveats.push(Caveat {
+            location: None,
+            identifier: condition.as_bytes().to_vec(),
+            verification_id: None,
+        });
+    }
+
+    /// Parses the v2 binary format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MacaroonError> {
+        let mut reader = Reader { bytes, pos: 0 };
+
+        let version = reader.byte()?;
+        if version != 2 {
+            return Err(MacaroonError::UnsupportedVersion(version));
+        }
+
+        let mut field = reader.field()?;
+        let location = match field {
+            Some((FIELD_LOCATION, data)) => {
+                field = reader.field()?;
+                Some(reader.string(data)?)
+            }
+            _ => None,
+        };
+        let identifier = match field {
+            Some((FIELD_IDENTIFIER, data)) => data.to_vec(),
+            _ => return Err(reader.malformed("expected macaroon identifier")),
+        };
+        if reader.field()?.is_some() {
+            return Err(reader.malformed("expected end of macaroon header"));
+        }
+
+        let mut caveats = vec![];
+        loop {
+            let mut field = reader.field()?;
+            let Some(first) = field else {
+                break;
+            };
+
+            let location = if first.0 == FIELD_LOCATION {
+                field = reader.field()?;
+                Some(reader.string(first.1)?)
+            } else {
+                None
+            };
+            let identifier = match field {
+                Some((FIELD_IDENTIFIER, data)) => data.to_vec(),
+                _ => return Err(reader.malformed("expected caveat identifier")),
+            };
+            let verification_id = match reader.field()? {
+                Some((FIELD_VID, data)) => {
+                    if reader.field()?.is_some() {
+                        return Err(reader.malformed("expected end of caveat"));
+                    }
+                    Some(data.to_vec())
+                }
+                None => None,
+                Some(_) => return Err(reader.malformed("expected end of caveat")),
+            };
+
+            caveats.push(Caveat {
+                location,
+                identifier,
+                verification_id,
+            });
+        }
+
+        let signature = match reader.field()? {
+            Some((FIELD_SIGNATURE, data)) => data.to_vec(),
+            _ => return Err(reader.malformed("expected macaroon signature")),
+        };
+
+        Ok(Self {
+            location,
+            identifier,
+            caveats,
+            signature,
+        })
+    }
+
+    pub fn first_party_caveats(&self) -> impl Iterator<Item = &Caveat> {
+        self.caveats.iter().filter(|caveat| caveat.is_first_party())
+    }
+
+    /// Values of the first party caveats with the given key, in order. Later
+    /// caveats can only narrow earlier ones.
+    pub fn caveat_values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.first_party_caveats()
+            .filter_map(|caveat| caveat.key_value())
+            .filter(move |(caveat_key, _)| *caveat_key == key)
+            .map(|(_, value)| value)
+    }
+
+    /// The earliest expiry of any caveat, as a unix timestamp. Understands the
+    /// L402 `<service>_valid_until` and lnd `time-before` caveats.
+    pub fn expires_at(&self) -> Option<u64> {
+        self.first_party_caveats()
+            .filter_map(|caveat| caveat.key_value())
+            .filter_map(|(key, value)| {
+                if key.ends_with("valid_until") || key == "expires_at" {
+                    value.parse::<u64>().ok()
+                } else if key == "time-before" {
+                    value.parse::<u64>().ok().or_else(|| parse_rfc3339(value))
+                } else {
+                    None
+                }
+            })
+            .min()
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// The payment hash of an L402 identifier: a big endian u16 version 0,
+    /// then the 32 byte payment hash and the 32 byte token id
+    pub fn payment_hash(&self) -> Option<[u8; 32]> {
+        if self.identifier.len() != 66 || self.identifier[..2] != [0, 0] {
+            return None;
+        }
+        self.identifier[2..34].try_into().ok()
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn malformed(&self, reason: &'static str) -> MacaroonError {
+        MacaroonError::Malformed(self.pos, reason)
+    }
+
+    fn byte(&mut self) -> Result<u8, MacaroonError> {
+        let byte = *self
+            .bytes
+            .get(self.pos)
+            .ok_or(self.malformed("unexpected end of macaroon"))?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, MacaroonError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(self.malformed("varint overflow"))
+    }
+
+    // Reads a field, returning None at an end of section marker
+    fn field(&mut self) -> Result<Option<(u64, &'a [u8])>, MacaroonError> {
+        let field_type = self.varint()?;
+        if field_type == FIELD_EOS {
+            return Ok(None);
+        }
+
+        let len =
+            usize::try_from(self.varint()?).map_err(|_| self.malformed("field length overflow"))?;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(self.malformed("field runs past end of macaroon"))?;
+        let data = &self.bytes[self.pos..end];
+        self.pos = end;
+
+        Ok(Some((field_type, data)))
+    }
+
+    fn string(&self, data: &[u8]) -> Result<String, MacaroonError> {
+        String::from_utf8(data.to_vec()).map_err(|_| self.malformed("location is not utf8"))
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn derive_key(root_key: &[u8]) -> Vec<u8> {
+    hmac_sha256(b"macaroons-key-generator", root_key)
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn write_field(bytes: &mut Vec<u8>, field_type: u64, data: &[u8]) {
+    write_varint(bytes, field_type);
+    write_varint(bytes, data.len() as u64);
+    bytes.extend_from_slice(data);
+}
+
+// Parses `YYYY-MM-DDTHH:MM:SS[.fraction](Z|±HH:MM)` into a unix timestamp
+fn parse_rfc3339(value: &str) -> Option<u64> {
+    let (date, time) = value.split_once(['T', 't', ' '])?;
+
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: i64 = date_parts.next()?.parse().ok()?;
+    let day: i64 = date_parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (clock, offset) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+        (clock, 0)
+    } else {
+        let split = time.rfind(['+', '-'])?;
+        let (clock, offset) = time.split_at(split);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        let offset = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+        (clock, sign * offset)
+    };
+    let clock = clock.split('.').next()?;
+    let mut clock_parts = clock.splitn(3, ':');
+    let hour: i64 = clock_parts.next()?.parse().ok()?;
+    let minute: i64 = clock_parts.next()?.parse().ok()?;
+    let second: i64 = clock_parts.next()?.parse().ok()?;
+
+    // days since the epoch, from Howard Hinnant's days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let timestamp = days * 86400 + hour * 3600 + minute * 60 + second - offset;
+    u64::try_from(timestamp).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a v2 macaroon with a location, one first party and one third party
+    // caveat, written out field by field
+    fn sample_bytes() -> Vec<u8> {
+        let mut bytes = vec![2];
+        bytes.extend([FIELD_LOCATION as u8, 7]);
+        bytes.extend(b"example");
+        bytes.extend([FIELD_IDENTIFIER as u8, 3]);
+        bytes.extend(b"id1");
+        bytes.push(0);
+        bytes.extend([FIELD_IDENTIFIER as u8, 24]);
+        bytes.extend(b"svc_valid_until=17000000");
+        bytes.push(0);
+        bytes.extend([FIELD_LOCATION as u8, 3]);
+        bytes.extend(b"tp1");
+        bytes.extend([FIELD_IDENTIFIER as u8, 3]);
+        bytes.extend(b"cid");
+        bytes.extend([FIELD_VID as u8, 2, 0xaa, 0xbb]);
+        bytes.push(0);
+        bytes.push(0);
+        bytes.extend([FIELD_SIGNATURE as u8, 32]);
+        bytes.extend([0x11; 32]);
+        bytes
+    }
+
+    #[test]
+    fn decodes_v2_binary_format() {
+        let macaroon = Macaroon::from_bytes(&sample_bytes()).unwrap();
+        assert_eq!(macaroon.location.as_deref(), Some("example"));
+        assert_eq!(macaroon.identifier, b"id1");
+        assert_eq!(macaroon.signature, vec![0x11; 32]);
+        assert_eq!(
+            macaroon.caveats,
+            vec![
+                Caveat {
+                    location: None,
+                    identifier: b"svc_valid_until=17000000".to_vec(),
+                    verification_id: None,
+                },
+                Caveat {
+                    location: Some("tp1".to_owned()),
+                    identifier: b"cid".to_vec(),
+                    verification_id: Some(vec![0xaa, 0xbb]),
+                },
+            ]
+        );
+        assert_eq!(macaroon.first_party_caveats().count(), 1);
+        assert_eq!(macaroon.to_bytes(), sample_bytes());
+    }
+
+    #[test]
+    fn decodes_every_base64_flavour() {
+        let bytes = sample_bytes();
+        for engine in [STANDARD, URL_SAFE, STANDARD_NO_PAD, URL_SAFE_NO_PAD] {
+            let encoded = engine.encode(&bytes);
+            assert_eq!(
+                Macaroon::decode(&format!(" {encoded}\n"))
+                    .unwrap()
+                    .to_bytes(),
+                bytes,
+                "{encoded}"
+            );
+        }
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let mut macaroon = Macaroon::new(b"root", b"identifier".to_vec(), None);
+        macaroon.add_first_party_caveat("route=/v1/chat");
+        macaroon.caveats.push(Caveat {
+            location: Some("https://auth.example".to_owned()),
+            identifier: vec![0; 300],
+            verification_id: Some(vec![1; 200]),
+        });
+
+        assert_eq!(Macaroon::decode(&macaroon.encode()).unwrap(), macaroon);
+    }
+
+    #[test]
+    fn rejects_malformed_macaroons() {
+        assert_eq!(
+            Macaroon::decode("not base64!"),
+            Err(MacaroonError::InvalidBase64)
+        );
+
+        let mut bytes = sample_bytes();
+        bytes[0] = 1;
+        assert_eq!(
+            Macaroon::from_bytes(&bytes),
+            Err(MacaroonError::UnsupportedVersion(1))
+        );
+
+        let bytes = sample_bytes();
+        for len in 1..bytes.len() {
+            assert!(
+                matches!(
+                    Macaroon::from_bytes(&bytes[..len]),
+                    Err(MacaroonError::Malformed(..))
+                ),
+                "truncated to {len} bytes"
+            );
+        }
+
+        // a header without an identifier
+        assert!(matches!(
+            Macaroon::from_bytes(&[2, FIELD_LOCATION as u8, 1, b'x', 0, 0]),
+            Err(MacaroonError::Malformed(_, "expected macaroon identifier"))
+        ));
+        // a field claiming more bytes than are left
+        assert!(matches!(
+            Macaroon::from_bytes(&[2, FIELD_IDENTIFIER as u8, 0xff, 0x01, b'x']),
+            Err(MacaroonError::Malformed(
+                _,
+                "field runs past end of macaroon"
+            ))
+        ));
+    }
+
+    #[test]
+    fn reads_expiry_caveats() {
+        let mut macaroon = Macaroon::new(b"root", b"id".to_vec(), None);
+        assert_eq!(macaroon.expires_at(), None);
+        assert!(!macaroon.is_expired(u64::MAX));
+
+        macaroon.add_first_party_caveat("svc_valid_until=2000");
+        macaroon.add_first_party_caveat("time-before 1970-01-01T00:25:00Z");
+        macaroon.add_first_party_caveat("expires_at=3000");
+        assert_eq!(macaroon.expires_at(), Some(1500));
+        assert!(!macaroon.is_expired(1499));
+        assert!(macaroon.is_expired(1500));
+    }
+
+    #[test]
+    fn parses_rfc3339_timestamps() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("2023-11-14T22:13:20Z"), Some(1_700_000_000));
+        assert_eq!(
+            parse_rfc3339("2023-11-14T23:13:20.123+01:00"),
+            Some(1_700_000_000)
+        );
+        assert_eq!(
+            parse_rfc3339("2023-11-14 21:13:20-01:00"),
+            Some(1_700_000_000)
+        );
+        assert_eq!(parse_rfc3339("2023-13-14T22:13:20Z"), None);
+        assert_eq!(parse_rfc3339("yesterday"), None);
+    }
+
+    #[test]
+    fn reads_l402_payment_hash() {
+        let mut identifier = vec![0, 0];
+        identifier.extend([0xab; 32]);
+        identifier.extend([0xcd; 32]);
+        let macaroon = Macaroon::new(b"root", identifier.clone(), None);
+        assert_eq!(macaroon.payment_hash(), Some([0xab; 32]));
+
+        identifier[1] = 1;
+        let macaroon = Macaroon::new(b"root", identifier, None);
+        assert_eq!(macaroon.payment_hash(), None);
+    }
+
+    #[test]
+    fn splits_caveat_conditions() {
+        let mut macaroon = Macaroon::new(b"root", b"id".to_vec(), None);
+        macaroon.add_first_party_caveat("route = /v1/chat");
+        macaroon.add_first_party_caveat("ipaddr 10.0.0.1");
+        macaroon.add_first_party_caveat("route=/v1/chat/stream");
+
+        assert_eq!(
+            macaroon.caveat_values("route").collect::<Vec<_>>(),
+            vec!["/v1/chat", "/v1/chat/stream"]
+        );
+        assert_eq!(
+            macaroon.caveat_values("ipaddr").collect::<Vec<_>>(),
+            vec!["10.0.0.1"]
+        );
+    }
+}
@@ -1,7 +1,21 @@
 pub mod base_client;
+pub mod error;
+pub mod l402_challenge;
 pub mod l402_client;
+pub mod macaroon;
+pub mod payment_policy;
+pub mod prepaid;
+pub mod receipts;
 pub mod replit_client;
+pub mod token_store;
 
 pub use base_client::{HttpClient, PinBoxStream};
+pub use error::L402Error;
+pub use l402_challenge::{L402Challenge, L402ChallengeError};
 pub use l402_client::L402Client;
+pub use macaroon::{Caveat, Macaroon, MacaroonError};
+pub use payment_policy::{PaymentApproval, PaymentApprover, PaymentPolicy, Reservation};
+pub use prepaid::PrepaidCredit;
+pub use receipts::{FileReceiptStore, MemoryReceiptStore, Receipt, ReceiptQuery, ReceiptStore};
 pub use replit_client::ReplitClient;
+pub use token_store::{FileTokenStore, L402Token, MemoryTokenStore, TokenScope, TokenStore};
@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription};
+use url::Url;
+
+use super::error::L402Error;
+use crate::lightning::model::PayOptions;
+
+/// What an L402 server is asking us to pay, as shown to a `PaymentApprover`
+#[derive(Debug, Clone)]
+pub struct PaymentApproval {
+    pub url: Url,
+    /// None when paying with ecash
+    pub invoice: Option<Bolt11Invoice>,
+    pub amount_msat: Option<u64>,
+    pub description: Option<String>,
+    /// The payee node, or the mint for ecash payments
+    pub payee: String,
+    /// Seconds since the unix epoch at which the invoice expires
+    pub expires_at: Option<u64>,
+}
+
+impl PaymentApproval {
+    pub fn new(url: Url, invoice: Bolt11Invoice) -> Self {
+        let description = match invoice.description() {
+            Bolt11InvoiceDescription::Direct(description) => Some(description.to_string()),
+            Bolt11InvoiceDescription::Hash(_) => None,
+        };
+        let payee = invoice
+            .payee_pub_key()
+            .copied()
+            .unwrap_or_else(|| invoice.recover_payee_pub_key())
+            .to_string();
+
+        Self {
+            url,
+            amount_msat: invoice.amount_milli_satoshis(),
+            description,
+            payee,
+            expires_at: invoice.expires_at().map(|expires_at| expires_at.as_secs()),
+            invoice: Some(invoice),
+        }
+    }
+
+    /// Takes the amount to pay a zero amount invoice from `options`
+    pub fn with_pay_options(mut self, options: &PayOptions) -> Self {
+        if let Some(invoice) = &self.invoice {
+            self.amount_msat = options.amount_msat(invoice);
+        }
+        self
+    }
+
+    pub fn ecash(url: Url, amount_msat: u64, description: Option<String>, mint: &str) -> Self {
+        Self {
+            url,
+            invoice: None,
+            amount_msat: Some(amount_msat),
+            description,
+            payee: mint.to_owned(),
+            expires_at: None,
+        }
+    }
+}
+
+/// An amount `PaymentPolicy::authorize` reserved, to hand back to `release`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservation(Option<u64>);
+
+#[async_trait]
+pub trait PaymentApprover: Send + Sync {
+    /// Returns whether the payment may go ahead
+    async fn approve(&self, approval: &PaymentApproval) -> bool;
+}
+
+/// Limits on what `L402Client` pays for 402 challenges. The default pays any
+/// invoice.
+#[derive(Default)]
+pub struct PaymentPolicy {
+    max_sats_per_request: Option<u64>,
+    max_sats_per_window: Option<(u64, Duration)>,
+    approver: Option<Arc<dyn PaymentApprover>>,
+    // id, time and amount of the payments inside the current window
+    payments: Mutex<VecDeque<(u64, Instant, u64)>>,
+    next_id: AtomicU64,
+}
+
+impl PaymentPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_sats_per_request(mut self, max_sats: u64) -> Self {
+        self.max_sats_per_request = Some(max_sats);
+        self
+    }
+
+    pub fn with_max_sats_per_window(mut self, max_sats: u64, window: Duration) -> Self {
+        self.max_sats_per_window = Some((max_sats, window));
+        self
+    }
+
+    pub fn with_approver(mut self, approver: Arc<dyn PaymentApprover>) -> Self {
+        self.approver = Some(approver);
+        self
+    }
+
+    /// Checks the payment against the policy and reserves its amount in the
+    /// spending window. Pass the reservation to `release` if the payment then
+    /// fails.
+    pub async fn authorize(&self, approval: &PaymentApproval) -> Result<Reservation, L402Error> {
+        let amount_msat = approval.amount_msat.ok_or_else(|| {
+            L402Error::PaymentDeclined("invoice does not specify an amount".to_owned())
+        })?;
+
+        if let Some(max_sats) = self.max_sats_per_request {
+            if amount_msat > max_sats * 1000 {
+                return Err(L402Error::PaymentDeclined(format!(
+                    "{} msat exceeds the {} sat per request limit",
+                    amount_msat, max_sats
+                )));
+            }
+        }
+
+        if let Some(approver) = &self.approver {
+            if !approver.approve(approval).await {
+                return Err(L402Error::PaymentDeclined(
+                    "rejected by approver".to_owned(),
+                ));
+            }
+        }
+
+        let Some((max_sats, window)) = self.max_sats_per_window else {
+            return Ok(Reservation(None));
+        };
+        let mut payments = self.payments.lock().unwrap();
+        let now = Instant::now();
+        while payments
+            .front()
+            .is_some_and(|(_, paid_at, _)| now.duration_since(*paid_at) > window)
+        {
+            payments.pop_front();
+        }
+
+        let spent_msat: u64 = payments.iter().map(|(_, _, amount)| amount).sum();
+        if spent_msat + amount_msat > max_sats * 1000 {
+            return Err(L402Error::PaymentDeclined(format!(
+                "{} msat would exceed the {} sat limit per {:?}",
+                amount_msat, max_sats, window
+            )));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        payments.push_back((id, now, amount_msat));
+
+        Ok(Reservation(Some(id)))
+    }
+
+    /// Releases the amount reserved by `authorize` for a payment that failed
+    pub fn release(&self, reservation: Reservation) {
+        let Reservation(Some(id)) = reservation else {
+            return;
+        };
+
+        let mut payments = self.payments.lock().unwrap();
+        payments.retain(|(reserved_id, _, _)| *reserved_id != id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::testing::invoice;
+
+    fn approval(amount_msat: Option<u64>) -> PaymentApproval {
+        let invoice = invoice(amount_msat).payment_request.parse().unwrap();
+        PaymentApproval::new(Url::parse("https://example.com/v1").unwrap(), invoice)
+    }
+
+    fn reserved_ids(policy: &PaymentPolicy) -> Vec<u64> {
+        policy
+            .payments
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, _, _)| *id)
+            .collect()
+    }
+
+    struct Deny;
+
+    #[async_trait]
+    impl PaymentApprover for Deny {
+        async fn approve(&self, _: &PaymentApproval) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn only_tracks_payments_with_a_window() {
+        let policy = PaymentPolicy::new().with_max_sats_per_request(10);
+        for _ in 0..3 {
+            let reservation = policy.authorize(&approval(Some(10_000))).await.unwrap();
+            assert_eq!(reservation, Reservation(None));
+        }
+        assert!(reserved_ids(&policy).is_empty());
+
+        let err = policy.authorize(&approval(Some(11_000))).await.unwrap_err();
+        assert!(matches!(err, L402Error::PaymentDeclined(_)));
+    }
+
+    #[tokio::test]
+    async fn releases_the_reservation_it_is_given() {
+        let policy = PaymentPolicy::new().with_max_sats_per_window(25, Duration::from_secs(60));
+
+        let first = policy.authorize(&approval(Some(10_000))).await.unwrap();
+        let second = policy.authorize(&approval(Some(10_000))).await.unwrap();
+        let err = policy.authorize(&approval(Some(10_000))).await.unwrap_err();
+        assert!(matches!(err, L402Error::PaymentDeclined(_)));
+
+        policy.release(first);
+        let Reservation(Some(second_id)) = second else {
+            panic!("payment is not reserved");
+        };
+        assert_eq!(reserved_ids(&policy), vec![second_id]);
+        // releasing twice doesn't free another payment's amount
+        policy.release(first);
+        assert_eq!(reserved_ids(&policy), vec![second_id]);
+
+        policy.authorize(&approval(Some(10_000))).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn zero_amount_invoices_use_the_pay_options_amount() {
+        let policy = PaymentPolicy::new().with_max_sats_per_request(10);
+
+        let err = policy.authorize(&approval(None)).await.unwrap_err();
+        assert!(matches!(err, L402Error::PaymentDeclined(_)));
+
+        let options = PayOptions::new().with_amount_msat_for_zero_amount(5_000);
+        let zero_amount = approval(None).with_pay_options(&options);
+        assert_eq!(zero_amount.amount_msat, Some(5_000));
+        policy.authorize(&zero_amount).await.unwrap();
+
+        let over_limit = approval(None)
+            .with_pay_options(&PayOptions::new().with_amount_msat_for_zero_amount(20_000));
+        assert!(policy.authorize(&over_limit).await.is_err());
+        // the invoice amount wins over the option
+        assert_eq!(
+            approval(Some(1_000)).with_pay_options(&options).amount_msat,
+            Some(1_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn approver_can_decline() {
+        let policy = PaymentPolicy::new()
+            .with_max_sats_per_window(100, Duration::from_secs(60))
+            .with_approver(Arc::new(Deny));
+
+        let err = policy.authorize(&approval(Some(1_000))).await.unwrap_err();
+        assert!(
+            matches!(err, L402Error::PaymentDeclined(reason) if reason == "rejected by approver")
+        );
+        assert!(reserved_ids(&policy).is_empty());
+    }
+}
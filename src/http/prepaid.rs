@@ -0,0 +1,75 @@
+use std::sync::Mutex;
+
+use reqwest::header::{HeaderMap, HeaderName};
+use url::Url;
+
+/// Credit bought up front from a server selling prepaid balance under one
+/// L402 token. `topup_url` is requested with `?amount=<sats>`, which the
+/// server answers with a 402 for that amount. The server reports what is left
+/// in `balance_header`, in msat, and the client tops up again once it drops
+/// below `threshold_sats`.
+#[derive(Debug)]
+pub struct PrepaidCredit {
+    pub topup_url: Url,
+    pub amount_sats: u64,
+    pub threshold_sats: u64,
+    pub balance_header: HeaderName,
+    // None until the server reports a balance, and while topping up
+    balance_msat: Mutex<Option<u64>>,
+}
+
+impl PrepaidCredit {
+    pub fn new(topup_url: Url, amount_sats: u64, threshold_sats: u64) -> Self {
+        Self {
+            topup_url,
+            amount_sats,
+            threshold_sats,
+            balance_header: HeaderName::from_static("x-l402-balance-msat"),
+            balance_msat: Mutex::new(None),
+        }
+    }
+
+    pub fn with_balance_header(mut self, balance_header: HeaderName) -> Self {
+        self.balance_header = balance_header;
+        self
+    }
+
+    pub fn balance_msat(&self) -> Option<u64> {
+        *self.balance_msat.lock().unwrap()
+    }
+
+    /// Whether the credit pays for requests to the url, i.e. it is on the
+    /// same origin as the top up url
+    pub fn covers(&self, url: &Url) -> bool {
+        url.origin() == self.topup_url.origin()
+    }
+
+    pub fn request_url(&self) -> Url {
+        let mut url = self.topup_url.clone();
+        url.query_pairs_mut()
+            .append_pair("amount", &self.amount_sats.to_string());
+        url
+    }
+
+    /// Records the balance the server reported, if any
+    pub fn update(&self, headers: &HeaderMap) {
+        let balance = headers
+            .get(&self.balance_header)
+            .and_then(|balance| balance.to_str().ok())
+            .and_then(|balance| balance.trim().parse::<u64>().ok());
+        if let Some(balance) = balance {
+            *self.balance_msat.lock().unwrap() = Some(balance);
+        }
+    }
+
+    /// Returns whether the balance is below the threshold, claiming the top
+    /// up so concurrent requests don't buy credit twice
+    pub fn claim_topup(&self) -> bool {
+        let mut balance_msat = self.balance_msat.lock().unwrap();
+        if balance_msat.is_some_and(|balance| balance < self.threshold_sats * 1000) {
+            *balance_msat = None;
+            return true;
+        }
+        false
+    }
+}
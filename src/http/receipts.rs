@@ -0,0 +1,425 @@
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Proof we paid for an L402 token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub url: String,
+    pub invoice: String,
+    pub amount_msat: Option<u64>,
+    pub fee_msat: Option<u64>,
+    pub payment_hash: String,
+    pub preimage: String,
+    /// Hex encoded macaroon identifier, if the macaroon could be decoded
+    pub macaroon_id: Option<String>,
+    /// Seconds since the unix epoch
+    pub paid_at: u64,
+}
+
+impl Receipt {
+    pub fn host(&self) -> Option<String> {
+        Url::parse(&self.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_owned))
+    }
+}
+
+/// Filters receipts, every field that is set has to match
+#[derive(Debug, Clone, Default)]
+pub struct ReceiptQuery {
+    pub host: Option<String>,
+    pub payment_hash: Option<String>,
+    /// Paid at or after, in seconds since the unix epoch
+    pub since: Option<u64>,
+    /// Paid before, in seconds since the unix epoch
+    pub until: Option<u64>,
+    /// At least this amount, receipts without an amount don't match
+    pub min_amount_msat: Option<u64>,
+    /// At most this amount, receipts without an amount don't match
+    pub max_amount_msat: Option<u64>,
+}
+
+impl ReceiptQuery {
+    pub fn matches(&self, receipt: &Receipt) -> bool {
+        self.host
+            .as_ref()
+            .is_none_or(|host| receipt.host().as_ref() == Some(host))
+            && self
+                .payment_hash
+                .as_ref()
+                .is_none_or(|payment_hash| &receipt.payment_hash == payment_hash)
+            && self.since.is_none_or(|since| receipt.paid_at >= since)
+            && self.until.is_none_or(|until| receipt.paid_at < until)
+            && self.min_amount_msat.is_none_or(|min_amount_msat| {
+                receipt
+                    .amount_msat
+                    .is_some_and(|amount| amount >= min_amount_msat)
+            })
+            && self.max_amount_msat.is_none_or(|max_amount_msat| {
+                receipt
+                    .amount_msat
+                    .is_some_and(|amount| amount <= max_amount_msat)
+            })
+    }
+}
+
+/// Append only log of receipts
+pub trait ReceiptStore: Send + Sync {
+    fn append(&self, receipt: Receipt) -> Result<(), anyhow::Error>;
+    /// Matching receipts, oldest first
+    fn query(&self, query: &ReceiptQuery) -> Result<Vec<Receipt>, anyhow::Error>;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryReceiptStore {
+    receipts: Mutex<Vec<Receipt>>,
+}
+
+impl MemoryReceiptStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ReceiptStore for MemoryReceiptStore {
+    fn append(&self, receipt: Receipt) -> Result<(), anyhow::Error> {
+        self.receipts.lock().unwrap().push(receipt);
+        Ok(())
+    }
+
+    fn query(&self, query: &ReceiptQuery) -> Result<Vec<Receipt>, anyhow::Error> {
+        Ok(self
+            .receipts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|receipt| query.matches(receipt))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Receipts appended to a file as JSON lines, existing lines are never
+/// rewritten
+#[derive(Debug)]
+pub struct FileReceiptStore {
+    path: PathBuf,
+    // serializes appends from clones of the same client
+    lock: Mutex<()>,
+}
+
+impl FileReceiptStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        Ok(Self {
+            path,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Opens the store at `L402_RECEIPTS`, or `~/.bullpen/l402_receipts.jsonl`
+    pub fn open_default() -> Result<Self, anyhow::Error> {
+        Self::open(default_path()?)
+    }
+}
+
+impl ReceiptStore for FileReceiptStore {
+    fn append(&self, receipt: Receipt) -> Result<(), anyhow::Error> {
+        let _lock = self.lock.lock().unwrap();
+
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        // the preimages are bearer credentials
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&self.path)?;
+
+        let mut line = serde_json::to_string(&receipt)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+
+        Ok(())
+    }
+
+    fn query(&self, query: &ReceiptQuery) -> Result<Vec<Receipt>, anyhow::Error> {
+        let _lock = self.lock.lock().unwrap();
+        if !self.path.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut receipts = vec![];
+        for (index, line) in BufReader::new(std::fs::File::open(&self.path)?)
+            .lines()
+            .enumerate()
+        {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // e.g. a line cut short by a crash mid append, the rest are good
+            let receipt: Receipt = match serde_json::from_str(&line) {
+                Ok(receipt) => receipt,
+                Err(err) => {
+                    warn!(
+                        "Skipping corrupt receipt on line {} of {}: {err}",
+                        index + 1,
+                        self.path.display()
+                    );
+                    continue;
+                }
+            };
+            if query.matches(&receipt) {
+                receipts.push(receipt);
+            }
+        }
+
+        Ok(receipts)
+    }
+}
+
+fn default_path() -> Result<PathBuf, anyhow::Error> {
+    dotenv::dotenv().ok();
+    if let Ok(path) = std::env::var("L402_RECEIPTS") {
+        return Ok(PathBuf::from(path));
+    }
+
+    let home = std::env::var("HOME")
+        .map_err(|_| anyhow::anyhow!("Neither L402_RECEIPTS nor HOME is set"))?;
+    Ok(PathBuf::from(home)
+        .join(".bullpen")
+        .join("l402_receipts.jsonl"))
+}
+
+/// The file backed store, falling back to memory if it can't be opened
+pub fn default_receipt_store() -> Arc<dyn ReceiptStore> {
+    match FileReceiptStore::open_default() {
+        Ok(store) => Arc::new(store),
+        Err(err) => {
+            warn!("Can not open L402 receipt store, keeping receipts in memory: {err}");
+            Arc::new(MemoryReceiptStore::new())
+        }
+    }
+}
+
+pub fn export_json(receipts: &[Receipt]) -> Result<String, anyhow::Error> {
+    Ok(serde_json::to_string_pretty(receipts)?)
+}
+
+pub fn export_csv(receipts: &[Receipt]) -> String {
+    let mut csv = String::from(
+        "paid_at,url,invoice,amount_msat,fee_msat,payment_hash,preimage,macaroon_id\n",
+    );
+    for receipt in receipts {
+        let fields = [
+            receipt.paid_at.to_string(),
+            receipt.url.clone(),
+            receipt.invoice.clone(),
+            receipt
+                .amount_msat
+                .map(|amount| amount.to_string())
+                .unwrap_or_default(),
+            receipt
+                .fee_msat
+                .map(|fee| fee.to_string())
+                .unwrap_or_default(),
+            receipt.payment_hash.clone(),
+            receipt.preimage.clone(),
+            receipt.macaroon_id.clone().unwrap_or_default(),
+        ];
+        let fields = fields
+            .iter()
+            .map(|field| csv_field(field))
+            .collect::<Vec<_>>();
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+// Quotes fields per RFC 4180 where needed
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt(url: &str, amount_msat: Option<u64>, paid_at: u64) -> Receipt {
+        Receipt {
+            url: url.to_owned(),
+            invoice: "lnbc10n1".to_owned(),
+            amount_msat,
+            fee_msat: Some(0),
+            payment_hash: format!("{paid_at:064x}"),
+            preimage: "00".repeat(32),
+            macaroon_id: None,
+            paid_at,
+        }
+    }
+
+    fn receipts() -> Vec<Receipt> {
+        vec![
+            receipt("https://api.example.com/v1/chat", Some(10_000), 100),
+            receipt("https://api.example.com/v1/images", Some(100_000), 200),
+            receipt("https://other.example.com/", None, 300),
+        ]
+    }
+
+    fn paid_at(query: &ReceiptQuery) -> Vec<u64> {
+        receipts()
+            .into_iter()
+            .filter(|receipt| query.matches(receipt))
+            .map(|receipt| receipt.paid_at)
+            .collect()
+    }
+
+    #[test]
+    fn queries_filter_receipts() {
+        assert_eq!(paid_at(&ReceiptQuery::default()), [100, 200, 300]);
+        assert_eq!(
+            paid_at(&ReceiptQuery {
+                host: Some("api.example.com".to_owned()),
+                ..Default::default()
+            }),
+            [100, 200]
+        );
+        assert_eq!(
+            paid_at(&ReceiptQuery {
+                since: Some(200),
+                until: Some(300),
+                ..Default::default()
+            }),
+            [200]
+        );
+        assert_eq!(
+            paid_at(&ReceiptQuery {
+                payment_hash: Some(format!("{:064x}", 300)),
+                ..Default::default()
+            }),
+            [300]
+        );
+        assert_eq!(
+            paid_at(&ReceiptQuery {
+                min_amount_msat: Some(10_001),
+                ..Default::default()
+            }),
+            [200]
+        );
+        assert_eq!(
+            paid_at(&ReceiptQuery {
+                max_amount_msat: Some(10_000),
+                ..Default::default()
+            }),
+            [100]
+        );
+    }
+
+    #[test]
+    fn csv_quotes_fields_that_need_it() {
+        let mut receipt = receipt("https://api.example.com/a,b", Some(10_000), 100);
+        receipt.invoice = "say \"hi\"".to_owned();
+        receipt.macaroon_id = Some("two\nlines".to_owned());
+
+        let csv = export_csv(&[receipt]);
+        assert_eq!(
+            csv,
+            format!(
+                "paid_at,url,invoice,amount_msat,fee_msat,payment_hash,preimage,macaroon_id\n\
+                 100,\"https://api.example.com/a,b\",\"say \"\"hi\"\"\",10000,0,{:064x},{},\"two\nlines\"\n",
+                100,
+                "00".repeat(32)
+            )
+        );
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("carriage\rreturn"), "\"carriage\rreturn\"");
+    }
+
+    #[test]
+    fn json_export_round_trips() {
+        let receipts = receipts();
+        let json = export_json(&receipts).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<Receipt>>(&json).unwrap(),
+            receipts
+        );
+    }
+
+    #[test]
+    fn file_store_appends_and_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("receipts").join("l402_receipts.jsonl");
+        let store = FileReceiptStore::open(&path).unwrap();
+        assert!(store.query(&ReceiptQuery::default()).unwrap().is_empty());
+
+        for receipt in receipts() {
+            store.append(receipt).unwrap();
+        }
+        // appends from another store for the same file
+        let reopened = FileReceiptStore::open(&path).unwrap();
+        reopened
+            .append(receipt("https://api.example.com/", Some(1_000), 400))
+            .unwrap();
+
+        let all = store.query(&ReceiptQuery::default()).unwrap();
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[..3], receipts()[..]);
+        let query = ReceiptQuery {
+            host: Some("api.example.com".to_owned()),
+            since: Some(200),
+            ..Default::default()
+        };
+        let matching: Vec<u64> = reopened
+            .query(&query)
+            .unwrap()
+            .iter()
+            .map(|receipt| receipt.paid_at)
+            .collect();
+        assert_eq!(matching, [200, 400]);
+    }
+
+    #[test]
+    fn file_store_skips_corrupt_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("l402_receipts.jsonl");
+        let store = FileReceiptStore::open(&path).unwrap();
+        for receipt in receipts() {
+            store.append(receipt).unwrap();
+        }
+
+        // a crash mid append leaves a truncated last line
+        let mut contents = std::fs::read_to_string(&path).unwrap();
+        let last = serde_json::to_string(&receipt("https://api.example.com/", None, 400)).unwrap();
+        contents.push_str(&last[..last.len() / 2]);
+        // and a line overwritten with garbage
+        contents = contents.replacen("{", "not json {", 1);
+        std::fs::write(&path, contents).unwrap();
+
+        let paid_at: Vec<u64> = store
+            .query(&ReceiptQuery::default())
+            .unwrap()
+            .iter()
+            .map(|receipt| receipt.paid_at)
+            .collect();
+        assert_eq!(paid_at, [200, 300]);
+    }
+}
@@ -1,5 +1,5 @@
 use bytes::Bytes;
-
+use futures_util::stream::{self, TryStreamExt};
 use reqwest::{Client, Method, Request, RequestBuilder, Response};
 
 use super::{HttpClient, PinBoxStream};
@@ -38,7 +38,7 @@ impl HttpClient for ReplitClient {
         self.request(Method::POST, url)
     }
 
-    async fn execute(&self, request: Request) -> Result<Response, reqwest::Error> {
+    async fn execute(&self, request: Request) -> Result<Response, anyhow::Error> {
         let mut request = request;
         request
             .headers_mut()
@@ -55,6 +55,9 @@ impl HttpClient for ReplitClient {
             .headers_mut()
             .insert("AUTHORIZATION", self.get_auth_header().parse().unwrap());
 
-        Box::pin(self.client.execute(request).await.unwrap().bytes_stream())
+        match self.client.execute(request).await {
+            Ok(response) => Box::pin(response.bytes_stream().map_err(anyhow::Error::from)),
+            Err(err) => Box::pin(stream::once(async move { Err(err.into()) })),
+        }
     }
 }
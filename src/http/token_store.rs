@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::l402_challenge::L402Scheme;
+use super::macaroon::{Macaroon, MacaroonError};
+
+// Caveats restricting a token to some paths, as minted by `L402Minter`
+const PATH_PREFIX_CAVEAT: &str = "path_prefix";
+const ROUTE_CAVEAT: &str = "route";
+
+/// A paid L402 token, ready to be sent as `Authorization: L402
+/// <macaroon>:<preimage>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct L402Token {
+    pub scheme: L402Scheme,
+    pub macaroon: String,
+    pub preimage: String,
+}
+
+impl L402Token {
+    pub fn authorization(&self) -> String {
+        format!("{} {}:{}", self.scheme, self.macaroon, self.preimage)
+    }
+
+    pub fn decode_macaroon(&self) -> Result<Macaroon, MacaroonError> {
+        Macaroon::decode(&self.macaroon)
+    }
+
+    /// A copy of the token restricted by extra first party caveats, e.g.
+    /// `["svc_valid_until=1700000000"]`, to hand to a less trusted process.
+    /// The preimage is unchanged, the caveats only narrow what the server
+    /// accepts the token for.
+    pub fn attenuate<S: AsRef<str>>(
+        &self,
+        caveats: impl IntoIterator<Item = S>,
+    ) -> Result<Self, MacaroonError> {
+        let mut macaroon = self.decode_macaroon()?;
+        for caveat in caveats {
+            macaroon.add_first_party_caveat(caveat.as_ref());
+        }
+
+        Ok(Self {
+            scheme: self.scheme,
+            macaroon: macaroon.encode(),
+            preimage: self.preimage.clone(),
+        })
+    }
+
+    /// Tokens whose macaroon can't be decoded are assumed not to expire
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.decode_macaroon()
+            .is_ok_and(|macaroon| macaroon.is_expired(now))
+    }
+}
+
+/// The requests a token is used for: every url on `origin` whose path starts
+/// with `path_prefix`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TokenScope {
+    pub origin: String,
+    pub path_prefix: String,
+}
+
+impl TokenScope {
+    pub fn new(url: &Url, path_prefix: &str) -> Self {
+        Self {
+            origin: url.origin().ascii_serialization(),
+            path_prefix: path_prefix.to_owned(),
+        }
+    }
+
+    /// Scope covering every path on the url's host
+    pub fn host(url: &Url) -> Self {
+        Self::new(url, "/")
+    }
+
+    /// Scope of a token bought for the url: the narrowest path its
+    /// `path_prefix` or `route` caveats allow, or the whole host if it has
+    /// none
+    pub fn for_token(url: &Url, token: &L402Token) -> Self {
+        let Ok(macaroon) = token.decode_macaroon() else {
+            return Self::host(url);
+        };
+        let path_prefix = macaroon
+            .caveat_values(PATH_PREFIX_CAVEAT)
+            .chain(macaroon.caveat_values(ROUTE_CAVEAT))
+            .filter(|path_prefix| path_prefix.starts_with('/'))
+            .max_by_key(|path_prefix| path_prefix.len());
+
+        match path_prefix {
+            Some(path_prefix) => Self::new(url, path_prefix),
+            None => Self::host(url),
+        }
+    }
+
+    pub fn matches(&self, url: &Url) -> bool {
+        url.origin().ascii_serialization() == self.origin
+            && url.path().starts_with(&self.path_prefix)
+    }
+}
+
+pub trait TokenStore: Send + Sync {
+    /// Returns the token with the longest path prefix matching the url
+    fn get(&self, url: &Url) -> Option<(TokenScope, L402Token)>;
+    fn insert(&self, scope: TokenScope, token: L402Token) -> Result<(), anyhow::Error>;
+    fn remove(&self, scope: &TokenScope) -> Result<(), anyhow::Error>;
+
+    /// Issues an attenuated copy of the token for the url, leaving the stored
+    /// token untouched
+    fn issue(
+        &self,
+        url: &Url,
+        caveats: &[&str],
+    ) -> Result<Option<(TokenScope, L402Token)>, anyhow::Error> {
+        match self.get(url) {
+            Some((scope, token)) => Ok(Some((scope, token.attenuate(caveats)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryTokenStore {
+    tokens: Mutex<HashMap<TokenScope, L402Token>>,
+}
+
+impl MemoryTokenStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TokenStore for MemoryTokenStore {
+    fn get(&self, url: &Url) -> Option<(TokenScope, L402Token)> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(scope, _)| scope.matches(url))
+            .max_by_key(|(scope, _)| scope.path_prefix.len())
+            .map(|(scope, token)| (scope.clone(), token.clone()))
+    }
+
+    fn insert(&self, scope: TokenScope, token: L402Token) -> Result<(), anyhow::Error> {
+        self.tokens.lock().unwrap().insert(scope, token);
+        Ok(())
+    }
+
+    fn remove(&self, scope: &TokenScope) -> Result<(), anyhow::Error> {
+        self.tokens.lock().unwrap().remove(scope);
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredToken {
+    scope: TokenScope,
+    token: L402Token,
+}
+
+/// Token store persisted as JSON, so tokens survive restarts. Processes can
+/// share the file: each change is applied to what is on disk under a lock,
+/// and lookups read the file again to see tokens others stored.
+#[derive(Debug)]
+pub struct FileTokenStore {
+    path: PathBuf,
+    // last state read from or written to the file, used if it can't be read
+    tokens: MemoryTokenStore,
+}
+
+impl FileTokenStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let store = Self {
+            path: path.as_ref().to_path_buf(),
+            tokens: MemoryTokenStore::new(),
+        };
+        *store.tokens.tokens.lock().unwrap() = store.read()?;
+
+        Ok(store)
+    }
+
+    /// Opens the store at `L402_TOKEN_STORE`, or `~/.bullpen/l402_tokens.json`
+    pub fn open_default() -> Result<Self, anyhow::Error> {
+        Self::open(default_path()?)
+    }
+
+    fn read(&self) -> Result<HashMap<TokenScope, L402Token>, anyhow::Error> {
+        if !self.path.exists() {
+            return Ok(HashMap::new());
+        }
+        let stored: Vec<StoredToken> = serde_json::from_str(&std::fs::read_to_string(&self.path)?)?;
+
+        Ok(stored
+            .into_iter()
+            .map(|StoredToken { scope, token }| (scope, token))
+            .collect())
+    }
+
+    // Applies `change` to the tokens on disk while holding the lock, so
+    // concurrent writers don't drop each other's tokens
+    fn update(
+        &self,
+        change: impl FnOnce(&mut HashMap<TokenScope, L402Token>),
+    ) -> Result<(), anyhow::Error> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.with_extension("lock"))?;
+        lock.lock()?;
+
+        let mut tokens = self.read()?;
+        change(&mut tokens);
+        self.write(&tokens)?;
+        *self.tokens.tokens.lock().unwrap() = tokens;
+
+        Ok(())
+    }
+
+    fn write(&self, tokens: &HashMap<TokenScope, L402Token>) -> Result<(), anyhow::Error> {
+        let stored = tokens
+            .iter()
+            .map(|(scope, token)| StoredToken {
+                scope: scope.clone(),
+                token: token.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        // write then rename so a crash never leaves a truncated store behind
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&stored)?)?;
+        // the preimages are bearer credentials
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o600))?;
+        }
+        std::fs::rename(tmp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn get(&self, url: &Url) -> Option<(TokenScope, L402Token)> {
+        match self.read() {
+            Ok(tokens) => *self.tokens.tokens.lock().unwrap() = tokens,
+            Err(err) => warn!("Can not read L402 token store, using the last tokens read: {err}"),
+        }
+        self.tokens.get(url)
+    }
+
+    fn insert(&self, scope: TokenScope, token: L402Token) -> Result<(), anyhow::Error> {
+        self.update(|tokens| {
+            tokens.insert(scope, token);
+        })
+    }
+
+    /// Leaves the token alone if another process replaced the one we last
+    /// saw under the scope
+    fn remove(&self, scope: &TokenScope) -> Result<(), anyhow::Error> {
+        let seen = self.tokens.tokens.lock().unwrap().get(scope).cloned();
+        self.update(|tokens| {
+            if seen.is_none() || tokens.get(scope) == seen.as_ref() {
+                tokens.remove(scope);
+            }
+        })
+    }
+}
+
+fn default_path() -> Result<PathBuf, anyhow::Error> {
+    dotenv::dotenv().ok();
+    if let Ok(path) = std::env::var("L402_TOKEN_STORE") {
+        return Ok(PathBuf::from(path));
+    }
+
+    let home = std::env::var("HOME")
+        .map_err(|_| anyhow::anyhow!("Neither L402_TOKEN_STORE nor HOME is set"))?;
+    Ok(PathBuf::from(home)
+        .join(".bullpen")
+        .join("l402_tokens.json"))
+}
+
+/// The file backed store, falling back to memory if it can't be opened
+pub fn default_token_store() -> Arc<dyn TokenStore> {
+    match FileTokenStore::open_default() {
+        Ok(store) => Arc::new(store),
+        Err(err) => {
+            warn!("Can not open L402 token store, keeping tokens in memory: {err}");
+            Arc::new(MemoryTokenStore::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::macaroon::Caveat;
+
+    const ROOT_KEY: &[u8] = b"root key";
+
+    fn token() -> L402Token {
+        let mut macaroon = Macaroon::new(ROOT_KEY, b"id".to_vec(), Some("svc".to_owned()));
+        macaroon.add_first_party_caveat("route=/v1");
+        L402Token {
+            scheme: L402Scheme::L402,
+            macaroon: macaroon.encode(),
+            preimage: "00".repeat(32),
+        }
+    }
+
+    #[test]
+    fn attenuated_token_verifies_with_the_root_key() {
+        let token = token();
+        let attenuated = token
+            .attenuate(["path_prefix=/v1/chat", "svc_valid_until=2000"])
+            .unwrap();
+        assert_eq!(attenuated.preimage, token.preimage);
+        assert_eq!(attenuated.scheme, token.scheme);
+
+        let macaroon = attenuated.decode_macaroon().unwrap();
+        assert!(macaroon.verify_signature(ROOT_KEY));
+        assert!(!macaroon.verify_signature(b"other key"));
+        assert_eq!(
+            macaroon
+                .caveats
+                .iter()
+                .filter_map(Caveat::condition)
+                .collect::<Vec<_>>(),
+            vec!["route=/v1", "path_prefix=/v1/chat", "svc_valid_until=2000"]
+        );
+        assert!(attenuated.is_expired(2000));
+        assert!(!token.is_expired(2000));
+    }
+
+    #[test]
+    fn caveats_can_not_be_stripped_or_changed() {
+        let attenuated = token().attenuate(["path_prefix=/v1/chat"]).unwrap();
+        let macaroon = attenuated.decode_macaroon().unwrap();
+
+        let mut stripped = macaroon.clone();
+        stripped.caveats.pop();
+        assert!(!stripped.verify_signature(ROOT_KEY));
+
+        let mut widened = macaroon.clone();
+        widened.caveats[1].identifier = b"path_prefix=/".to_vec();
+        assert!(!widened.verify_signature(ROOT_KEY));
+
+        let mut reordered = macaroon;
+        reordered.caveats.swap(0, 1);
+        assert!(!reordered.verify_signature(ROOT_KEY));
+    }
+
+    #[test]
+    fn issue_leaves_the_stored_token_untouched() {
+        let store = MemoryTokenStore::new();
+        let url = Url::parse("https://api.example/v1/chat").unwrap();
+        store.insert(TokenScope::host(&url), token()).unwrap();
+
+        let (scope, issued) = store
+            .issue(&url, &["path_prefix=/v1/chat"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(scope, TokenScope::host(&url));
+        assert_eq!(issued.decode_macaroon().unwrap().caveats.len(), 2);
+        assert_eq!(store.get(&url).unwrap().1, token());
+
+        let other = Url::parse("https://other.example/").unwrap();
+        assert!(store.issue(&other, &[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn tokens_are_scoped_by_their_path_caveats() {
+        let url = Url::parse("https://api.example/v1/chat").unwrap();
+        assert_eq!(
+            TokenScope::for_token(&url, &token()),
+            TokenScope::new(&url, "/v1")
+        );
+
+        let narrowed = token().attenuate(["path_prefix=/v1/chat"]).unwrap();
+        let scope = TokenScope::for_token(&url, &narrowed);
+        assert_eq!(scope, TokenScope::new(&url, "/v1/chat"));
+        assert!(!scope.matches(&Url::parse("https://api.example/v1/embed").unwrap()));
+
+        let unscoped = L402Token {
+            macaroon: Macaroon::new(ROOT_KEY, b"id".to_vec(), None).encode(),
+            ..token()
+        };
+        assert_eq!(
+            TokenScope::for_token(&url, &unscoped),
+            TokenScope::host(&url)
+        );
+    }
+
+    #[test]
+    fn file_stores_sharing_a_path_keep_each_others_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.json");
+        let chat = Url::parse("https://api.example/v1/chat").unwrap();
+        let embed = Url::parse("https://other.example/v1/embed").unwrap();
+        let first = FileTokenStore::open(&path).unwrap();
+        let second = FileTokenStore::open(&path).unwrap();
+
+        first.insert(TokenScope::host(&chat), token()).unwrap();
+        second.insert(TokenScope::host(&embed), token()).unwrap();
+
+        assert!(first.get(&embed).is_some());
+        let reopened = FileTokenStore::open(&path).unwrap();
+        assert!(reopened.get(&chat).is_some());
+        assert!(reopened.get(&embed).is_some());
+    }
+
+    #[test]
+    fn file_store_keeps_a_token_another_process_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.json");
+        let url = Url::parse("https://api.example/v1/chat").unwrap();
+        let scope = TokenScope::host(&url);
+        let first = FileTokenStore::open(&path).unwrap();
+        let second = FileTokenStore::open(&path).unwrap();
+        let replacement = token().attenuate(["path_prefix=/v1"]).unwrap();
+
+        first.insert(scope.clone(), token()).unwrap();
+        second.insert(scope.clone(), replacement.clone()).unwrap();
+        // the first store evicts the token it saw, which is gone already
+        first.remove(&scope).unwrap();
+        assert_eq!(second.get(&url).unwrap().1, replacement);
+
+        second.remove(&scope).unwrap();
+        assert!(first.get(&url).is_none());
+    }
+}
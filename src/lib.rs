@@ -1,10 +1,12 @@
 #![feature(return_position_impl_trait_in_trait)]
 #![feature(async_fn_in_trait)]
 #![feature(type_alias_impl_trait)]
+pub mod cashu;
 mod config;
 pub mod http;
 pub mod lightning;
 pub mod models;
+pub mod server;
 pub mod token_manager;
 
 mod utils;
@@ -1,9 +1,22 @@
-
 use reqwest::header::{HeaderValue, CONTENT_TYPE};
+use serde::Deserialize;
 use url::Url;
 
 use super::error::LightningError;
-use super::model::{CreateInvoiceParams, CreateInvoiceResult, PayInvoiceResult};
+use super::model::{
+    BalanceResult, CreateInvoiceParams, CreateInvoiceResult, InvoiceStatus, PayInvoiceResult,
+    PaymentStatus,
+};
+use super::utils::unix_timestamp;
+
+// Alby reports amount and fee in sats
+#[derive(Deserialize)]
+struct AlbyPayResponse {
+    payment_hash: String,
+    payment_preimage: Option<String>,
+    fee: Option<u64>,
+    amount: Option<u64>,
+}
 
 #[derive(Clone)]
 pub struct AlbyClient {
@@ -14,8 +27,10 @@ pub struct AlbyClient {
 
 impl AlbyClient {
     pub fn new(api_key: &str) -> Result<AlbyClient, LightningError> {
-        let alby_url = Url::parse("https://api.getalby.com")?;
+        Self::with_url(api_key, Url::parse("https://api.getalby.com")?)
+    }
 
+    pub fn with_url(api_key: &str, alby_url: Url) -> Result<AlbyClient, LightningError> {
         let reqwest_client = reqwest::Client::builder().build()?;
 
         Ok(AlbyClient {
@@ -36,7 +51,6 @@ impl AlbyClient {
             .send()
             .await?;
 
-        // Alby API returns a 404 for invoices that aren't settled yet
         if response.status() == reqwest::StatusCode::NOT_FOUND {
             return Err(LightningError::NotFound);
         }
@@ -76,7 +90,7 @@ impl AlbyClient {
         params: &CreateInvoiceParams,
     ) -> Result<CreateInvoiceResult, LightningError> {
         let params = serde_json::json!({
-            "amount": params.amount,
+            "amount": params.amount_sats("alby")?,
             "description": params.memo,
         });
 
@@ -95,26 +109,33 @@ impl AlbyClient {
             .to_owned();
 
         Ok(CreateInvoiceResult {
-            payment_hash: payment_hash.as_bytes().to_vec(),
+            payment_hash: hex::decode(payment_hash)?,
             payment_request,
         })
     }
 
-    pub async fn pay_invoice(&self, bolt11: &str) -> Result<PayInvoiceResult, LightningError> {
+    /// `amount` in sats is only needed for zero amount invoices
+    pub async fn pay_invoice(
+        &self,
+        bolt11: &str,
+        amount: Option<u64>,
+    ) -> Result<PayInvoiceResult, LightningError> {
+        let mut params = serde_json::json!({ "invoice": bolt11 });
+        if let Some(amount) = amount {
+            params["amount"] = amount.into();
+        }
         let body = self
-            .make_post(
-                "payments/bolt11",
-                &serde_json::to_string(&serde_json::json!({"invoice": bolt11 }))?,
-            )
+            .make_post("payments/bolt11", &serde_json::to_string(&params)?)
             .await?;
 
-        let response: serde_json::Value = serde_json::from_str(&body)?;
+        let response: AlbyPayResponse = serde_json::from_str(&body)?;
 
         Ok(PayInvoiceResult {
-            payment_hash: response["payment_hash"]
-                .as_str()
-                .expect("payment_hash is empty")
-                .to_owned(),
+            payment_hash: response.payment_hash,
+            preimage: response.payment_preimage,
+            fee_msat: response.fee.map(|fee| fee * 1000),
+            amount_msat: response.amount.map(|amount| amount * 1000),
+            settled_at: unix_timestamp(),
         })
     }
 
@@ -124,4 +145,195 @@ impl AlbyClient {
             .as_bool()
             .unwrap_or(false))
     }
+
+    /// Alby has no lookup by hash for outgoing payments, so the most recent
+    /// ones are searched
+    pub async fn payment_status(
+        &self,
+        payment_hash: &str,
+    ) -> Result<PaymentStatus, LightningError> {
+        const PAGE_SIZE: usize = 100;
+        const MAX_PAGES: usize = 10;
+
+        for page in 1..=MAX_PAGES {
+            let body = self
+                .make_get(&format!("invoices/outgoing?page={page}&items={PAGE_SIZE}"))
+                .await?;
+            let payments: Vec<serde_json::Value> = serde_json::from_str(&body)?;
+
+            let payment = payments.iter().find(|payment| {
+                payment["payment_hash"]
+                    .as_str()
+                    .is_some_and(|hash| hash.eq_ignore_ascii_case(payment_hash))
+            });
+            if let Some(payment) = payment {
+                return Ok(alby_payment_status(payment));
+            }
+            if payments.len() < PAGE_SIZE {
+                break;
+            }
+        }
+
+        Err(LightningError::NotFound)
+    }
+
+    pub async fn invoice_status(
+        &self,
+        payment_hash: &str,
+    ) -> Result<InvoiceStatus, LightningError> {
+        let body = self.make_get(&format!("invoices/{payment_hash}")).await?;
+        let response: serde_json::Value = serde_json::from_str(&body)?;
+
+        if response["settled"].as_bool().unwrap_or(false) {
+            return Ok(InvoiceStatus::Paid);
+        }
+
+        Ok(match response["state"].as_str() {
+            Some("EXPIRED") => InvoiceStatus::Expired,
+            _ => InvoiceStatus::Pending,
+        })
+    }
+
+    pub async fn get_balance(&self) -> Result<BalanceResult, LightningError> {
+        let body = self.make_get("balance").await?;
+        let response: serde_json::Value = serde_json::from_str(&body)?;
+
+        // Alby reports the balance in sats
+        Ok(BalanceResult {
+            balance_msat: response["balance"].as_u64().unwrap_or(0) * 1000,
+        })
+    }
+}
+
+fn alby_payment_status(payment: &serde_json::Value) -> PaymentStatus {
+    if payment["settled"].as_bool().unwrap_or(false) {
+        return PaymentStatus::Succeeded {
+            preimage: payment["preimage"].as_str().map(str::to_owned),
+            fee_msat: payment["fee"].as_u64().map(|fee| fee * 1000),
+        };
+    }
+
+    match payment["state"].as_str() {
+        Some(state) if state.eq_ignore_ascii_case("error") => PaymentStatus::Failed {
+            reason: payment["error_message"]
+                .as_str()
+                .unwrap_or("Alby reports the payment as failed")
+                .to_owned(),
+        },
+        _ => PaymentStatus::Pending,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::lightning::testing::{respond, serve};
+
+    const PAYMENT_HASH: &str = "7a7c7d1fd9f3ab84fbe9b11d7c59bd6f8db4d33d4f0f1b34c3f8a7c3a6f1e0d2";
+
+    fn outgoing(page: usize, count: usize) -> serde_json::Value {
+        (0..count)
+            .map(|index| {
+                json!({
+                    "payment_hash": format!("{:064x}", page * 1000 + index),
+                    "settled": true,
+                })
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn payment_status_searches_outgoing_payments() {
+        let url = serve(|request| {
+            assert!(
+                request.path.starts_with("/invoices/outgoing?"),
+                "{}",
+                request.path
+            );
+            if request.path.contains("page=1&") {
+                return respond(200, outgoing(1, 100).to_string());
+            }
+            let mut page = outgoing(2, 10);
+            page.as_array_mut().unwrap().push(json!({
+                "payment_hash": PAYMENT_HASH.to_uppercase(),
+                "settled": true,
+                "preimage": "00ff",
+                "fee": 2,
+            }));
+            respond(200, page.to_string())
+        })
+        .await;
+        let client = AlbyClient::with_url("key", url).unwrap();
+
+        assert_eq!(
+            client.payment_status(PAYMENT_HASH).await.unwrap(),
+            PaymentStatus::Succeeded {
+                preimage: Some("00ff".to_owned()),
+                fee_msat: Some(2000),
+            }
+        );
+        assert!(matches!(
+            client.payment_status(&"00".repeat(32)).await,
+            Err(LightningError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pay_invoice_reads_the_payment() {
+        let url = serve(|request| {
+            assert_eq!(request.path, "/payments/bolt11");
+            if request.json()["invoice"] == "lnbc1" {
+                return respond(
+                    200,
+                    json!({
+                        "payment_hash": PAYMENT_HASH,
+                        "payment_preimage": "00ff",
+                        "fee": 2,
+                        "amount": 10,
+                    })
+                    .to_string(),
+                );
+            }
+            respond(
+                400,
+                r#"{"error":true,"code":8,"message":"not enough balance"}"#,
+            )
+        })
+        .await;
+        let client = AlbyClient::with_url("key", url).unwrap();
+
+        let payment = client.pay_invoice("lnbc1", None).await.unwrap();
+        assert_eq!(payment.payment_hash, PAYMENT_HASH);
+        assert_eq!(payment.preimage.as_deref(), Some("00ff"));
+        assert_eq!(payment.fee_msat, Some(2000));
+        assert_eq!(payment.amount_msat, Some(10_000));
+
+        assert!(matches!(
+            client.pay_invoice("lnbc2", None).await,
+            Err(LightningError::SerdeError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_invoice_is_not_found() {
+        let url = serve(|request| match request.path.as_str() {
+            "/invoices/7a7c7d1fd9f3ab84fbe9b11d7c59bd6f8db4d33d4f0f1b34c3f8a7c3a6f1e0d2" => {
+                respond(200, r#"{"settled":false,"state":"CREATED"}"#)
+            }
+            _ => respond(404, r#"{"error":true,"message":"Not found"}"#),
+        })
+        .await;
+        let client = AlbyClient::with_url("key", url).unwrap();
+
+        assert_eq!(
+            client.invoice_status(PAYMENT_HASH).await.unwrap(),
+            InvoiceStatus::Pending
+        );
+        assert!(matches!(
+            client.invoice_status(&"00".repeat(32)).await,
+            Err(LightningError::NotFound)
+        ));
+    }
 }
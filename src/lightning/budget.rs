@@ -0,0 +1,540 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::error::LightningError;
+use super::invoice::{DecodedInvoice, InvoiceError};
+use super::model::{
+    BalanceResult, CreateInvoiceParams, CreateInvoiceResult, InvoiceStatus, PayInvoiceResult,
+    PayOptions, PaymentStatus,
+};
+use super::utils::{rejected_before_paying, unix_timestamp};
+use super::{InvoiceEventStream, Lightning};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BudgetPeriod {
+    Hour,
+    Day,
+    /// The last 30 days
+    Month,
+    Lifetime,
+}
+
+impl BudgetPeriod {
+    pub const ALL: [BudgetPeriod; 4] = [
+        BudgetPeriod::Hour,
+        BudgetPeriod::Day,
+        BudgetPeriod::Month,
+        BudgetPeriod::Lifetime,
+    ];
+
+    fn seconds(&self) -> Option<u64> {
+        match self {
+            BudgetPeriod::Hour => Some(60 * 60),
+            BudgetPeriod::Day => Some(24 * 60 * 60),
+            BudgetPeriod::Month => Some(30 * 24 * 60 * 60),
+            BudgetPeriod::Lifetime => None,
+        }
+    }
+}
+
+impl fmt::Display for BudgetPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetPeriod::Hour => write!(f, "hour"),
+            BudgetPeriod::Day => write!(f, "day"),
+            BudgetPeriod::Month => write!(f, "month"),
+            BudgetPeriod::Lifetime => write!(f, "lifetime"),
+        }
+    }
+}
+
+/// Caps on outgoing payments, in sats over rolling periods
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Budget {
+    pub max_sats_per_hour: Option<u64>,
+    pub max_sats_per_day: Option<u64>,
+    pub max_sats_per_month: Option<u64>,
+    pub max_sats_lifetime: Option<u64>,
+}
+
+impl Budget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_sats_per_hour(mut self, max_sats: u64) -> Self {
+        self.max_sats_per_hour = Some(max_sats);
+        self
+    }
+
+    pub fn with_max_sats_per_day(mut self, max_sats: u64) -> Self {
+        self.max_sats_per_day = Some(max_sats);
+        self
+    }
+
+    pub fn with_max_sats_per_month(mut self, max_sats: u64) -> Self {
+        self.max_sats_per_month = Some(max_sats);
+        self
+    }
+
+    pub fn with_max_sats_lifetime(mut self, max_sats: u64) -> Self {
+        self.max_sats_lifetime = Some(max_sats);
+        self
+    }
+
+    pub fn limit_sats(&self, period: BudgetPeriod) -> Option<u64> {
+        match period {
+            BudgetPeriod::Hour => self.max_sats_per_hour,
+            BudgetPeriod::Day => self.max_sats_per_day,
+            BudgetPeriod::Month => self.max_sats_per_month,
+            BudgetPeriod::Lifetime => self.max_sats_lifetime,
+        }
+    }
+}
+
+/// Payment hash of the entry older payments are merged into
+pub const PRUNED_ENTRY: &str = "pruned";
+
+/// An outgoing payment, including routing fees
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub payment_hash: String,
+    pub amount_msat: u64,
+    pub paid_at: u64,
+    /// Still in flight. Pending payments count against the budget.
+    pub pending: bool,
+}
+
+/// Wraps a backend so no payment takes spending over a `Budget`. Every
+/// payment is reserved in the ledger before it is sent, so concurrent
+/// payments can't overshoot a cap together. A payment that may have gone
+/// through stays reserved until `reconcile` learns its outcome.
+pub struct BudgetLightning {
+    inner: Arc<dyn Lightning>,
+    budget: Budget,
+    path: Option<PathBuf>,
+    ledger: Mutex<Vec<LedgerEntry>>,
+}
+
+impl BudgetLightning {
+    /// Keeps the ledger in memory, so it starts from zero on every restart
+    pub fn new(inner: Arc<dyn Lightning>, budget: Budget) -> Self {
+        Self {
+            inner,
+            budget,
+            path: None,
+            ledger: Mutex::new(vec![]),
+        }
+    }
+
+    /// Persists the ledger as JSON at `path`, loading any earlier payments
+    pub fn open(
+        inner: Arc<dyn Lightning>,
+        budget: Budget,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, LightningError> {
+        let path = path.as_ref().to_path_buf();
+        let ledger = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            vec![]
+        };
+
+        Ok(Self {
+            path: Some(path),
+            ledger: Mutex::new(ledger),
+            ..Self::new(inner, budget)
+        })
+    }
+
+    pub fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    pub fn ledger(&self) -> Vec<LedgerEntry> {
+        self.ledger.lock().unwrap().clone()
+    }
+
+    /// Spent in the period, including payments still in flight
+    pub fn spent_msat(&self, period: BudgetPeriod) -> u64 {
+        spent_msat(&self.ledger.lock().unwrap(), period, unix_timestamp())
+    }
+
+    /// What is left to spend in the period, None if it has no cap
+    pub fn remaining_msat(&self, period: BudgetPeriod) -> Option<u64> {
+        let limit_msat = self.budget.limit_sats(period)? * 1000;
+        Some(limit_msat.saturating_sub(self.spent_msat(period)))
+    }
+
+    /// Asks the backend about payments still reserved, e.g. from before a
+    /// restart, settling the ones it knows the outcome of
+    pub async fn reconcile(&self) -> Result<(), LightningError> {
+        let pending: Vec<LedgerEntry> = self
+            .ledger()
+            .into_iter()
+            .filter(|entry| entry.pending)
+            .collect();
+
+        for entry in pending {
+            match self.inner.payment_status(&entry.payment_hash).await {
+                Ok(PaymentStatus::Succeeded { .. }) => {
+                    self.settle(&entry.payment_hash, Some(entry.amount_msat))?
+                }
+                Ok(PaymentStatus::Failed { .. }) => self.settle(&entry.payment_hash, None)?,
+                Ok(PaymentStatus::Pending) => {}
+                Err(err) => warn!(
+                    "Can not reconcile payment {}, keeping its budget reserved: {err}",
+                    entry.payment_hash
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Checks every cap and records the payment as pending
+    fn reserve(&self, payment_hash: &str, amount_msat: u64) -> Result<(), LightningError> {
+        let mut ledger = self.ledger.lock().unwrap();
+        let now = unix_timestamp();
+
+        for period in BudgetPeriod::ALL {
+            let Some(limit_sats) = self.budget.limit_sats(period) else {
+                continue;
+            };
+            let spent_msat = spent_msat(&ledger, period, now);
+            if spent_msat + amount_msat > limit_sats * 1000 {
+                return Err(LightningError::BudgetExceeded {
+                    period,
+                    limit_sats,
+                    spent_msat,
+                    amount_msat,
+                });
+            }
+        }
+
+        prune(&mut ledger, now);
+        ledger.push(LedgerEntry {
+            payment_hash: payment_hash.to_owned(),
+            amount_msat,
+            paid_at: now,
+            pending: true,
+        });
+        if let Err(err) = self.save(&ledger) {
+            // nothing is paid without the reservation on disk
+            ledger.pop();
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // Settles the reservation with what was actually paid, or drops it if the
+    // payment definitely failed
+    fn settle(&self, payment_hash: &str, paid_msat: Option<u64>) -> Result<(), LightningError> {
+        let mut ledger = self.ledger.lock().unwrap();
+        let Some(index) = ledger
+            .iter()
+            .rposition(|entry| entry.pending && entry.payment_hash == payment_hash)
+        else {
+            return Ok(());
+        };
+
+        match paid_msat {
+            Some(amount_msat) => {
+                ledger[index].amount_msat = amount_msat;
+                ledger[index].pending = false;
+            }
+            None => {
+                ledger.remove(index);
+            }
+        }
+        self.save(&ledger)
+    }
+
+    fn save(&self, ledger: &[LedgerEntry]) -> Result<(), LightningError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // write then rename so a crash never leaves a truncated ledger behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(ledger)?)?;
+        std::fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+// Settled payments older than every capped period but the lifetime one are
+// only needed for their total, so they are merged into a single entry
+fn prune(ledger: &mut Vec<LedgerEntry>, now: u64) {
+    let since = now.saturating_sub(BudgetPeriod::Month.seconds().unwrap_or(0));
+    let (old, recent): (Vec<_>, Vec<_>) = ledger
+        .drain(..)
+        .partition(|entry| !entry.pending && entry.paid_at < since);
+
+    if let Some(paid_at) = old.iter().map(|entry| entry.paid_at).max() {
+        ledger.push(LedgerEntry {
+            payment_hash: PRUNED_ENTRY.to_owned(),
+            amount_msat: old.iter().map(|entry| entry.amount_msat).sum(),
+            paid_at,
+            pending: false,
+        });
+    }
+    ledger.extend(recent);
+}
+
+fn spent_msat(ledger: &[LedgerEntry], period: BudgetPeriod, now: u64) -> u64 {
+    let since = period
+        .seconds()
+        .map(|seconds| now.saturating_sub(seconds))
+        .unwrap_or(0);
+    ledger
+        .iter()
+        .filter(|entry| entry.paid_at >= since)
+        .map(|entry| entry.amount_msat)
+        .sum()
+}
+
+#[async_trait]
+impl Lightning for BudgetLightning {
+    async fn pay_invoice_with_options(
+        &self,
+        payment_request: String,
+        options: PayOptions,
+    ) -> Result<PayInvoiceResult, anyhow::Error> {
+        let invoice = DecodedInvoice::decode(&payment_request)?.invoice;
+        let amount_msat = options
+            .amount_msat(&invoice)
+            .ok_or(InvoiceError::MissingAmount)?;
+        // without a fee limit the fee isn't known up front, it is added once
+        // the payment settles
+        let reserved_msat = amount_msat + options.fee_limit_msat(&invoice).unwrap_or(0);
+        let payment_hash = invoice.payment_hash().to_string();
+
+        self.reserve(&payment_hash, reserved_msat)?;
+        match self
+            .inner
+            .pay_invoice_with_options(payment_request, options)
+            .await
+        {
+            Ok(payment) => {
+                let paid_msat =
+                    payment.amount_msat.unwrap_or(amount_msat) + payment.fee_msat.unwrap_or(0);
+                self.settle(&payment_hash, Some(paid_msat))?;
+                Ok(payment)
+            }
+            Err(err) if rejected_before_paying(&err) => {
+                self.settle(&payment_hash, None)?;
+                Err(err)
+            }
+            // the payment may still have gone through, so the reservation is
+            // only dropped once the backend reports it failed
+            Err(err) => {
+                match self.inner.payment_status(&payment_hash).await {
+                    Ok(PaymentStatus::Succeeded { fee_msat, .. }) => {
+                        let paid_msat =
+                            fee_msat.map_or(reserved_msat, |fee_msat| amount_msat + fee_msat);
+                        self.settle(&payment_hash, Some(paid_msat))?;
+                    }
+                    Ok(PaymentStatus::Failed { .. }) => self.settle(&payment_hash, None)?,
+                    Ok(PaymentStatus::Pending) => {}
+                    Err(status_err) => warn!(
+                        "Can not tell if payment {payment_hash} went through, \
+                         keeping its budget reserved: {status_err}"
+                    ),
+                }
+                Err(err)
+            }
+        }
+    }
+
+    async fn create_invoice(
+        &self,
+        params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        self.inner.create_invoice(params).await
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error> {
+        self.inner.invoice_status(payment_hash).await
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus, anyhow::Error> {
+        self.inner.payment_status(payment_hash).await
+    }
+
+    fn subscribe_invoice(&self, payment_hash: &str) -> InvoiceEventStream<'_> {
+        self.inner.subscribe_invoice(payment_hash)
+    }
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error> {
+        self.inner.balance().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::testing::{FakeLightning, Pay};
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn budget() -> Budget {
+        Budget::new()
+            .with_max_sats_per_day(100)
+            .with_max_sats_lifetime(1_000)
+    }
+
+    #[tokio::test]
+    async fn settles_what_was_paid_and_refuses_payments_over_budget() {
+        let inner = Arc::new(FakeLightning::paying(Pay::Succeed { fee_msat: 1_000 }));
+        let lightning = BudgetLightning::new(inner.clone(), budget());
+
+        let invoice = inner.invoice(Some(60_000));
+        lightning
+            .pay_invoice_with_options(
+                invoice.payment_request,
+                PayOptions::new().with_max_fee_msat(5_000),
+            )
+            .await
+            .unwrap();
+        // the fee limit was reserved, the fee paid is what is spent
+        assert_eq!(lightning.spent_msat(BudgetPeriod::Day), 61_000);
+        assert_eq!(lightning.remaining_msat(BudgetPeriod::Day), Some(39_000));
+        assert_eq!(lightning.remaining_msat(BudgetPeriod::Hour), None);
+
+        let over_budget = inner.invoice(Some(40_000));
+        let err = lightning
+            .pay_invoice(over_budget.payment_request)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(LightningError::BudgetExceeded {
+                period: BudgetPeriod::Day,
+                limit_sats: 100,
+                spent_msat: 61_000,
+                amount_msat: 40_000,
+            })
+        ));
+        assert_eq!(inner.paid(), vec![invoice.payment_hash]);
+    }
+
+    #[tokio::test]
+    async fn releases_reservation_of_failed_payment() {
+        let inner = Arc::new(FakeLightning::paying(Pay::Fail));
+        let lightning = BudgetLightning::new(inner.clone(), budget());
+
+        let invoice = inner.invoice(Some(60_000));
+        lightning
+            .pay_invoice(invoice.payment_request)
+            .await
+            .unwrap_err();
+        assert_eq!(lightning.spent_msat(BudgetPeriod::Day), 0);
+        assert!(lightning.ledger().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_reservation_while_the_outcome_is_unknown() {
+        let inner = Arc::new(FakeLightning::paying(Pay::Lose));
+        let lightning = BudgetLightning::new(inner.clone(), budget());
+
+        // the backend reports the lost payment went through
+        let paid = inner.invoice(Some(30_000));
+        lightning
+            .pay_invoice(paid.payment_request)
+            .await
+            .unwrap_err();
+        assert_eq!(lightning.spent_msat(BudgetPeriod::Day), 30_000);
+        assert!(lightning.ledger().iter().all(|entry| !entry.pending));
+
+        // the backend can't be asked
+        *inner.lookups_fail.lock().unwrap() = true;
+        let unknown = inner.invoice(Some(20_000));
+        lightning
+            .pay_invoice(unknown.payment_request)
+            .await
+            .unwrap_err();
+        assert_eq!(lightning.spent_msat(BudgetPeriod::Day), 50_000);
+        lightning.reconcile().await.unwrap();
+        assert_eq!(lightning.spent_msat(BudgetPeriod::Day), 50_000);
+
+        // it turns out it failed after all
+        *inner.lookups_fail.lock().unwrap() = false;
+        inner.set_payment(
+            &unknown.payment_hash,
+            PaymentStatus::Failed {
+                reason: "no route".to_owned(),
+            },
+        );
+        lightning.reconcile().await.unwrap();
+        assert_eq!(lightning.spent_msat(BudgetPeriod::Day), 30_000);
+        assert_eq!(lightning.ledger().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn nothing_is_paid_when_the_reservation_can_not_be_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        // the ledger's directory can't be created where a file is
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let inner = Arc::new(FakeLightning::new());
+        let lightning =
+            BudgetLightning::open(inner.clone(), budget(), file.join("ledger.json")).unwrap();
+
+        let invoice = inner.invoice(Some(10_000));
+        let err = lightning
+            .pay_invoice(invoice.payment_request)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(LightningError::IoError(_))
+        ));
+        assert!(lightning.ledger().is_empty());
+        assert!(inner.paid().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ledger_is_persisted_and_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ledger.json");
+        let now = unix_timestamp();
+        let old_entries: Vec<LedgerEntry> = (0..50)
+            .map(|index| LedgerEntry {
+                payment_hash: format!("{index:064x}"),
+                amount_msat: 10_000,
+                paid_at: now - 40 * DAY - index,
+                pending: false,
+            })
+            .collect();
+        std::fs::write(&path, serde_json::to_string(&old_entries).unwrap()).unwrap();
+
+        let inner = Arc::new(FakeLightning::new());
+        let lightning = BudgetLightning::open(inner.clone(), budget(), &path).unwrap();
+        assert_eq!(lightning.spent_msat(BudgetPeriod::Lifetime), 500_000);
+
+        let invoice = inner.invoice(Some(10_000));
+        lightning
+            .pay_invoice(invoice.payment_request)
+            .await
+            .unwrap();
+
+        let reopened = BudgetLightning::open(inner, budget(), &path).unwrap();
+        let ledger = reopened.ledger();
+        assert_eq!(ledger.len(), 2);
+        assert_eq!(ledger[0].payment_hash, PRUNED_ENTRY);
+        assert_eq!(ledger[0].paid_at, now - 40 * DAY);
+        assert_eq!(ledger[1].payment_hash, invoice.payment_hash);
+        assert_eq!(reopened.spent_msat(BudgetPeriod::Lifetime), 510_000);
+        assert_eq!(reopened.spent_msat(BudgetPeriod::Day), 10_000);
+    }
+}
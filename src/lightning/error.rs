@@ -1,3 +1,6 @@
+use super::budget::BudgetPeriod;
+use super::invoice::InvoiceError;
+
 #[derive(Debug, thiserror::Error)]
 pub enum LightningError {
     #[error("reqwest error: {0}")]
@@ -9,6 +12,27 @@ pub enum LightningError {
     #[error("serde error: {0}")]
     SerdeError(#[from] serde_json::Error),
 
+    #[error("hex error: {0}")]
+    HexError(#[from] hex::FromHexError),
+
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("toml error: {0}")]
+    TomlError(#[from] toml::de::Error),
+
+    #[error("env error: {0}")]
+    EnvError(#[from] envy::Error),
+
+    #[error("lnd error: {0}")]
+    LndError(String),
+
+    #[error("missing setting: {0}")]
+    MissingSetting(&'static str),
+
+    #[error("invalid setting {0}: {1}")]
+    InvalidSetting(&'static str, String),
+
     #[error("Not found")]
     NotFound,
 
@@ -17,4 +41,58 @@ pub enum LightningError {
 
     #[error("Payment failed")]
     PaymentFailed,
+
+    #[error("Payment {0} is already in flight")]
+    PaymentInFlight(String),
+
+    #[error("Payment {0} is still pending, check payment_status before paying again")]
+    PaymentPending(String),
+
+    #[error("Timed out waiting for invoice {0} to be paid")]
+    InvoiceWaitTimeout(String),
+
+    #[error("Preimage unavailable")]
+    PreimageUnavailable,
+
+    #[error("Invalid amount: {0}")]
+    InvalidAmount(String),
+
+    #[error(transparent)]
+    InvalidInvoice(#[from] InvoiceError),
+
+    #[error(
+        "paying {amount_msat} msat would exceed the {limit_sats} sat {period} budget, \
+         {spent_msat} msat already spent"
+    )]
+    BudgetExceeded {
+        period: BudgetPeriod,
+        limit_sats: u64,
+        spent_msat: u64,
+        amount_msat: u64,
+    },
+
+    #[error("{backend} does not support the {option} pay option")]
+    UnsupportedPayOption {
+        backend: &'static str,
+        option: &'static str,
+    },
+
+    #[error("routing fee of {fee_msat} msat exceeds the {max_fee_msat} msat limit")]
+    FeeLimitExceeded { fee_msat: u64, max_fee_msat: u64 },
+}
+
+impl LightningError {
+    /// Whether the payment was refused before it reached the network, so
+    /// nothing can have been paid
+    pub fn is_rejected_before_paying(&self) -> bool {
+        matches!(
+            self,
+            LightningError::InvalidInvoice(_)
+                | LightningError::InvalidAmount(_)
+                | LightningError::PaymentInFlight(_)
+                | LightningError::BudgetExceeded { .. }
+                | LightningError::UnsupportedPayOption { .. }
+                | LightningError::FeeLimitExceeded { .. }
+        )
+    }
 }
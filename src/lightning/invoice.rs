@@ -0,0 +1,363 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription, Currency, RouteHint};
+
+use super::model::{
+    BalanceResult, CreateInvoiceParams, CreateInvoiceResult, InvoiceStatus, PayInvoiceResult,
+    PayOptions, PaymentStatus,
+};
+use super::{InvoiceEventStream, Lightning};
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum InvoiceError {
+    #[error("invalid invoice: {0}")]
+    Invalid(String),
+
+    #[error("invoice is for {0:?}, which is not an allowed network")]
+    NetworkNotAllowed(Currency),
+
+    #[error("invoice expired at {0}")]
+    Expired(u64),
+
+    #[error("invoice does not specify an amount and none was supplied")]
+    MissingAmount,
+
+    #[error("payee {0} is not allowed")]
+    PayeeNotAllowed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvoiceDescription {
+    Direct(String),
+    /// Hex sha256 of a description the invoice only commits to
+    Hash(String),
+}
+
+/// Everything a payer might want to check about a BOLT11 invoice
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedInvoice {
+    pub invoice: Bolt11Invoice,
+    pub payment_hash: String,
+    pub amount_msat: Option<u64>,
+    pub description: InvoiceDescription,
+    /// Hex node id, recovered from the signature if not given explicitly
+    pub payee_pubkey: String,
+    /// Unix timestamps (seconds)
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub network: Currency,
+    pub route_hints: Vec<RouteHint>,
+    /// The known features the invoice sets, e.g. `basic_mpp`
+    pub features: Vec<&'static str>,
+    pub min_final_cltv_expiry_delta: u64,
+}
+
+impl DecodedInvoice {
+    pub fn decode(payment_request: &str) -> Result<Self, InvoiceError> {
+        let invoice: Bolt11Invoice = payment_request.trim().parse().map_err(
+            |err: lightning_invoice::ParseOrSemanticError| InvoiceError::Invalid(err.to_string()),
+        )?;
+        Ok(Self::from(invoice))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.invoice.is_expired()
+    }
+}
+
+impl From<Bolt11Invoice> for DecodedInvoice {
+    fn from(invoice: Bolt11Invoice) -> Self {
+        let description = match invoice.description() {
+            Bolt11InvoiceDescription::Direct(description) => {
+                InvoiceDescription::Direct(description.to_string())
+            }
+            Bolt11InvoiceDescription::Hash(hash) => InvoiceDescription::Hash(hash.0.to_string()),
+        };
+        let payee_pubkey = invoice
+            .payee_pub_key()
+            .copied()
+            .unwrap_or_else(|| invoice.recover_payee_pub_key())
+            .to_string();
+        let features = invoice
+            .features()
+            .map(|features| {
+                [
+                    ("var_onion_optin", features.supports_variable_length_onion()),
+                    ("payment_secret", features.supports_payment_secret()),
+                    ("basic_mpp", features.supports_basic_mpp()),
+                    ("payment_metadata", features.supports_payment_metadata()),
+                ]
+                .into_iter()
+                .filter(|(_, supported)| *supported)
+                .map(|(feature, _)| feature)
+                .collect()
+            })
+            .unwrap_or_default();
+        let created_at = invoice.duration_since_epoch().as_secs();
+
+        Self {
+            payment_hash: invoice.payment_hash().to_string(),
+            amount_msat: invoice.amount_milli_satoshis(),
+            description,
+            payee_pubkey,
+            created_at,
+            expires_at: created_at.saturating_add(invoice.expiry_time().as_secs()),
+            network: invoice.currency(),
+            route_hints: invoice.route_hints(),
+            features,
+            min_final_cltv_expiry_delta: invoice.min_final_cltv_expiry_delta(),
+            invoice,
+        }
+    }
+}
+
+/// Checks an invoice has to pass before it is paid. Expired invoices and zero
+/// amount invoices without a supplied amount are always rejected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InvoicePolicy {
+    /// Empty allows every network
+    pub networks: Vec<Currency>,
+    /// Hex node ids, empty allows every payee
+    pub allowed_payees: Vec<String>,
+}
+
+impl InvoicePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_network(mut self, network: Currency) -> Self {
+        self.networks.push(network);
+        self
+    }
+
+    pub fn with_allowed_payee(mut self, pubkey: &str) -> Self {
+        self.allowed_payees.push(pubkey.to_lowercase());
+        self
+    }
+
+    pub fn validate(
+        &self,
+        invoice: &DecodedInvoice,
+        options: &PayOptions,
+    ) -> Result<(), InvoiceError> {
+        if !self.networks.is_empty() && !self.networks.contains(&invoice.network) {
+            return Err(InvoiceError::NetworkNotAllowed(invoice.network.clone()));
+        }
+        if invoice.is_expired() {
+            return Err(InvoiceError::Expired(invoice.expires_at));
+        }
+        if options.amount_msat(&invoice.invoice).is_none() {
+            return Err(InvoiceError::MissingAmount);
+        }
+        if !self.allowed_payees.is_empty() && !self.allowed_payees.contains(&invoice.payee_pubkey) {
+            return Err(InvoiceError::PayeeNotAllowed(invoice.payee_pubkey.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Decodes and validates the invoice in one go
+    pub fn check(
+        &self,
+        payment_request: &str,
+        options: &PayOptions,
+    ) -> Result<DecodedInvoice, InvoiceError> {
+        let invoice = DecodedInvoice::decode(payment_request)?;
+        self.validate(&invoice, options)?;
+        Ok(invoice)
+    }
+}
+
+/// Wraps a backend so it only pays invoices passing an `InvoicePolicy`.
+/// `LightningType::connect` wraps every backend it connects to.
+pub struct InvoicePolicyLightning {
+    inner: Arc<dyn Lightning>,
+    policy: InvoicePolicy,
+}
+
+impl InvoicePolicyLightning {
+    pub fn new(inner: Arc<dyn Lightning>, policy: InvoicePolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    pub fn policy(&self) -> &InvoicePolicy {
+        &self.policy
+    }
+}
+
+#[async_trait]
+impl Lightning for InvoicePolicyLightning {
+    async fn pay_invoice_with_options(
+        &self,
+        payment_request: String,
+        options: PayOptions,
+    ) -> Result<PayInvoiceResult, anyhow::Error> {
+        self.policy.check(&payment_request, &options)?;
+        self.inner
+            .pay_invoice_with_options(payment_request, options)
+            .await
+    }
+
+    async fn create_invoice(
+        &self,
+        params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        self.inner.create_invoice(params).await
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error> {
+        self.inner.invoice_status(payment_hash).await
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus, anyhow::Error> {
+        self.inner.payment_status(payment_hash).await
+    }
+
+    fn subscribe_invoice(&self, payment_hash: &str) -> InvoiceEventStream<'_> {
+        self.inner.subscribe_invoice(payment_hash)
+    }
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error> {
+        self.inner.balance().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::lightning::testing::{
+        build_invoice, invoice, payee_pubkey, FakeLightning, InvoiceSpec, PAYEE_KEY,
+    };
+
+    fn decode(spec: InvoiceSpec) -> DecodedInvoice {
+        DecodedInvoice::decode(&build_invoice(spec).payment_request).unwrap()
+    }
+
+    #[test]
+    fn decodes_invoice_details() {
+        let test_invoice = invoice(Some(21_000));
+        let decoded =
+            DecodedInvoice::decode(&format!(" {} ", test_invoice.payment_request)).unwrap();
+
+        assert_eq!(decoded.payment_hash, test_invoice.payment_hash);
+        assert_eq!(decoded.amount_msat, Some(21_000));
+        assert_eq!(
+            decoded.description,
+            InvoiceDescription::Direct("test".to_owned())
+        );
+        assert_eq!(decoded.payee_pubkey, payee_pubkey(PAYEE_KEY));
+        assert_eq!(decoded.network, Currency::Bitcoin);
+        assert_eq!(decoded.expires_at, decoded.created_at + 3600);
+        assert!(decoded.features.contains(&"payment_secret"));
+        assert!(!decoded.is_expired());
+
+        assert!(matches!(
+            DecodedInvoice::decode("lnbc1notaninvoice"),
+            Err(InvoiceError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn default_policy_accepts_payable_invoices() {
+        let policy = InvoicePolicy::default();
+
+        policy
+            .validate(&decode(InvoiceSpec::default()), &PayOptions::new())
+            .unwrap();
+        // a zero amount invoice with the amount supplied
+        let zero_amount = decode(InvoiceSpec {
+            amount_msat: None,
+            ..Default::default()
+        });
+        policy
+            .validate(
+                &zero_amount,
+                &PayOptions::new().with_amount_msat_for_zero_amount(5_000),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn policy_rejects_invoices() {
+        let other_key = [0x07; 32];
+        let policy = InvoicePolicy::new()
+            .with_network(Currency::Bitcoin)
+            .with_allowed_payee(&payee_pubkey(PAYEE_KEY).to_uppercase());
+
+        let expired = decode(InvoiceSpec {
+            created_at: SystemTime::now() - Duration::from_secs(7200),
+            ..Default::default()
+        });
+        let cases = [
+            (
+                decode(InvoiceSpec {
+                    currency: Currency::BitcoinTestnet,
+                    ..Default::default()
+                }),
+                InvoiceError::NetworkNotAllowed(Currency::BitcoinTestnet),
+            ),
+            (expired.clone(), InvoiceError::Expired(expired.expires_at)),
+            (
+                decode(InvoiceSpec {
+                    amount_msat: None,
+                    ..Default::default()
+                }),
+                InvoiceError::MissingAmount,
+            ),
+            (
+                decode(InvoiceSpec {
+                    payee_key: other_key,
+                    ..Default::default()
+                }),
+                InvoiceError::PayeeNotAllowed(payee_pubkey(other_key)),
+            ),
+        ];
+        for (invoice, expected) in cases {
+            assert_eq!(policy.validate(&invoice, &PayOptions::new()), Err(expected));
+        }
+
+        policy
+            .validate(&decode(InvoiceSpec::default()), &PayOptions::new())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn wrapped_backend_only_pays_accepted_invoices() {
+        let inner = Arc::new(FakeLightning::new());
+        let lightning = InvoicePolicyLightning::new(
+            inner.clone(),
+            InvoicePolicy::new().with_network(Currency::Bitcoin),
+        );
+
+        let testnet = build_invoice(InvoiceSpec {
+            currency: Currency::BitcoinTestnet,
+            ..Default::default()
+        });
+        let err = lightning
+            .pay_invoice(testnet.payment_request)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&InvoiceError::NetworkNotAllowed(Currency::BitcoinTestnet))
+        );
+        let err = lightning
+            .pay_invoice(invoice(None).payment_request)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&InvoiceError::MissingAmount));
+        assert!(inner.paid().is_empty());
+
+        let payable = inner.invoice(Some(10_000));
+        let payment = lightning
+            .pay_invoice(payable.payment_request)
+            .await
+            .unwrap();
+        assert_eq!(payment.preimage, Some(payable.preimage));
+        assert_eq!(inner.paid(), vec![payable.payment_hash]);
+    }
+}
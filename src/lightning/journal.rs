@@ -0,0 +1,542 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::error::LightningError;
+use super::invoice::DecodedInvoice;
+use super::model::{
+    BalanceResult, CreateInvoiceParams, CreateInvoiceResult, InvoiceStatus, PayInvoiceResult,
+    PayOptions, PaymentStatus,
+};
+use super::utils::{rejected_before_paying, unix_timestamp};
+use super::{InvoiceEventStream, Lightning};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JournalState {
+    /// Handed to the backend, the outcome isn't known yet
+    Pending,
+    Succeeded,
+    /// Known not to have been paid, so it may be paid again
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub payment_hash: String,
+    pub payment_request: String,
+    pub state: JournalState,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    /// Set once the payment succeeded
+    pub payment: Option<PayInvoiceResult>,
+    /// Set once the payment failed
+    pub failure: Option<String>,
+}
+
+/// Wraps a backend so no invoice is paid twice. The intent to pay is written
+/// to the journal before the backend is called and the outcome after it
+/// returns. Paying an invoice that already succeeded returns the recorded
+/// payment, paying one still in flight fails with
+/// `LightningError::PaymentInFlight`.
+pub struct JournaledLightning {
+    inner: Arc<dyn Lightning>,
+    path: Option<PathBuf>,
+    entries: Mutex<HashMap<String, JournalEntry>>,
+}
+
+impl JournaledLightning {
+    /// Keeps the journal in memory, so it only guards against duplicate pays
+    /// within this process
+    pub fn new(inner: Arc<dyn Lightning>) -> Self {
+        Self {
+            inner,
+            path: None,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Persists the journal as JSON at `path`, loading earlier entries and
+    /// reconciling those left pending by a previous run
+    pub async fn open(
+        inner: Arc<dyn Lightning>,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, LightningError> {
+        let path = path.as_ref().to_path_buf();
+        let entries: Vec<JournalEntry> = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            vec![]
+        };
+
+        let journal = Self {
+            path: Some(path),
+            entries: Mutex::new(
+                entries
+                    .into_iter()
+                    .map(|entry| (entry.payment_hash.clone(), entry))
+                    .collect(),
+            ),
+            ..Self::new(inner)
+        };
+        journal.reconcile().await?;
+
+        Ok(journal)
+    }
+
+    pub fn entry(&self, payment_hash: &str) -> Option<JournalEntry> {
+        self.entries.lock().unwrap().get(payment_hash).cloned()
+    }
+
+    pub fn entries(&self) -> Vec<JournalEntry> {
+        let mut entries: Vec<_> = self.entries.lock().unwrap().values().cloned().collect();
+        entries.sort_by_key(|entry| entry.started_at);
+        entries
+    }
+
+    /// Asks the backend about every pending entry and records the payments it
+    /// knows the outcome of. Entries it has no record of stay pending, as some
+    /// backends can't look up every payment they made.
+    pub async fn reconcile(&self) -> Result<(), LightningError> {
+        let pending: Vec<JournalEntry> = self
+            .entries()
+            .into_iter()
+            .filter(|entry| entry.state == JournalState::Pending)
+            .collect();
+
+        for entry in pending {
+            match self.inner.payment_status(&entry.payment_hash).await {
+                Ok(PaymentStatus::Pending) => {
+                    info!("Payment {} is still in flight", entry.payment_hash);
+                }
+                Ok(status) => {
+                    info!("Reconciled payment {}: {:?}", entry.payment_hash, status);
+                    self.finish(&entry.payment_hash, status)?;
+                }
+                Err(err) => {
+                    warn!(
+                        "Can not reconcile payment {}, leaving it pending: {err}",
+                        entry.payment_hash
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops an entry, e.g. a payment stuck pending on a backend that can't
+    /// report payment status. Only do this once sure it wasn't paid.
+    pub fn forget(&self, payment_hash: &str) -> Result<(), LightningError> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(payment_hash);
+        self.save(&entries)
+    }
+
+    // Records the intent to pay, or returns the earlier payment if the
+    // invoice was already paid
+    fn begin(
+        &self,
+        payment_hash: &str,
+        payment_request: &str,
+    ) -> Result<Option<PayInvoiceResult>, LightningError> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get(payment_hash) {
+            match entry.state {
+                JournalState::Succeeded => return Ok(entry.payment.clone()),
+                JournalState::Pending => {
+                    return Err(LightningError::PaymentInFlight(payment_hash.to_owned()))
+                }
+                JournalState::Failed => {}
+            }
+        }
+
+        entries.insert(
+            payment_hash.to_owned(),
+            JournalEntry {
+                payment_hash: payment_hash.to_owned(),
+                payment_request: payment_request.to_owned(),
+                state: JournalState::Pending,
+                started_at: unix_timestamp(),
+                finished_at: None,
+                payment: None,
+                failure: None,
+            },
+        );
+        self.save(&entries)?;
+        Ok(None)
+    }
+
+    fn finish(&self, payment_hash: &str, status: PaymentStatus) -> Result<(), LightningError> {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get_mut(payment_hash) else {
+            return Ok(());
+        };
+
+        match status {
+            PaymentStatus::Pending => return Ok(()),
+            PaymentStatus::Succeeded { preimage, fee_msat } => {
+                let amount_msat = DecodedInvoice::decode(&entry.payment_request)
+                    .ok()
+                    .and_then(|invoice| invoice.amount_msat);
+                entry.state = JournalState::Succeeded;
+                entry.payment = Some(PayInvoiceResult {
+                    payment_hash: payment_hash.to_owned(),
+                    preimage,
+                    fee_msat,
+                    amount_msat,
+                    settled_at: unix_timestamp(),
+                });
+            }
+            PaymentStatus::Failed { reason } => {
+                entry.state = JournalState::Failed;
+                entry.failure = Some(reason);
+            }
+        }
+        entry.finished_at = Some(unix_timestamp());
+        self.save(&entries)
+    }
+
+    fn succeed(&self, payment: &PayInvoiceResult) -> Result<(), LightningError> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&payment.payment_hash) {
+            entry.state = JournalState::Succeeded;
+            entry.finished_at = Some(unix_timestamp());
+            entry.payment = Some(payment.clone());
+        }
+        self.save(&entries)
+    }
+
+    fn save(&self, entries: &HashMap<String, JournalEntry>) -> Result<(), LightningError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut entries: Vec<_> = entries.values().collect();
+        entries.sort_by_key(|entry| entry.started_at);
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // write then rename so a crash never leaves a truncated journal behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(&entries)?)?;
+        std::fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Lightning for JournaledLightning {
+    async fn pay_invoice_with_options(
+        &self,
+        payment_request: String,
+        options: PayOptions,
+    ) -> Result<PayInvoiceResult, anyhow::Error> {
+        let payment_hash = DecodedInvoice::decode(&payment_request)?.payment_hash;
+        if let Some(payment) = self.begin(&payment_hash, &payment_request)? {
+            info!("Invoice {payment_hash} was already paid, not paying it again");
+            return Ok(payment);
+        }
+
+        match self
+            .inner
+            .pay_invoice_with_options(payment_request, options)
+            .await
+        {
+            Ok(payment) => {
+                // payment_hash is the one we keyed the entry by, whatever the
+                // backend reports
+                let payment = PayInvoiceResult {
+                    payment_hash,
+                    ..payment
+                };
+                self.succeed(&payment)?;
+                Ok(payment)
+            }
+            Err(err) if rejected_before_paying(&err) => {
+                self.finish(
+                    &payment_hash,
+                    PaymentStatus::Failed {
+                        reason: err.to_string(),
+                    },
+                )?;
+                Err(err)
+            }
+            // any other error doesn't mean nothing was paid, e.g. the
+            // connection may have dropped mid-payment, so only the backend
+            // can settle it
+            Err(err) => {
+                match self.inner.payment_status(&payment_hash).await {
+                    Ok(status @ PaymentStatus::Succeeded { .. }) => {
+                        self.finish(&payment_hash, status)?;
+                        if let Some(payment) =
+                            self.entry(&payment_hash).and_then(|entry| entry.payment)
+                        {
+                            return Ok(payment);
+                        }
+                    }
+                    Ok(PaymentStatus::Pending) => {}
+                    Ok(status) => self.finish(&payment_hash, status)?,
+                    Err(status_err) => warn!(
+                        "Can not tell if payment {payment_hash} went through, \
+                         leaving it pending: {status_err}"
+                    ),
+                }
+                Err(err)
+            }
+        }
+    }
+
+    async fn create_invoice(
+        &self,
+        params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        self.inner.create_invoice(params).await
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error> {
+        self.inner.invoice_status(payment_hash).await
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus, anyhow::Error> {
+        self.inner.payment_status(payment_hash).await
+    }
+
+    fn subscribe_invoice(&self, payment_hash: &str) -> InvoiceEventStream<'_> {
+        self.inner.subscribe_invoice(payment_hash)
+    }
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error> {
+        self.inner.balance().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::lightning::testing::{FakeLightning, Pay, TestInvoice};
+
+    // Starts paying through a journal at `path` and kills it once the backend
+    // has the payment in flight, as if the process crashed
+    async fn crash_mid_payment(path: &Path) -> TestInvoice {
+        let lightning = Arc::new(FakeLightning::paying(Pay::Hang));
+        let invoice = lightning.invoice(Some(5_000));
+        let journal = Arc::new(
+            JournaledLightning::open(lightning.clone(), path)
+                .await
+                .unwrap(),
+        );
+
+        let payment = tokio::spawn({
+            let journal = journal.clone();
+            let payment_request = invoice.payment_request.clone();
+            async move { journal.pay_invoice(payment_request).await }
+        });
+        while lightning.paid().is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        payment.abort();
+        let _ = payment.await;
+
+        invoice
+    }
+
+    fn in_flight(err: &anyhow::Error) -> bool {
+        matches!(
+            err.downcast_ref::<LightningError>(),
+            Some(LightningError::PaymentInFlight(_))
+        )
+    }
+
+    #[tokio::test]
+    async fn crash_leaves_payment_in_flight_when_backend_lost_track_of_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.json");
+        let invoice = crash_mid_payment(&path).await;
+
+        // e.g. strike after a restart, which can't look the payment up
+        let lightning = Arc::new(FakeLightning::new());
+        let journal = JournaledLightning::open(lightning.clone(), &path)
+            .await
+            .unwrap();
+        let entry = journal.entry(&invoice.payment_hash).unwrap();
+        assert_eq!(entry.state, JournalState::Pending);
+
+        let err = journal
+            .pay_invoice(invoice.payment_request.clone())
+            .await
+            .unwrap_err();
+        assert!(in_flight(&err), "{err}");
+        assert!(lightning.paid().is_empty());
+    }
+
+    #[tokio::test]
+    async fn crash_leaves_payment_in_flight_when_backend_is_unreachable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.json");
+        let invoice = crash_mid_payment(&path).await;
+
+        let lightning = Arc::new(FakeLightning::new());
+        lightning.set_payment(
+            &invoice.payment_hash,
+            PaymentStatus::Failed {
+                reason: "no route".to_owned(),
+            },
+        );
+        *lightning.lookups_fail.lock().unwrap() = true;
+        let journal = JournaledLightning::open(lightning.clone(), &path)
+            .await
+            .unwrap();
+
+        let err = journal
+            .pay_invoice(invoice.payment_request.clone())
+            .await
+            .unwrap_err();
+        assert!(in_flight(&err), "{err}");
+        assert!(lightning.paid().is_empty());
+    }
+
+    #[tokio::test]
+    async fn crash_reconciles_to_a_payment_the_backend_completed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.json");
+        let invoice = crash_mid_payment(&path).await;
+
+        let lightning = Arc::new(FakeLightning::new());
+        lightning.set_payment(
+            &invoice.payment_hash,
+            PaymentStatus::Succeeded {
+                preimage: Some(invoice.preimage.clone()),
+                fee_msat: Some(3),
+            },
+        );
+        let journal = JournaledLightning::open(lightning.clone(), &path)
+            .await
+            .unwrap();
+        assert_eq!(
+            journal.entry(&invoice.payment_hash).unwrap().state,
+            JournalState::Succeeded
+        );
+
+        let payment = journal
+            .pay_invoice(invoice.payment_request.clone())
+            .await
+            .unwrap();
+        assert_eq!(payment.preimage, Some(invoice.preimage));
+        assert_eq!(payment.fee_msat, Some(3));
+        assert_eq!(payment.amount_msat, Some(5_000));
+        assert!(lightning.paid().is_empty());
+    }
+
+    #[tokio::test]
+    async fn crash_reconciles_to_a_payment_the_backend_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.json");
+        let invoice = crash_mid_payment(&path).await;
+
+        let lightning = Arc::new(FakeLightning::new());
+        lightning.set_payment(
+            &invoice.payment_hash,
+            PaymentStatus::Failed {
+                reason: "no route".to_owned(),
+            },
+        );
+        let journal = JournaledLightning::open(lightning.clone(), &path)
+            .await
+            .unwrap();
+        assert_eq!(
+            journal.entry(&invoice.payment_hash).unwrap().state,
+            JournalState::Failed
+        );
+
+        journal
+            .pay_invoice(invoice.payment_request.clone())
+            .await
+            .unwrap();
+        assert_eq!(lightning.paid(), vec![invoice.payment_hash]);
+    }
+
+    #[tokio::test]
+    async fn paid_invoice_is_not_paid_again() {
+        let lightning = Arc::new(FakeLightning::new());
+        let invoice = lightning.invoice(Some(1_000));
+        let journal = JournaledLightning::new(lightning.clone());
+
+        let first = journal
+            .pay_invoice(invoice.payment_request.clone())
+            .await
+            .unwrap();
+        let second = journal
+            .pay_invoice(invoice.payment_request.clone())
+            .await
+            .unwrap();
+        assert_eq!(first.preimage, second.preimage);
+        assert_eq!(lightning.paid().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn lost_response_is_recovered_from_the_backend() {
+        let lightning = Arc::new(FakeLightning::paying(Pay::Lose));
+        let invoice = lightning.invoice(Some(1_000));
+        let journal = JournaledLightning::new(lightning.clone());
+
+        let payment = journal
+            .pay_invoice(invoice.payment_request.clone())
+            .await
+            .unwrap();
+        assert_eq!(payment.preimage, Some(invoice.preimage));
+        assert_eq!(
+            journal.entry(&invoice.payment_hash).unwrap().state,
+            JournalState::Succeeded
+        );
+    }
+
+    #[tokio::test]
+    async fn lost_response_stays_in_flight_when_backend_lost_track_of_it() {
+        let lightning = Arc::new(FakeLightning::paying(Pay::Lose));
+        *lightning.lookups_fail.lock().unwrap() = true;
+        let invoice = lightning.invoice(Some(1_000));
+        let journal = JournaledLightning::new(lightning.clone());
+
+        journal
+            .pay_invoice(invoice.payment_request.clone())
+            .await
+            .unwrap_err();
+        let err = journal
+            .pay_invoice(invoice.payment_request.clone())
+            .await
+            .unwrap_err();
+        assert!(in_flight(&err), "{err}");
+        assert_eq!(lightning.paid().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_payment_can_be_retried() {
+        let lightning = Arc::new(FakeLightning::paying(Pay::Fail));
+        let invoice = lightning.invoice(Some(1_000));
+        let journal = JournaledLightning::new(lightning.clone());
+
+        journal
+            .pay_invoice(invoice.payment_request.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            journal.entry(&invoice.payment_hash).unwrap().state,
+            JournalState::Failed
+        );
+
+        *lightning.pay.lock().unwrap() = Pay::Succeed { fee_msat: 0 };
+        journal
+            .pay_invoice(invoice.payment_request.clone())
+            .await
+            .unwrap();
+        assert_eq!(lightning.paid().len(), 2);
+    }
+}
@@ -1,8 +1,19 @@
+use log::warn;
 use reqwest::header::{HeaderValue, CONTENT_TYPE};
+use serde::Deserialize;
 use url::Url;
 
 use super::error::LightningError;
-use super::model::{CreateInvoiceParams, CreateInvoiceResult, PayInvoiceResult};
+use super::model::{
+    BalanceResult, CreateInvoiceParams, CreateInvoiceResult, InvoiceStatus, PayInvoiceResult,
+    PaymentStatus,
+};
+use super::utils::unix_timestamp;
+
+#[derive(Deserialize)]
+struct LnbitsPayResponse {
+    payment_hash: String,
+}
 
 #[derive(Clone)]
 pub struct LNBitsClient {
@@ -110,7 +121,7 @@ impl LNBitsClient {
             .to_owned();
 
         Ok(CreateInvoiceResult {
-            payment_hash: payment_hash.as_bytes().to_vec(),
+            payment_hash: hex::decode(payment_hash)?,
             payment_request,
         })
     }
@@ -123,7 +134,36 @@ impl LNBitsClient {
             )
             .await?;
 
-        Ok(serde_json::from_str(&body)?)
+        let response: LnbitsPayResponse = serde_json::from_str(&body)?;
+        let payment_hash = response.payment_hash;
+
+        // the pay response only carries the hash, the preimage and fee live on the
+        // payment itself. The payment went through either way, so a failed
+        // lookup must not turn it into an error.
+        let payment = match self
+            .make_get(&format!("api/v1/payments/{payment_hash}"))
+            .await
+            .and_then(|body| Ok(serde_json::from_str::<serde_json::Value>(&body)?))
+        {
+            Ok(payment) => payment,
+            Err(err) => {
+                warn!("Paid {payment_hash} but can not look up its preimage and fee: {err}");
+                serde_json::Value::Null
+            }
+        };
+
+        // outgoing amounts and fees are reported as negative msats
+        Ok(PayInvoiceResult {
+            payment_hash,
+            preimage: payment["preimage"].as_str().map(str::to_owned),
+            fee_msat: payment["details"]["fee"]
+                .as_i64()
+                .map(|fee| fee.unsigned_abs()),
+            amount_msat: payment["details"]["amount"]
+                .as_i64()
+                .map(|amount| amount.unsigned_abs()),
+            settled_at: unix_timestamp(),
+        })
     }
 
     pub async fn is_invoice_paid(&self, payment_hash: &str) -> Result<bool, LightningError> {
@@ -135,4 +175,121 @@ impl LNBitsClient {
             .as_bool()
             .unwrap_or(false))
     }
+
+    pub async fn payment_status(
+        &self,
+        payment_hash: &str,
+    ) -> Result<PaymentStatus, LightningError> {
+        let body = self
+            .make_get(&format!("api/v1/payments/{payment_hash}"))
+            .await?;
+        let response: serde_json::Value = serde_json::from_str(&body)?;
+
+        if response["paid"].as_bool().unwrap_or(false) {
+            return Ok(PaymentStatus::Succeeded {
+                preimage: response["preimage"].as_str().map(str::to_owned),
+                fee_msat: response["details"]["fee"]
+                    .as_i64()
+                    .map(|fee| fee.unsigned_abs()),
+            });
+        }
+
+        // older versions only report whether the payment is still pending
+        let failed = match response["details"]["status"].as_str() {
+            Some(status) => status == "failed",
+            None => response["details"]["pending"].as_bool() == Some(false),
+        };
+        Ok(if failed {
+            PaymentStatus::Failed {
+                reason: "LNbits reports the payment as failed".to_owned(),
+            }
+        } else {
+            PaymentStatus::Pending
+        })
+    }
+
+    pub async fn invoice_status(
+        &self,
+        payment_hash: &str,
+    ) -> Result<InvoiceStatus, LightningError> {
+        let body = self
+            .make_get(&format!("api/v1/payments/{payment_hash}"))
+            .await?;
+        let response: serde_json::Value = serde_json::from_str(&body)?;
+
+        if response["paid"].as_bool().unwrap_or(false) {
+            return Ok(InvoiceStatus::Paid);
+        }
+
+        Ok(match response["details"]["status"].as_str() {
+            Some("expired") | Some("failed") => InvoiceStatus::Expired,
+            _ => InvoiceStatus::Pending,
+        })
+    }
+
+    pub async fn get_balance(&self) -> Result<BalanceResult, LightningError> {
+        let body = self.make_get("api/v1/wallet").await?;
+        let response: serde_json::Value = serde_json::from_str(&body)?;
+
+        // LNbits reports the wallet balance in msats
+        Ok(BalanceResult {
+            balance_msat: response["balance"].as_u64().unwrap_or(0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::testing::{respond, serve};
+
+    const PAYMENT_HASH: &str = "7a7c7d1fd9f3ab84fbe9b11d7c59bd6f8db4d33d4f0f1b34c3f8a7c3a6f1e0d2";
+
+    #[tokio::test]
+    async fn pay_invoice_reads_preimage_and_fee_from_the_payment() {
+        let url = serve(|request| match request.path.as_str() {
+            "/api/v1/payments" => {
+                assert_eq!(request.method, "POST");
+                assert_eq!(request.headers["x-api-key"], "key");
+                assert_eq!(request.json()["bolt11"], "lnbc1");
+                assert_eq!(request.json()["out"], true);
+                respond(201, format!(r#"{{"payment_hash":"{PAYMENT_HASH}"}}"#))
+            }
+            _ => respond(
+                200,
+                r#"{"paid":true,"preimage":"00ff","details":{"amount":-10000,"fee":-2000}}"#,
+            ),
+        })
+        .await;
+        let client = LNBitsClient::new("key", url.as_str(), None).unwrap();
+
+        let payment = client.pay_invoice("lnbc1").await.unwrap();
+        assert_eq!(payment.payment_hash, PAYMENT_HASH);
+        assert_eq!(payment.preimage.as_deref(), Some("00ff"));
+        assert_eq!(payment.fee_msat, Some(2000));
+        assert_eq!(payment.amount_msat, Some(10000));
+    }
+
+    #[tokio::test]
+    async fn pay_invoice_succeeds_when_the_payment_lookup_fails() {
+        let url = serve(|request| match request.path.as_str() {
+            "/api/v1/payments" => respond(201, format!(r#"{{"payment_hash":"{PAYMENT_HASH}"}}"#)),
+            _ => respond(500, "Internal Server Error"),
+        })
+        .await;
+        let client = LNBitsClient::new("key", url.as_str(), None).unwrap();
+
+        let payment = client.pay_invoice("lnbc1").await.unwrap();
+        assert_eq!(payment.payment_hash, PAYMENT_HASH);
+        assert_eq!(payment.preimage, None);
+    }
+
+    #[tokio::test]
+    async fn pay_invoice_errors_without_a_payment_hash() {
+        let url = serve(|_| respond(400, r#"{"detail":"Insufficient balance."}"#)).await;
+        let client = LNBitsClient::new("key", url.as_str(), None).unwrap();
+
+        let err = client.pay_invoice("lnbc1").await.unwrap_err();
+        assert!(matches!(err, LightningError::SerdeError(_)), "{err}");
+    }
 }
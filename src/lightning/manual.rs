@@ -0,0 +1,365 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription};
+use log::warn;
+use once_cell::sync::OnceCell;
+use qrcode::render::unicode;
+use qrcode::QrCode;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::Mutex;
+
+use super::model::{
+    BalanceResult, CreateInvoiceParams, CreateInvoiceResult, InvoiceStatus, PayInvoiceResult,
+    PayOptions, PaymentStatus,
+};
+use super::utils::{decode_invoice, unix_timestamp};
+use super::Lightning;
+
+type Lines = Mutex<UnboundedReceiver<String>>;
+
+// stdin is read line by line on a thread of its own. Receiving from the
+// channel can be cancelled, so a payment that times out while waiting doesn't
+// swallow a line meant for the next one.
+static STDIN_LINES: OnceCell<Lines> = OnceCell::new();
+
+fn stdin_lines() -> &'static Lines {
+    STDIN_LINES.get_or_init(|| {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Mutex::new(receiver)
+    })
+}
+
+/// Looks up the preimage of an invoice paid outside of bullpen, e.g. through
+/// the API of the wallet it was paid from
+#[async_trait]
+pub trait PreimageSource: Send + Sync {
+    async fn preimage(&self, invoice: &Bolt11Invoice) -> Result<Option<String>, anyhow::Error>;
+}
+
+/// Pays by showing the invoice as a QR code in the terminal for a phone wallet
+/// to scan, then waits for the preimage to be pasted on stdin or to turn up in
+/// a `PreimageSource`. Can only pay invoices.
+#[derive(Clone)]
+pub struct ManualLightning {
+    preimage_source: Option<Arc<dyn PreimageSource>>,
+    poll_interval: Duration,
+}
+
+impl Default for ManualLightning {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualLightning {
+    pub fn new() -> Self {
+        Self {
+            preimage_source: None,
+            poll_interval: Duration::from_secs(2),
+        }
+    }
+
+    pub fn with_preimage_source(
+        mut self,
+        preimage_source: Arc<dyn PreimageSource>,
+        poll_interval: Duration,
+    ) -> Self {
+        self.preimage_source = Some(preimage_source);
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    fn show_invoice(&self, invoice: &Bolt11Invoice, options: &PayOptions) {
+        let amount = match options.amount_msat(invoice) {
+            Some(amount_msat) if amount_msat % 1000 == 0 => format!("{} sats", amount_msat / 1000),
+            Some(amount_msat) => format!("{} msats", amount_msat),
+            None => "any amount".to_owned(),
+        };
+        let description = match invoice.description() {
+            Bolt11InvoiceDescription::Direct(description) => description.to_string(),
+            Bolt11InvoiceDescription::Hash(_) => "(description hash)".to_owned(),
+        };
+
+        // uppercase bech32 makes for a smaller QR code
+        let uri = format!("lightning:{}", invoice.to_string().to_uppercase());
+        match QrCode::new(uri.as_bytes()) {
+            Ok(code) => eprintln!(
+                "\n{}",
+                code.render::<unicode::Dense1x2>()
+                    .dark_color(unicode::Dense1x2::Light)
+                    .light_color(unicode::Dense1x2::Dark)
+                    .build()
+            ),
+            Err(err) => eprintln!("Can not render invoice QR code: {err}"),
+        }
+        eprintln!("{invoice}\n");
+        eprintln!("Amount:       {amount}");
+        eprintln!("Description:  {description}");
+        eprintln!("Payment hash: {}", invoice.payment_hash());
+        if let Some(expires_at) = invoice.expires_at() {
+            let expires_in = expires_at.as_secs().saturating_sub(unix_timestamp());
+            eprintln!("Expires in:   {expires_in}s");
+        }
+        eprintln!("\nPay the invoice, then paste the preimage and press enter:");
+    }
+
+    async fn read_preimage(
+        &self,
+        invoice: &Bolt11Invoice,
+        lines: &Lines,
+    ) -> Result<String, anyhow::Error> {
+        let mut lines = lines.lock().await;
+        while let Some(line) = lines.recv().await {
+            let preimage = line.trim().to_lowercase();
+            if preimage.is_empty() {
+                continue;
+            }
+            if preimage_matches(invoice, &preimage) {
+                return Ok(preimage);
+            }
+            eprintln!("That is not the preimage of this invoice, try again:");
+        }
+
+        Err(anyhow::anyhow!("stdin closed before a preimage was pasted"))
+    }
+
+    // Errors from the source, e.g. a flaky wallet API, are only logged so
+    // polling goes on until the payment times out
+    async fn poll_preimage(
+        &self,
+        invoice: &Bolt11Invoice,
+        preimage_source: &dyn PreimageSource,
+    ) -> String {
+        loop {
+            match preimage_source.preimage(invoice).await {
+                Ok(Some(preimage)) if preimage_matches(invoice, &preimage) => return preimage,
+                Ok(_) => {}
+                Err(err) => warn!(
+                    "Can not look up the preimage of {}: {err}",
+                    invoice.payment_hash()
+                ),
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Waits up to `wait` for the preimage to be pasted on `lines` or found by
+    /// the preimage source
+    async fn wait_for_preimage(
+        &self,
+        invoice: &Bolt11Invoice,
+        lines: &Lines,
+        wait: Duration,
+    ) -> Result<String, anyhow::Error> {
+        let pasted = async {
+            match self.read_preimage(invoice, lines).await {
+                // e.g. not run from a terminal, the source may still find it
+                Err(err) if self.preimage_source.is_some() => {
+                    warn!("{err}, waiting for the preimage source");
+                    std::future::pending().await
+                }
+                preimage => preimage,
+            }
+        };
+        let polled = async {
+            match &self.preimage_source {
+                Some(preimage_source) => {
+                    self.poll_preimage(invoice, preimage_source.as_ref()).await
+                }
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            preimage = pasted => preimage,
+            preimage = polled => Ok(preimage),
+            _ = tokio::time::sleep(wait) => Err(anyhow::anyhow!("Invoice was not paid in time")),
+        }
+    }
+}
+
+fn preimage_matches(invoice: &Bolt11Invoice, preimage: &str) -> bool {
+    hex::decode(preimage).is_ok_and(|preimage| {
+        hex::encode(Sha256::digest(preimage)) == invoice.payment_hash().to_string()
+    })
+}
+
+#[async_trait]
+impl Lightning for ManualLightning {
+    async fn pay_invoice_with_options(
+        &self,
+        payment_request: String,
+        options: PayOptions,
+    ) -> Result<PayInvoiceResult, anyhow::Error> {
+        // the fee is up to whichever wallet the invoice is paid from
+        options.ensure_supported("manual", &["timeout", "amount_msat_for_zero_amount"])?;
+        let invoice = decode_invoice(&payment_request)?.invoice;
+        self.show_invoice(&invoice, &options);
+
+        let expires_in = invoice
+            .expires_at()
+            .map(|expires_at| expires_at.as_secs().saturating_sub(unix_timestamp()))
+            .unwrap_or(u64::MAX);
+        let wait = options
+            .timeout
+            .map_or(Duration::from_secs(expires_in), |timeout| {
+                timeout.min(Duration::from_secs(expires_in))
+            });
+        let preimage = self
+            .wait_for_preimage(&invoice, stdin_lines(), wait)
+            .await?;
+        eprintln!("Preimage accepted");
+
+        Ok(PayInvoiceResult {
+            payment_hash: invoice.payment_hash().to_string(),
+            preimage: Some(preimage),
+            fee_msat: None,
+            amount_msat: options.amount_msat(&invoice),
+            settled_at: unix_timestamp(),
+        })
+    }
+
+    async fn create_invoice(
+        &self,
+        _params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        Err(anyhow::anyhow!("Manual payments can only pay invoices"))
+    }
+
+    async fn invoice_status(&self, _payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error> {
+        Err(anyhow::anyhow!("Manual payments can only pay invoices"))
+    }
+
+    async fn payment_status(&self, _payment_hash: &str) -> Result<PaymentStatus, anyhow::Error> {
+        Err(anyhow::anyhow!(
+            "Manual payments are not tracked, check the wallet they were paid from"
+        ))
+    }
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error> {
+        Err(anyhow::anyhow!("Manual payments can only pay invoices"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::lightning::testing;
+
+    // Errors on the first lookup, then finds the preimage
+    struct FlakySource {
+        preimage: String,
+        lookups: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl PreimageSource for FlakySource {
+        async fn preimage(
+            &self,
+            _invoice: &Bolt11Invoice,
+        ) -> Result<Option<String>, anyhow::Error> {
+            match self.lookups.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(anyhow::anyhow!("connection reset")),
+                _ => Ok(Some(self.preimage.clone())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn timed_out_read_keeps_the_next_line() {
+        let invoice = testing::invoice(Some(10_000));
+        let decoded = decode_invoice(&invoice.payment_request).unwrap().invoice;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let lines = Mutex::new(receiver);
+        let manual = ManualLightning::new();
+
+        let timed_out = tokio::time::timeout(
+            Duration::from_millis(10),
+            manual.read_preimage(&decoded, &lines),
+        )
+        .await;
+        assert!(timed_out.is_err());
+
+        sender
+            .send(format!("  {}\n", invoice.preimage.to_uppercase()))
+            .unwrap();
+        let preimage = manual.read_preimage(&decoded, &lines).await.unwrap();
+        assert_eq!(preimage, invoice.preimage);
+    }
+
+    #[tokio::test]
+    async fn read_errors_once_stdin_closes() {
+        let invoice = testing::invoice(Some(10_000));
+        let decoded = decode_invoice(&invoice.payment_request).unwrap().invoice;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let lines = Mutex::new(receiver);
+
+        sender.send("not a preimage".to_owned()).unwrap();
+        drop(sender);
+        assert!(ManualLightning::new()
+            .read_preimage(&decoded, &lines)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_polling_after_a_failed_lookup() {
+        let invoice = testing::invoice(Some(10_000));
+        let source = Arc::new(FlakySource {
+            preimage: invoice.preimage.clone(),
+            lookups: AtomicUsize::new(0),
+        });
+        let manual =
+            ManualLightning::new().with_preimage_source(source.clone(), Duration::from_millis(1));
+
+        let payment = manual
+            .pay_invoice_with_options(
+                invoice.payment_request.clone(),
+                PayOptions {
+                    timeout: Some(Duration::from_secs(10)),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(payment.preimage, Some(invoice.preimage));
+        assert_eq!(payment.amount_msat, Some(10_000));
+        assert_eq!(source.lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn closed_stdin_leaves_the_source_polling() {
+        let invoice = testing::invoice(Some(10_000));
+        let decoded = decode_invoice(&invoice.payment_request).unwrap().invoice;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let lines = Mutex::new(receiver);
+        drop(sender);
+        let source = Arc::new(FlakySource {
+            preimage: "00".repeat(32),
+            lookups: AtomicUsize::new(0),
+        });
+        let manual =
+            ManualLightning::new().with_preimage_source(source.clone(), Duration::from_millis(1));
+
+        // the source never turns up the right preimage
+        let err = manual
+            .wait_for_preimage(&decoded, &lines, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not paid in time"), "{err}");
+        assert!(source.lookups.load(Ordering::SeqCst) > 2);
+    }
+}
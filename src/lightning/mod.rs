@@ -1,27 +1,49 @@
 use std::fmt::{self, Formatter};
+use std::pin::Pin;
+use std::time::Duration;
 
 use async_trait::async_trait;
+use cln_rpc::model::{requests, responses};
+use cln_rpc::primitives::{Amount, AmountOrAny, ChannelState};
+use cln_rpc::ClnRpc;
+use futures_util::future;
+use futures_util::stream::{self, Stream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+use tonic_lnd::lnrpc::invoice::InvoiceState;
+use tonic_lnd::lnrpc::payment::PaymentStatus as PaymentState;
 use tonic_lnd::Client;
 use url::Url;
 mod alby;
+pub mod budget;
 pub mod error;
+pub mod invoice;
+pub mod journal;
 mod lnbits;
-mod model;
+pub mod manual;
+pub mod model;
 mod strike;
+#[cfg(test)]
+pub(crate) mod testing;
 pub mod utils;
+pub mod webhook;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use self::alby::AlbyClient;
+use self::error::LightningError;
+use self::invoice::{InvoicePolicy, InvoicePolicyLightning};
 use self::lnbits::LNBitsClient;
-use self::model::PayInvoiceResult;
+use self::model::{
+    BalanceResult, CreateInvoiceParams, CreateInvoiceResult, InvoiceEvent, InvoiceStatus,
+    PayInvoiceResult, PayOptions, PaymentStatus,
+};
 use self::strike::StrikeClient;
-use self::utils::decode_invoice;
+use self::utils::{decode_invoice, unix_timestamp, whole_sats};
 
-#[derive(Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase")]
 pub enum LightningType {
     Lnbits(LnbitsLightningSettings),
     Alby(AlbyLightningSettings),
@@ -30,6 +52,133 @@ pub enum LightningType {
     Cln(ClnLightningSettings),
 }
 
+impl LightningType {
+    /// Parses a `LightningType` from TOML, e.g.
+    ///
+    /// ```toml
+    /// backend = "lnbits"
+    /// admin_key = "..."
+    /// url = "https://legend.lnbits.com"
+    /// ```
+    pub fn from_toml_str(toml: &str) -> Result<Self, LightningError> {
+        let lightning_type: Self = toml::from_str(toml)?;
+        lightning_type.validate()?;
+        Ok(lightning_type)
+    }
+
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<Self, LightningError> {
+        Self::from_toml_str(&std::fs::read_to_string(path)?)
+    }
+
+    /// Loads a `LightningType` from the environment. `LIGHTNING_BACKEND`
+    /// selects the backend and its settings are read from variables
+    /// prefixed with the backend name, e.g. `LND_GRPC_HOST` or
+    /// `LNBITS_ADMIN_KEY`.
+    pub fn from_env() -> Result<Self, LightningError> {
+        dotenv::dotenv().ok();
+        let backend = std::env::var("LIGHTNING_BACKEND")
+            .map_err(|_| LightningError::MissingSetting("LIGHTNING_BACKEND"))?;
+
+        let lightning_type = match backend.to_lowercase().as_str() {
+            "lnbits" => LightningType::Lnbits(envy::prefixed("LNBITS_").from_env()?),
+            "alby" => LightningType::Alby(envy::prefixed("ALBY_").from_env()?),
+            "strike" => LightningType::Strike(envy::prefixed("STRIKE_").from_env()?),
+            "lnd" => LightningType::Lnd(envy::prefixed("LND_").from_env()?),
+            "cln" => LightningType::Cln(envy::prefixed("CLN_").from_env()?),
+            _ => {
+                return Err(LightningError::InvalidSetting(
+                    "LIGHTNING_BACKEND",
+                    format!("unknown backend {backend}"),
+                ))
+            }
+        };
+        lightning_type.validate()?;
+
+        Ok(lightning_type)
+    }
+
+    pub fn validate(&self) -> Result<(), LightningError> {
+        match self {
+            LightningType::Lnbits(settings) => settings.validate(),
+            LightningType::Alby(settings) => settings.validate(),
+            LightningType::Strike(settings) => settings.validate(),
+            LightningType::Lnd(settings) => settings.validate(),
+            LightningType::Cln(settings) => settings.validate(),
+        }
+    }
+
+    /// Validates the settings and connects to the configured backend, which
+    /// checks invoices against the default `InvoicePolicy` before paying
+    pub async fn connect(&self) -> Result<Arc<dyn Lightning>, anyhow::Error> {
+        self.connect_with_policy(InvoicePolicy::default()).await
+    }
+
+    /// Like `connect`, with a policy restricting e.g. networks or payees
+    pub async fn connect_with_policy(
+        &self,
+        invoice_policy: InvoicePolicy,
+    ) -> Result<Arc<dyn Lightning>, anyhow::Error> {
+        self.validate()?;
+
+        // validate() guarantees the required settings are present
+        let lightning: Arc<dyn Lightning> = match self {
+            LightningType::Lnbits(settings) => Arc::new(LnbitsLightning::new(
+                required(&settings.admin_key, "admin_key")?.to_owned(),
+                required(&settings.url, "url")?.to_owned(),
+            )?),
+            LightningType::Alby(settings) => Arc::new(AlbyLightning::new(
+                required(&settings.api_key, "api_key")?.to_owned(),
+            )?),
+            LightningType::Strike(settings) => Arc::new(StrikeLightning::new(
+                required(&settings.api_key, "api_key")?.to_owned(),
+            )?),
+            LightningType::Lnd(settings) => Arc::new(
+                LndLightning::new(
+                    required(&settings.grpc_host, "grpc_host")?.clone(),
+                    required(&settings.tls_cert_path, "tls_cert_path")?,
+                    required(&settings.macaroon_path, "macaroon_path")?,
+                )
+                .await?,
+            ),
+            LightningType::Cln(settings) => {
+                Arc::new(ClnLightning::new(required(&settings.rpc_path, "rpc_path")?).await?)
+            }
+        };
+
+        Ok(Arc::new(InvoicePolicyLightning::new(
+            lightning,
+            invoice_policy,
+        )))
+    }
+}
+
+fn required<'a, T>(setting: &'a Option<T>, name: &'static str) -> Result<&'a T, LightningError> {
+    setting.as_ref().ok_or(LightningError::MissingSetting(name))
+}
+
+fn required_file(setting: &Option<PathBuf>, name: &'static str) -> Result<(), LightningError> {
+    let path = required(setting, name)?;
+    if !path.is_file() {
+        return Err(LightningError::InvalidSetting(
+            name,
+            format!("{} is not a file", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+fn display_setting<T: fmt::Display>(setting: &Option<T>) -> String {
+    setting
+        .as_ref()
+        .map_or_else(|| "<unset>".to_owned(), ToString::to_string)
+}
+
+fn display_path_setting(setting: &Option<PathBuf>) -> String {
+    setting
+        .as_ref()
+        .map_or_else(|| "<unset>".to_owned(), |path| path.display().to_string())
+}
+
 impl fmt::Display for LightningType {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -44,8 +193,144 @@ impl fmt::Display for LightningType {
 
 #[async_trait]
 pub trait Lightning: Send + Sync {
-    async fn pay_invoice(&self, payment_request: String)
-        -> Result<PayInvoiceResult, anyhow::Error>;
+    async fn pay_invoice(
+        &self,
+        payment_request: String,
+    ) -> Result<PayInvoiceResult, anyhow::Error> {
+        self.pay_invoice_with_options(payment_request, PayOptions::default())
+            .await
+    }
+
+    async fn pay_invoice_with_options(
+        &self,
+        payment_request: String,
+        options: PayOptions,
+    ) -> Result<PayInvoiceResult, anyhow::Error>;
+
+    async fn create_invoice(
+        &self,
+        params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error>;
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error>;
+
+    /// Status of a payment we made, `LightningError::NotFound` if the backend
+    /// has no record of it
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus, anyhow::Error>;
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error>;
+
+    /// Events for an invoice we created, starting with its current status and
+    /// ending once it is paid or expires. Polls `invoice_status` with backoff
+    /// unless the backend can push updates.
+    fn subscribe_invoice(&self, payment_hash: &str) -> InvoiceEventStream<'_> {
+        let payment_hash = payment_hash.to_owned();
+        let polls = stream::unfold(None, move |delay: Option<Duration>| {
+            let payment_hash = payment_hash.clone();
+            async move {
+                if let Some(delay) = delay {
+                    tokio::time::sleep(delay).await;
+                }
+                let event = self
+                    .invoice_status(&payment_hash)
+                    .await
+                    .map(|status| InvoiceEvent::new(&payment_hash, status));
+                let next_delay = delay.map_or(INVOICE_POLL_MIN_DELAY, |delay| {
+                    (delay * 2).min(INVOICE_POLL_MAX_DELAY)
+                });
+                Some((event, Some(next_delay)))
+            }
+        });
+        until_settled(polls)
+    }
+
+    /// Returns the event that settled the invoice, paid or expired, or
+    /// `LightningError::InvoiceWaitTimeout` if neither happens in time
+    async fn wait_for_payment(
+        &self,
+        payment_hash: &str,
+        timeout: Duration,
+    ) -> Result<InvoiceEvent, anyhow::Error> {
+        let mut events = self.subscribe_invoice(payment_hash);
+        let settled = async {
+            while let Some(event) = events.next().await {
+                let event = event?;
+                if event.status != InvoiceStatus::Pending {
+                    return Ok(event);
+                }
+            }
+            Err(anyhow::anyhow!(
+                "Invoice events ended before it was settled"
+            ))
+        };
+
+        tokio::time::timeout(timeout, settled)
+            .await
+            .map_err(|_| LightningError::InvoiceWaitTimeout(payment_hash.to_owned()))?
+    }
+}
+
+const INVOICE_POLL_MIN_DELAY: Duration = Duration::from_secs(1);
+const INVOICE_POLL_MAX_DELAY: Duration = Duration::from_secs(15);
+
+pub type InvoiceEventStream<'a> =
+    Pin<Box<dyn Stream<Item = Result<InvoiceEvent, anyhow::Error>> + Send + 'a>>;
+
+// Passes on events until the invoice is paid or expires, dropping repeats of
+// the last status. Ends after the first error.
+fn until_settled<'a>(
+    events: impl Stream<Item = Result<InvoiceEvent, anyhow::Error>> + Send + 'a,
+) -> InvoiceEventStream<'a> {
+    let state = (Box::pin(events), None, false);
+    Box::pin(stream::unfold(
+        state,
+        |(mut events, mut last, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                match events.next().await? {
+                    Ok(event) if last == Some(event.status) => continue,
+                    Ok(event) => {
+                        let done = event.status != InvoiceStatus::Pending;
+                        last = Some(event.status);
+                        return Some((Ok(event), (events, last, done)));
+                    }
+                    Err(err) => return Some((Err(err), (events, last, true))),
+                }
+            }
+        },
+    ))
+}
+
+pub type PaymentStatusStream =
+    Pin<Box<dyn Stream<Item = Result<PaymentStatus, anyhow::Error>> + Send>>;
+
+/// Polls the status of a payment, yielding every change until it succeeds or
+/// fails. The stream ends after the first error.
+pub fn payment_status_stream(
+    lightning: Arc<dyn Lightning>,
+    payment_hash: String,
+    poll_interval: Duration,
+) -> PaymentStatusStream {
+    Box::pin(stream::unfold(Some(None), move |last| {
+        let lightning = lightning.clone();
+        let payment_hash = payment_hash.clone();
+        async move {
+            let last: Option<PaymentStatus> = last?;
+            loop {
+                match lightning.payment_status(&payment_hash).await {
+                    Ok(status) if last.as_ref() == Some(&status) => {}
+                    Ok(status) => {
+                        let next = (!status.is_final()).then(|| Some(status.clone()));
+                        return Some((Ok(status), next));
+                    }
+                    Err(err) => return Some((Err(err), None)),
+                }
+                tokio::time::sleep(poll_interval).await;
+            }
+        }
+    }))
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
@@ -68,37 +353,72 @@ impl fmt::Display for LnbitsLightningSettings {
         write!(
             f,
             "admin_key: {}, url: {}",
-            self.admin_key.as_ref().unwrap(),
-            self.url.as_ref().unwrap()
+            display_setting(&self.admin_key),
+            display_setting(&self.url)
         )
     }
 }
 
+impl LnbitsLightningSettings {
+    pub fn validate(&self) -> Result<(), LightningError> {
+        required(&self.admin_key, "admin_key")?;
+        Url::parse(required(&self.url, "url")?)
+            .map_err(|err| LightningError::InvalidSetting("url", err.to_string()))?;
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct LnbitsLightning {
     pub client: LNBitsClient,
 }
 
 impl LnbitsLightning {
-    pub fn new(admin_key: String, url: String) -> Self {
-        Self {
-            client: LNBitsClient::new(&admin_key, &url, None)
-                .expect("Can not create Lnbits client"),
-        }
+    pub fn new(admin_key: String, url: String) -> Result<Self, LightningError> {
+        Ok(Self {
+            client: LNBitsClient::new(&admin_key, &url, None)?,
+        })
     }
 }
 
 #[async_trait]
 impl Lightning for LnbitsLightning {
-    async fn pay_invoice(
+    async fn pay_invoice_with_options(
         &self,
         payment_request: String,
+        options: PayOptions,
     ) -> Result<PayInvoiceResult, anyhow::Error> {
+        options.ensure_supported("lnbits", &[])?;
         self.client
             .pay_invoice(&payment_request)
             .await
             .map_err(|err| anyhow::anyhow!("Failed to pay invoice: {}", err))
     }
+
+    async fn create_invoice(
+        &self,
+        params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        self.client
+            .create_invoice(&params)
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to create invoice: {}", err))
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error> {
+        Ok(self.client.invoice_status(payment_hash).await?)
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus, anyhow::Error> {
+        Ok(self.client.payment_status(payment_hash).await?)
+    }
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error> {
+        self.client
+            .get_balance()
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to get balance: {}", err))
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
@@ -108,7 +428,7 @@ pub struct AlbyLightningSettings {
 
 impl fmt::Display for AlbyLightningSettings {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "api_key: {}", self.api_key.as_ref().unwrap(),)
+        write!(f, "api_key: {}", display_setting(&self.api_key))
     }
 }
 
@@ -118,6 +438,11 @@ impl AlbyLightningSettings {
             api_key: Some(api_key.to_owned()),
         }
     }
+
+    pub fn validate(&self) -> Result<(), LightningError> {
+        required(&self.api_key, "api_key")?;
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -126,23 +451,53 @@ pub struct AlbyLightning {
 }
 
 impl AlbyLightning {
-    pub fn new(api_key: String) -> Self {
-        Self {
-            client: AlbyClient::new(&api_key).expect("Can not create Alby client"),
-        }
+    pub fn new(api_key: String) -> Result<Self, LightningError> {
+        Ok(Self {
+            client: AlbyClient::new(&api_key)?,
+        })
     }
 }
 #[async_trait]
 impl Lightning for AlbyLightning {
-    async fn pay_invoice(
+    async fn pay_invoice_with_options(
         &self,
         payment_request: String,
+        options: PayOptions,
     ) -> Result<PayInvoiceResult, anyhow::Error> {
+        options.ensure_supported("alby", &["amount_msat_for_zero_amount"])?;
+        let invoice = decode_invoice(&payment_request)?.invoice;
+        let amount = whole_sats(options.amount_override_msat(&invoice), "alby")?;
+
         self.client
-            .pay_invoice(&payment_request)
+            .pay_invoice(&payment_request, amount)
             .await
             .map_err(|err| anyhow::anyhow!("Failed to pay invoice: {}", err))
     }
+
+    async fn create_invoice(
+        &self,
+        params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        self.client
+            .create_invoice(&params)
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to create invoice: {}", err))
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error> {
+        Ok(self.client.invoice_status(payment_hash).await?)
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus, anyhow::Error> {
+        Ok(self.client.payment_status(payment_hash).await?)
+    }
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error> {
+        self.client
+            .get_balance()
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to get balance: {}", err))
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
@@ -152,7 +507,7 @@ pub struct StrikeLightningSettings {
 
 impl fmt::Display for StrikeLightningSettings {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "api_key: {}", self.api_key.as_ref().unwrap(),)
+        write!(f, "api_key: {}", display_setting(&self.api_key))
     }
 }
 
@@ -162,6 +517,11 @@ impl StrikeLightningSettings {
             api_key: Some(api_key.to_owned()),
         }
     }
+
+    pub fn validate(&self) -> Result<(), LightningError> {
+        required(&self.api_key, "api_key")?;
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -170,45 +530,86 @@ pub struct StrikeLightning {
 }
 
 impl StrikeLightning {
-    pub fn new(api_key: String) -> Self {
-        Self {
-            client: StrikeClient::new(&api_key).expect("Can not create Strike client"),
-        }
+    pub fn new(api_key: String) -> Result<Self, LightningError> {
+        Ok(Self {
+            client: StrikeClient::new(&api_key)?,
+        })
     }
 }
 
 #[async_trait]
 impl Lightning for StrikeLightning {
-    async fn pay_invoice(
+    async fn pay_invoice_with_options(
         &self,
         payment_request: String,
+        options: PayOptions,
     ) -> Result<PayInvoiceResult, anyhow::Error> {
+        options.ensure_supported(
+            "strike",
+            &["max_fee_msat", "max_fee_ppm", "amount_msat_for_zero_amount"],
+        )?;
         // strike doesn't return the payment_hash so we have to read the invoice into a
         // Bolt11 and extract it
-        let invoice = decode_invoice(payment_request.clone());
+        let invoice = decode_invoice(&payment_request)?.invoice;
         let payment_hash = invoice.payment_hash().to_vec();
+        let fee_limit_msat = options.fee_limit_msat(&invoice);
+        let amount = whole_sats(options.amount_override_msat(&invoice), "strike")?;
 
-        let payment_quote_id = self
+        let quote = self
             .client
-            .create_ln_payment_quote(&invoice.into_signed_raw().to_string())
+            .create_ln_payment_quote(&invoice.into_signed_raw().to_string(), amount)
             .await?;
 
-        let payment_result = self
-            .client
-            .execute_ln_payment_quote(&payment_quote_id)
-            .await?;
+        // the quote fixes the fee, so the limit is checked before paying
+        if let Some(max_fee_msat) = fee_limit_msat {
+            let fee_msat = quote.fee_msat.ok_or_else(|| {
+                anyhow::anyhow!("Strike quote has no fee to check against the fee limit")
+            })?;
+            if fee_msat > max_fee_msat {
+                return Err(LightningError::FeeLimitExceeded {
+                    fee_msat,
+                    max_fee_msat,
+                }
+                .into());
+            }
+        }
 
-        if !payment_result {
-            return Err(anyhow::anyhow!(
-                "Failed to pay invoice: {}",
-                payment_request
-            ));
+        let payment_result = self.client.execute_ln_payment_quote(&quote.id).await?;
+        match payment_result.state.as_str() {
+            "COMPLETED" => {}
+            "FAILED" => return Err(LightningError::PaymentFailed.into()),
+            // the payment may still go through, so it must not be retried
+            _ => return Err(LightningError::PaymentPending(hex::encode(payment_hash)).into()),
         }
 
+        // strike never exposes the preimage of payments it makes
         Ok(PayInvoiceResult {
             payment_hash: hex::encode(payment_hash),
+            preimage: None,
+            fee_msat: payment_result.fee_msat,
+            amount_msat: payment_result.amount_msat,
+            settled_at: unix_timestamp(),
         })
     }
+
+    async fn create_invoice(
+        &self,
+        params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        Ok(self.client.create_invoice(&params).await?)
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error> {
+        Ok(self.client.invoice_status(payment_hash).await?)
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus, anyhow::Error> {
+        Ok(self.client.payment_status(payment_hash).await?)
+    }
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error> {
+        Ok(self.client.get_balance().await?)
+    }
 }
 
 fn format_as_uuid_string(bytes: &[u8]) -> String {
@@ -256,22 +657,25 @@ impl fmt::Display for LndLightningSettings {
         write!(
             f,
             "grpc_host: {}, tls_cert_path: {}, macaroon_path: {}",
-            self.grpc_host.as_ref().unwrap(),
-            self.tls_cert_path
-                .as_ref()
-                .unwrap() // FIXME unwrap
-                .to_str()
-                .unwrap_or_default(),
-            self.macaroon_path
-                .as_ref()
-                .unwrap()
-                .to_str()
-                .unwrap_or_default()
+            display_setting(&self.grpc_host),
+            display_path_setting(&self.tls_cert_path),
+            display_path_setting(&self.macaroon_path)
         )
     }
 }
 
-pub struct LndLightning(Arc<Mutex<Client>>);
+impl LndLightningSettings {
+    pub fn validate(&self) -> Result<(), LightningError> {
+        required(&self.grpc_host, "grpc_host")?;
+        required_file(&self.tls_cert_path, "tls_cert_path")?;
+        required_file(&self.macaroon_path, "macaroon_path")?;
+        Ok(())
+    }
+}
+
+pub struct LndLightning {
+    client: Arc<Mutex<Client>>,
+}
 
 impl LndLightning {
     pub async fn new(
@@ -279,40 +683,229 @@ impl LndLightning {
         cert_file: &PathBuf,
         macaroon_file: &PathBuf,
     ) -> Result<Self, anyhow::Error> {
-        let client = tonic_lnd::connect(address.to_string(), cert_file, &macaroon_file).await;
+        let client = tonic_lnd::connect(address.to_string(), cert_file, &macaroon_file).await?;
 
-        Ok(Self(Arc::new(Mutex::new(client.unwrap()))))
+        Ok(Self {
+            client: Arc::new(Mutex::new(client)),
+        })
     }
 
     pub async fn client_lock(
         &self,
     ) -> anyhow::Result<MappedMutexGuard<'_, tonic_lnd::LightningClient>> {
-        let guard = self.0.lock().await;
+        let guard = self.client.lock().await;
         Ok(MutexGuard::map(guard, |client| client.lightning()))
     }
+
+    async fn lookup_invoice(
+        &self,
+        payment_hash: &str,
+    ) -> Result<tonic_lnd::lnrpc::Invoice, anyhow::Error> {
+        let request = tonic_lnd::lnrpc::PaymentHash {
+            r_hash: hex::decode(payment_hash)?,
+            ..Default::default()
+        };
+        Ok(self
+            .client_lock()
+            .await?
+            .lookup_invoice(tonic_lnd::tonic::Request::new(request))
+            .await?
+            .into_inner())
+    }
+}
+
+fn lnd_invoice_event(invoice: &tonic_lnd::lnrpc::Invoice) -> InvoiceEvent {
+    // lnd cancels open invoices once they expire
+    let status = match invoice.state() {
+        InvoiceState::Settled => InvoiceStatus::Paid,
+        InvoiceState::Canceled => InvoiceStatus::Expired,
+        InvoiceState::Open | InvoiceState::Accepted => InvoiceStatus::Pending,
+    };
+    InvoiceEvent {
+        payment_hash: hex::encode(&invoice.r_hash),
+        status,
+        amount_received_msat: (status == InvoiceStatus::Paid)
+            .then_some(invoice.amt_paid_msat as u64),
+    }
 }
 
 #[async_trait]
 impl Lightning for LndLightning {
-    async fn pay_invoice(
+    async fn pay_invoice_with_options(
         &self,
         payment_request: String,
+        options: PayOptions,
     ) -> Result<PayInvoiceResult, anyhow::Error> {
+        // SendPaymentSync has no payment timeout, that needs the router rpc
+        options.ensure_supported(
+            "lnd",
+            &["max_fee_msat", "max_fee_ppm", "amount_msat_for_zero_amount"],
+        )?;
+        let invoice = decode_invoice(&payment_request)?.invoice;
         let pay_req = tonic_lnd::lnrpc::SendRequest {
             payment_request,
+            amt_msat: options
+                .amount_override_msat(&invoice)
+                .map(|amount_msat| amount_msat as i64)
+                .unwrap_or_default(),
+            fee_limit: options.fee_limit_msat(&invoice).map(|fee_limit_msat| {
+                tonic_lnd::lnrpc::FeeLimit {
+                    limit: Some(tonic_lnd::lnrpc::fee_limit::Limit::FixedMsat(
+                        fee_limit_msat as i64,
+                    )),
+                }
+            }),
             ..Default::default()
         };
         let payment = self
             .client_lock()
-            .await
-            .expect("failed to lock client") //FIXME map error
+            .await?
             .send_payment_sync(tonic_lnd::tonic::Request::new(pay_req))
             .await
-            .expect("failed to pay invoice")
+            .map_err(|status| LightningError::LndError(status.message().to_owned()))?
             .into_inner();
 
+        if !payment.payment_error.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Failed to pay invoice: {}",
+                payment.payment_error
+            ));
+        }
+
+        let route = payment.payment_route.unwrap_or_default();
+
         Ok(PayInvoiceResult {
             payment_hash: hex::encode(payment.payment_hash),
+            preimage: Some(hex::encode(payment.payment_preimage)),
+            fee_msat: Some(route.total_fees_msat as u64),
+            amount_msat: Some((route.total_amt_msat - route.total_fees_msat) as u64),
+            settled_at: unix_timestamp(),
+        })
+    }
+
+    async fn create_invoice(
+        &self,
+        params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        let invoice = tonic_lnd::lnrpc::Invoice {
+            value_msat: params.amount_msat()? as i64,
+            memo: params.memo.unwrap_or_default(),
+            expiry: params.expiry.map(i64::from).unwrap_or_default(),
+            ..Default::default()
+        };
+        let response = self
+            .client_lock()
+            .await?
+            .add_invoice(tonic_lnd::tonic::Request::new(invoice))
+            .await?
+            .into_inner();
+
+        Ok(CreateInvoiceResult {
+            payment_hash: response.r_hash,
+            payment_request: response.payment_request,
+        })
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error> {
+        let invoice = self.lookup_invoice(payment_hash).await?;
+        Ok(lnd_invoice_event(&invoice).status)
+    }
+
+    fn subscribe_invoice(&self, payment_hash: &str) -> InvoiceEventStream<'_> {
+        let payment_hash = payment_hash.to_owned();
+        let events = stream::once(async move {
+            // subscribe before the lookup so a settle in between isn't missed
+            let updates = self
+                .client_lock()
+                .await?
+                .subscribe_invoices(tonic_lnd::tonic::Request::new(
+                    tonic_lnd::lnrpc::InvoiceSubscription::default(),
+                ))
+                .await?
+                .into_inner();
+            let invoice = self.lookup_invoice(&payment_hash).await?;
+
+            let r_hash = invoice.r_hash.clone();
+            let updates = updates
+                .try_filter(move |update| future::ready(update.r_hash == r_hash))
+                .map_ok(|update| lnd_invoice_event(&update))
+                .map_err(anyhow::Error::from);
+            // settles are pushed but expiry isn't, so look again once it's due
+            let expires_in = (invoice.creation_date + invoice.expiry)
+                .saturating_sub(unix_timestamp() as i64)
+                .max(0) as u64;
+            let expiry = stream::once(async move {
+                tokio::time::sleep(Duration::from_secs(expires_in + 1)).await;
+                let invoice = self.lookup_invoice(&payment_hash).await?;
+                Ok(lnd_invoice_event(&invoice))
+            });
+
+            Ok::<_, anyhow::Error>(
+                stream::once(future::ready(Ok(lnd_invoice_event(&invoice))))
+                    .chain(stream::select(updates, expiry)),
+            )
+        })
+        .try_flatten();
+
+        until_settled(events)
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus, anyhow::Error> {
+        // ListPayments can't filter by hash, so page back from the newest
+        let mut index_offset = 0;
+        loop {
+            let request = tonic_lnd::lnrpc::ListPaymentsRequest {
+                include_incomplete: true,
+                index_offset,
+                max_payments: 100,
+                reversed: true,
+                ..Default::default()
+            };
+            let response = self
+                .client_lock()
+                .await?
+                .list_payments(tonic_lnd::tonic::Request::new(request))
+                .await?
+                .into_inner();
+            if response.payments.is_empty() {
+                return Err(LightningError::NotFound.into());
+            }
+
+            if let Some(payment) = response
+                .payments
+                .iter()
+                .find(|payment| payment.payment_hash == payment_hash)
+            {
+                return Ok(match payment.status() {
+                    PaymentState::Succeeded => PaymentStatus::Succeeded {
+                        preimage: Some(payment.payment_preimage.clone()),
+                        fee_msat: Some(payment.fee_msat as u64),
+                    },
+                    PaymentState::Failed => PaymentStatus::Failed {
+                        reason: format!("{:?}", payment.failure_reason()),
+                    },
+                    PaymentState::Unknown | PaymentState::InFlight => PaymentStatus::Pending,
+                });
+            }
+            index_offset = response.first_index_offset;
+        }
+    }
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error> {
+        let response = self
+            .client_lock()
+            .await?
+            .channel_balance(tonic_lnd::tonic::Request::new(
+                tonic_lnd::lnrpc::ChannelBalanceRequest {},
+            ))
+            .await?
+            .into_inner();
+
+        Ok(BalanceResult {
+            balance_msat: response
+                .local_balance
+                .map(|amount| amount.msat)
+                .unwrap_or(0),
         })
     }
 }
@@ -323,10 +916,546 @@ pub struct ClnLightningSettings {
 }
 impl fmt::Display for ClnLightningSettings {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "rpc_path: {}",
-            self.rpc_path.as_ref().unwrap().to_str().unwrap_or_default()
-        )
+        write!(f, "rpc_path: {}", display_path_setting(&self.rpc_path))
+    }
+}
+
+impl ClnLightningSettings {
+    pub fn validate(&self) -> Result<(), LightningError> {
+        let rpc_path = required(&self.rpc_path, "rpc_path")?;
+        if !rpc_path.exists() {
+            return Err(LightningError::InvalidSetting(
+                "rpc_path",
+                format!("{} does not exist", rpc_path.display()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub struct ClnLightning {
+    client: Arc<Mutex<ClnRpc>>,
+    rpc_path: PathBuf,
+}
+
+impl ClnLightning {
+    pub async fn new(rpc_path: &PathBuf) -> Result<Self, anyhow::Error> {
+        let client = ClnRpc::new(rpc_path).await?;
+
+        Ok(Self {
+            client: Arc::new(Mutex::new(client)),
+            rpc_path: rpc_path.clone(),
+        })
+    }
+
+    async fn call(&self, request: cln_rpc::Request) -> Result<cln_rpc::Response, anyhow::Error> {
+        self.client
+            .lock()
+            .await
+            .call(request)
+            .await
+            .map_err(|err| anyhow::anyhow!("CLN rpc error: {}", err))
+    }
+
+    async fn find_invoice(
+        &self,
+        payment_hash: &str,
+    ) -> Result<responses::ListinvoicesInvoices, anyhow::Error> {
+        let request = requests::ListinvoicesRequest {
+            label: None,
+            invstring: None,
+            payment_hash: Some(payment_hash.to_owned()),
+            offer_id: None,
+            index: None,
+            start: None,
+            limit: None,
+        };
+
+        let invoices = match self.call(cln_rpc::Request::ListInvoices(request)).await? {
+            cln_rpc::Response::ListInvoices(response) => response.invoices,
+            _ => return Err(anyhow::anyhow!("Unexpected response from CLN")),
+        };
+        Ok(invoices
+            .into_iter()
+            .next()
+            .ok_or(LightningError::NotFound)?)
+    }
+
+    async fn wait_invoice(
+        &self,
+        label: String,
+        payment_hash: String,
+    ) -> Result<InvoiceEvent, anyhow::Error> {
+        // waitinvoice holds the connection until the invoice settles, so it
+        // gets one of its own
+        let mut rpc = ClnRpc::new(&self.rpc_path).await?;
+        let request = requests::WaitinvoiceRequest { label };
+        match rpc.call(cln_rpc::Request::WaitInvoice(request)).await {
+            Ok(cln_rpc::Response::WaitInvoice(response)) => Ok(InvoiceEvent {
+                payment_hash,
+                status: match response.status {
+                    responses::WaitinvoiceStatus::PAID => InvoiceStatus::Paid,
+                    responses::WaitinvoiceStatus::EXPIRED => InvoiceStatus::Expired,
+                },
+                amount_received_msat: response.amount_received_msat.map(|amount| amount.msat()),
+            }),
+            Ok(_) => Err(anyhow::anyhow!("Unexpected response from CLN")),
+            // waitinvoice errors once the invoice expires
+            Err(err) => {
+                let event = cln_invoice_event(&self.find_invoice(&payment_hash).await?);
+                if event.status == InvoiceStatus::Pending {
+                    return Err(anyhow::anyhow!("CLN rpc error: {}", err));
+                }
+                Ok(event)
+            }
+        }
+    }
+}
+
+fn cln_invoice_event(invoice: &responses::ListinvoicesInvoices) -> InvoiceEvent {
+    InvoiceEvent {
+        payment_hash: invoice.payment_hash.to_string(),
+        status: match invoice.status {
+            responses::ListinvoicesInvoicesStatus::UNPAID => InvoiceStatus::Pending,
+            responses::ListinvoicesInvoicesStatus::PAID => InvoiceStatus::Paid,
+            responses::ListinvoicesInvoicesStatus::EXPIRED => InvoiceStatus::Expired,
+        },
+        amount_received_msat: invoice.amount_received_msat.map(|amount| amount.msat()),
+    }
+}
+
+#[async_trait]
+impl Lightning for ClnLightning {
+    async fn pay_invoice_with_options(
+        &self,
+        payment_request: String,
+        options: PayOptions,
+    ) -> Result<PayInvoiceResult, anyhow::Error> {
+        let invoice = decode_invoice(&payment_request)?.invoice;
+        let request = requests::PayRequest {
+            bolt11: payment_request.clone(),
+            amount_msat: options
+                .amount_override_msat(&invoice)
+                .map(Amount::from_msat),
+            label: None,
+            riskfactor: None,
+            maxfeepercent: None,
+            retry_for: options
+                .timeout
+                .map(|timeout| timeout.as_secs().try_into().unwrap_or(u16::MAX)),
+            maxdelay: None,
+            exemptfee: None,
+            localinvreqid: None,
+            exclude: None,
+            maxfee: options.fee_limit_msat(&invoice).map(Amount::from_msat),
+            description: None,
+        };
+
+        let payment = match self.call(cln_rpc::Request::Pay(request)).await? {
+            cln_rpc::Response::Pay(payment) => payment,
+            _ => return Err(anyhow::anyhow!("Unexpected response from CLN")),
+        };
+
+        match payment.status {
+            responses::PayStatus::COMPLETE => {}
+            responses::PayStatus::FAILED => return Err(LightningError::PaymentFailed.into()),
+            // the payment may still go through, so it must not be retried
+            responses::PayStatus::PENDING => {
+                return Err(LightningError::PaymentPending(payment.payment_hash.to_string()).into())
+            }
+        }
+
+        Ok(PayInvoiceResult {
+            payment_hash: payment.payment_hash.to_string(),
+            preimage: Some(hex::encode(payment.payment_preimage.to_vec())),
+            fee_msat: Some(payment.amount_sent_msat.msat() - payment.amount_msat.msat()),
+            amount_msat: Some(payment.amount_msat.msat()),
+            settled_at: unix_timestamp(),
+        })
+    }
+
+    async fn create_invoice(
+        &self,
+        params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        // CLN requires a unique label for every invoice
+        let label = format_as_uuid_string(&secp256k1::rand::random::<[u8; 16]>());
+        let request = requests::InvoiceRequest {
+            amount_msat: AmountOrAny::Amount(Amount::from_msat(params.amount_msat()?)),
+            description: params.memo.unwrap_or_default(),
+            label,
+            expiry: params.expiry.map(u64::from),
+            fallbacks: None,
+            preimage: None,
+            cltv: None,
+            deschashonly: None,
+        };
+
+        match self.call(cln_rpc::Request::Invoice(request)).await? {
+            cln_rpc::Response::Invoice(invoice) => Ok(CreateInvoiceResult {
+                payment_hash: hex::decode(invoice.payment_hash.to_string())?,
+                payment_request: invoice.bolt11,
+            }),
+            _ => Err(anyhow::anyhow!("Unexpected response from CLN")),
+        }
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error> {
+        let invoice = self.find_invoice(payment_hash).await?;
+        Ok(cln_invoice_event(&invoice).status)
+    }
+
+    fn subscribe_invoice(&self, payment_hash: &str) -> InvoiceEventStream<'_> {
+        let payment_hash = payment_hash.to_owned();
+        let events = stream::once(async move {
+            let invoice = self.find_invoice(&payment_hash).await?;
+            let current = cln_invoice_event(&invoice);
+            let settled = stream::once(self.wait_invoice(invoice.label, payment_hash));
+
+            Ok::<_, anyhow::Error>(stream::once(future::ready(Ok(current))).chain(settled))
+        })
+        .try_flatten();
+
+        until_settled(events)
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus, anyhow::Error> {
+        let request = requests::ListpaysRequest {
+            bolt11: None,
+            payment_hash: Some(payment_hash.parse()?),
+            status: None,
+        };
+
+        let pays = match self.call(cln_rpc::Request::ListPays(request)).await? {
+            cln_rpc::Response::ListPays(response) => response.pays,
+            _ => return Err(anyhow::anyhow!("Unexpected response from CLN")),
+        };
+
+        // a hash can have several attempts, any complete one means we paid
+        if let Some(pay) = pays
+            .iter()
+            .find(|pay| pay.status == responses::ListpaysPaysStatus::COMPLETE)
+        {
+            let fee_msat = match (pay.amount_sent_msat, pay.amount_msat) {
+                (Some(sent), Some(amount)) => Some(sent.msat() - amount.msat()),
+                _ => None,
+            };
+            return Ok(PaymentStatus::Succeeded {
+                preimage: pay.preimage.map(|preimage| hex::encode(preimage.to_vec())),
+                fee_msat,
+            });
+        }
+        if pays
+            .iter()
+            .any(|pay| pay.status == responses::ListpaysPaysStatus::PENDING)
+        {
+            return Ok(PaymentStatus::Pending);
+        }
+
+        match pays.last() {
+            Some(pay) => Ok(PaymentStatus::Failed {
+                reason: pay
+                    .erroronion
+                    .clone()
+                    .unwrap_or_else(|| "CLN reports the payment as failed".to_owned()),
+            }),
+            None => Err(LightningError::NotFound.into()),
+        }
+    }
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error> {
+        let request = requests::ListfundsRequest { spent: None };
+
+        let channels = match self.call(cln_rpc::Request::ListFunds(request)).await? {
+            cln_rpc::Response::ListFunds(response) => response.channels,
+            _ => return Err(anyhow::anyhow!("Unexpected response from CLN")),
+        };
+
+        Ok(BalanceResult {
+            balance_msat: channels
+                .iter()
+                .filter(|channel| channel.state == ChannelState::CHANNELD_NORMAL)
+                .map(|channel| channel.our_amount_msat.msat())
+                .sum(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use serde_json::{json, Value};
+
+    use super::testing::{self, serve_cln};
+    use super::*;
+
+    type Calls = Arc<StdMutex<Vec<(String, Value)>>>;
+
+    #[tokio::test]
+    async fn cln_pays_invoice() {
+        let invoice = testing::invoice(Some(10_000));
+        let calls = Calls::default();
+        let (_dir, rpc_path) = serve_cln({
+            let calls = calls.clone();
+            let invoice = invoice.clone();
+            move |method, params| {
+                calls.lock().unwrap().push((method.to_owned(), params));
+                Ok(json!({
+                    "payment_preimage": invoice.preimage,
+                    "payment_hash": invoice.payment_hash,
+                    "created_at": 1700000000.0,
+                    "parts": 1,
+                    "amount_msat": 10_000,
+                    "amount_sent_msat": 10_012,
+                    "status": "complete",
+                }))
+            }
+        });
+        let lightning = ClnLightning::new(&rpc_path).await.unwrap();
+
+        let result = lightning
+            .pay_invoice_with_options(
+                invoice.payment_request.clone(),
+                PayOptions::new().with_max_fee_msat(50),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.payment_hash, invoice.payment_hash);
+        assert_eq!(result.preimage, Some(invoice.preimage));
+        assert_eq!(result.fee_msat, Some(12));
+        assert_eq!(result.amount_msat, Some(10_000));
+
+        let calls = calls.lock().unwrap();
+        let (method, params) = &calls[0];
+        assert_eq!(method, "pay");
+        assert_eq!(params["bolt11"], invoice.payment_request);
+        assert_eq!(params["maxfee"], "50msat");
+        // the amount is only sent along for zero amount invoices
+        assert!(params.get("amount_msat").is_none());
+    }
+
+    #[tokio::test]
+    async fn cln_pay_errors() {
+        let invoice = testing::invoice(Some(10_000));
+        let (_dir, rpc_path) = serve_cln({
+            let invoice = invoice.clone();
+            move |_, params| match params["bolt11"].as_str() {
+                Some(bolt11) if bolt11 == invoice.payment_request => {
+                    Err("Ran out of routes to try".to_owned())
+                }
+                _ => Ok(json!({
+                    "payment_preimage": invoice.preimage,
+                    "payment_hash": invoice.payment_hash,
+                    "created_at": 1700000000.0,
+                    "parts": 1,
+                    "amount_msat": 10_000,
+                    "amount_sent_msat": 10_000,
+                    "status": "failed",
+                })),
+            }
+        });
+        let lightning = ClnLightning::new(&rpc_path).await.unwrap();
+
+        let err = lightning
+            .pay_invoice(invoice.payment_request)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Ran out of routes"), "{err}");
+        let err = lightning
+            .pay_invoice(testing::invoice(Some(10_000)).payment_request)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(LightningError::PaymentFailed)),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn cln_creates_invoice() {
+        let invoice = testing::invoice(Some(21_000));
+        let calls = Calls::default();
+        let (_dir, rpc_path) = serve_cln({
+            let calls = calls.clone();
+            let invoice = invoice.clone();
+            move |method, params| {
+                calls.lock().unwrap().push((method.to_owned(), params));
+                Ok(json!({
+                    "bolt11": invoice.payment_request,
+                    "payment_hash": invoice.payment_hash,
+                    "payment_secret": "07".repeat(32),
+                    "expires_at": 1700003600,
+                }))
+            }
+        });
+        let lightning = ClnLightning::new(&rpc_path).await.unwrap();
+
+        let result = lightning
+            .create_invoice(CreateInvoiceParams {
+                amount: 21,
+                unit: "sat".to_owned(),
+                memo: Some("coffee".to_owned()),
+                expiry: Some(600),
+                webhook: None,
+                internal: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(result.payment_request, invoice.payment_request);
+        assert_eq!(hex::encode(result.payment_hash), invoice.payment_hash);
+
+        {
+            let recorded = calls.lock().unwrap();
+            let (method, params) = &recorded[0];
+            assert_eq!(method, "invoice");
+            assert_eq!(params["amount_msat"], "21000msat");
+            assert_eq!(params["description"], "coffee");
+            assert_eq!(params["expiry"], 600);
+            assert!(params["label"]
+                .as_str()
+                .is_some_and(|label| !label.is_empty()));
+        }
+
+        lightning
+            .create_invoice(CreateInvoiceParams {
+                amount: 21_500,
+                unit: "msat".to_owned(),
+                memo: None,
+                expiry: None,
+                webhook: None,
+                internal: None,
+            })
+            .await
+            .unwrap();
+        let err = lightning
+            .create_invoice(CreateInvoiceParams {
+                amount: 21,
+                unit: "usd".to_owned(),
+                memo: None,
+                expiry: None,
+                webhook: None,
+                internal: None,
+            })
+            .await
+            .unwrap_err();
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls[1].1["amount_msat"], "21500msat");
+        assert_eq!(calls.len(), 2);
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(LightningError::InvalidAmount(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn cln_looks_up_invoices_and_payments() {
+        let paid = testing::invoice(Some(10_000));
+        let (_dir, rpc_path) = serve_cln({
+            let paid = paid.clone();
+            move |method, params| {
+                let known = params["payment_hash"] == paid.payment_hash;
+                match method {
+                    "listinvoices" if known => Ok(json!({"invoices": [{
+                        "label": "a",
+                        "payment_hash": paid.payment_hash,
+                        "status": "paid",
+                        "expires_at": 1700003600,
+                        "amount_received_msat": 10_000,
+                    }]})),
+                    "listinvoices" => Ok(json!({"invoices": []})),
+                    // a failed attempt followed by one that went through
+                    "listpays" if known => Ok(json!({"pays": [
+                        {
+                            "payment_hash": paid.payment_hash,
+                            "status": "failed",
+                            "created_at": 1700000000,
+                        },
+                        {
+                            "payment_hash": paid.payment_hash,
+                            "status": "complete",
+                            "created_at": 1700000010,
+                            "amount_msat": 10_000,
+                            "amount_sent_msat": 10_003,
+                            "preimage": paid.preimage,
+                        },
+                    ]})),
+                    "listpays" => Ok(json!({"pays": []})),
+                    _ => Err(format!("unexpected {method}")),
+                }
+            }
+        });
+        let lightning = ClnLightning::new(&rpc_path).await.unwrap();
+        let unknown = testing::invoice(Some(10_000));
+
+        assert_eq!(
+            lightning.invoice_status(&paid.payment_hash).await.unwrap(),
+            InvoiceStatus::Paid
+        );
+        let err = lightning
+            .invoice_status(&unknown.payment_hash)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(LightningError::NotFound)));
+
+        assert_eq!(
+            lightning.payment_status(&paid.payment_hash).await.unwrap(),
+            PaymentStatus::Succeeded {
+                preimage: Some(paid.preimage.clone()),
+                fee_msat: Some(3),
+            }
+        );
+        let err = lightning
+            .payment_status(&unknown.payment_hash)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(LightningError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn strike_pay_reports_pending_payments() {
+        let pending = testing::invoice(Some(10_000));
+        let url = testing::serve({
+            let pending = pending.clone();
+            move |request| match request.path.as_str() {
+                "/v1/payment-quotes/lightning" => {
+                    let quote_id = if request.json()["lnInvoice"] == pending.payment_request {
+                        "pending"
+                    } else {
+                        "failed"
+                    };
+                    testing::respond(201, json!({ "paymentQuoteId": quote_id }).to_string())
+                }
+                "/v1/payment-quotes/pending/execute" => {
+                    testing::respond(200, r#"{"state":"PENDING"}"#)
+                }
+                _ => testing::respond(200, r#"{"state":"FAILED"}"#),
+            }
+        })
+        .await;
+        let lightning = StrikeLightning {
+            client: StrikeClient::with_url("key", url).unwrap(),
+        };
+
+        let err = lightning
+            .pay_invoice(pending.payment_request)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref(),
+                Some(LightningError::PaymentPending(payment_hash)) if *payment_hash == pending.payment_hash
+            ),
+            "{err}"
+        );
+        let err = lightning
+            .pay_invoice(testing::invoice(Some(10_000)).payment_request)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(LightningError::PaymentFailed)),
+            "{err}"
+        );
     }
 }
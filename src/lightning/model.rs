@@ -1,5 +1,11 @@
+use std::time::Duration;
+
+use lightning_invoice::Bolt11Invoice;
 use serde::{Deserialize, Serialize};
 
+use super::error::LightningError;
+use super::utils::whole_sats;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateInvoiceParams {
     pub amount: u64,
@@ -10,13 +16,189 @@ pub struct CreateInvoiceParams {
     pub internal: Option<bool>,
 }
 
+impl CreateInvoiceParams {
+    /// The amount in msat, `unit` being "sat" or "msat"
+    pub fn amount_msat(&self) -> Result<u64, LightningError> {
+        match self.unit.as_str() {
+            "sat" => self.amount.checked_mul(1000).ok_or_else(|| {
+                LightningError::InvalidAmount(format!("{} sat is too large", self.amount))
+            }),
+            "msat" => Ok(self.amount),
+            unit => Err(LightningError::InvalidAmount(format!(
+                "unsupported unit {unit}"
+            ))),
+        }
+    }
+
+    /// The amount in sats for backends that only take whole sats
+    pub fn amount_sats(&self, backend: &str) -> Result<u64, LightningError> {
+        Ok(whole_sats(Some(self.amount_msat()?), backend)?.unwrap_or_default())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateInvoiceResult {
     pub payment_hash: Vec<u8>,
     pub payment_request: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PayInvoiceResult {
     pub payment_hash: String,
+    pub preimage: Option<String>,
+    pub fee_msat: Option<u64>,
+    pub amount_msat: Option<u64>,
+    /// Unix timestamp (seconds) at which the payment settled
+    pub settled_at: u64,
+}
+
+impl PayInvoiceResult {
+    /// Returns the preimage, or an error for backends that don't expose it
+    pub fn require_preimage(&self) -> Result<&str, LightningError> {
+        self.preimage
+            .as_deref()
+            .ok_or(LightningError::PreimageUnavailable)
+    }
+}
+
+/// Limits on a single payment. Backends return
+/// `LightningError::UnsupportedPayOption` for options they can't honor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PayOptions {
+    pub max_fee_msat: Option<u64>,
+    /// Fee limit in parts per million of the amount paid
+    pub max_fee_ppm: Option<u64>,
+    /// How long to keep retrying routes before giving up
+    pub timeout: Option<Duration>,
+    /// The amount to pay if the invoice doesn't specify one
+    pub amount_msat_for_zero_amount: Option<u64>,
+}
+
+impl PayOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_fee_msat(mut self, max_fee_msat: u64) -> Self {
+        self.max_fee_msat = Some(max_fee_msat);
+        self
+    }
+
+    pub fn with_max_fee_ppm(mut self, max_fee_ppm: u64) -> Self {
+        self.max_fee_ppm = Some(max_fee_ppm);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_amount_msat_for_zero_amount(mut self, amount_msat: u64) -> Self {
+        self.amount_msat_for_zero_amount = Some(amount_msat);
+        self
+    }
+
+    /// The amount to send along with the invoice, only set for zero amount
+    /// invoices
+    pub fn amount_override_msat(&self, invoice: &Bolt11Invoice) -> Option<u64> {
+        match invoice.amount_milli_satoshis() {
+            Some(_) => None,
+            None => self.amount_msat_for_zero_amount,
+        }
+    }
+
+    pub fn amount_msat(&self, invoice: &Bolt11Invoice) -> Option<u64> {
+        invoice
+            .amount_milli_satoshis()
+            .or(self.amount_msat_for_zero_amount)
+    }
+
+    /// The tighter of the two fee limits for paying the invoice
+    pub fn fee_limit_msat(&self, invoice: &Bolt11Invoice) -> Option<u64> {
+        let ppm_limit = self.max_fee_ppm.and_then(|ppm| {
+            self.amount_msat(invoice)
+                .map(|amount_msat| amount_msat * ppm / 1_000_000)
+        });
+        match (self.max_fee_msat, ppm_limit) {
+            (Some(max_fee_msat), Some(ppm_limit)) => Some(max_fee_msat.min(ppm_limit)),
+            (max_fee_msat, ppm_limit) => max_fee_msat.or(ppm_limit),
+        }
+    }
+
+    /// Errors on the first option that is set but not in `supported`
+    pub fn ensure_supported(
+        &self,
+        backend: &'static str,
+        supported: &[&'static str],
+    ) -> Result<(), LightningError> {
+        let set = [
+            ("max_fee_msat", self.max_fee_msat.is_some()),
+            ("max_fee_ppm", self.max_fee_ppm.is_some()),
+            ("timeout", self.timeout.is_some()),
+            (
+                "amount_msat_for_zero_amount",
+                self.amount_msat_for_zero_amount.is_some(),
+            ),
+        ];
+        match set
+            .into_iter()
+            .find(|(option, is_set)| *is_set && !supported.contains(option))
+        {
+            Some((option, _)) => Err(LightningError::UnsupportedPayOption { backend, option }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BalanceResult {
+    pub balance_msat: u64,
+}
+
+/// Status of a payment we made, looked up by its payment hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PaymentStatus {
+    /// Still in flight, it may yet succeed or fail
+    Pending,
+    Succeeded {
+        preimage: Option<String>,
+        fee_msat: Option<u64>,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+impl PaymentStatus {
+    /// Whether the status can no longer change
+    pub fn is_final(&self) -> bool {
+        !matches!(self, PaymentStatus::Pending)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InvoiceStatus {
+    Pending,
+    Paid,
+    Expired,
+}
+
+/// A change in the status of an invoice we created
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InvoiceEvent {
+    pub payment_hash: String,
+    pub status: InvoiceStatus,
+    /// Set once paid, if the backend reports it
+    pub amount_received_msat: Option<u64>,
+}
+
+impl InvoiceEvent {
+    pub fn new(payment_hash: &str, status: InvoiceStatus) -> Self {
+        Self {
+            payment_hash: payment_hash.to_owned(),
+            status,
+            amount_received_msat: None,
+        }
+    }
 }
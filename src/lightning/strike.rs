@@ -1,9 +1,12 @@
 use reqwest::header::{HeaderValue, CONTENT_TYPE};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use url::Url;
 
 use super::error::LightningError;
-use super::model::CreateInvoiceParams;
+use super::model::{
+    BalanceResult, CreateInvoiceParams, CreateInvoiceResult, InvoiceStatus, PaymentStatus,
+};
+use super::utils::decode_invoice;
 
 #[derive(Clone)]
 pub struct StrikeClient {
@@ -14,8 +17,10 @@ pub struct StrikeClient {
 
 impl StrikeClient {
     pub fn new(api_key: &str) -> Result<StrikeClient, LightningError> {
-        let strike_url = Url::parse("https://api.strike.me")?;
+        Self::with_url(api_key, Url::parse("https://api.strike.me")?)
+    }
 
+    pub fn with_url(api_key: &str, strike_url: Url) -> Result<StrikeClient, LightningError> {
         let reqwest_client = reqwest::Client::builder().build()?;
 
         Ok(StrikeClient {
@@ -94,70 +99,128 @@ impl StrikeClient {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct QuoteRequest {
-    #[serde(rename = "descriptionHash")]
-    pub description_hash: String,
+#[derive(Debug)]
+pub struct LnPaymentQuote {
+    pub id: String,
+    pub fee_msat: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct LnPaymentExecution {
+    /// `PENDING`, `COMPLETED` or `FAILED`
+    pub state: String,
+    pub amount_msat: Option<u64>,
+    pub fee_msat: Option<u64>,
+}
+
+// strike amounts are `{ "amount": "0.00001", "currency": "BTC" }` objects
+fn btc_amount_to_msat(amount: &serde_json::Value) -> Option<u64> {
+    if amount["currency"].as_str() != Some("BTC") {
+        return None;
+    }
+
+    amount["amount"]
+        .as_str()
+        .and_then(|amount| amount.parse::<f64>().ok())
+        .map(|btc| (btc * 100_000_000_000.0).round() as u64)
+}
+
+fn sats_to_btc_amount(amount_sats: u64) -> String {
+    format!(
+        "{}.{:08}",
+        amount_sats / 100_000_000,
+        amount_sats % 100_000_000
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct StrikeReceiveRequest {
+    bolt11: StrikeBolt11,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StrikeBolt11 {
+    invoice: String,
+    payment_hash: String,
+}
+
+// strike looks everything up by its own ids, so invoices and payments are found
+// by listing them filtered on the payment hash. The hash is checked again on
+// our side in case the filter is ignored.
+impl StrikeClient {
+    async fn find_by_payment_hash(
+        &self,
+        endpoint: &str,
+        hash_field: &str,
+        payment_hash: &str,
+    ) -> Result<Option<serde_json::Value>, LightningError> {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("$filter", &format!("{hash_field} eq '{payment_hash}'"))
+            .append_pair("$top", "50")
+            .finish();
+        let body = self.make_get(&format!("{endpoint}?{query}")).await?;
+        let response: serde_json::Value = serde_json::from_str(&body)?;
+
+        let pointer = format!("/{hash_field}");
+        Ok(response["items"].as_array().and_then(|items| {
+            items
+                .iter()
+                .find(|item| {
+                    item.pointer(&pointer)
+                        .and_then(|hash| hash.as_str())
+                        .is_some_and(|hash| hash.eq_ignore_ascii_case(payment_hash))
+                })
+                .cloned()
+        }))
+    }
 }
 
-// strike has a 2 step process for getting a lightning invoice
-// 1. create an "invoice" which on their platform means a currency agnostic
-//    payment request
-// 2. generate a "quote" for the invoice which is a specific quoted conversion
-//    rate and a lightning invoice
 impl StrikeClient {
-    // this is not a lightning invoice, it's the strike internal representation of
-    // an invoice
-    pub async fn create_strike_invoice(
+    pub async fn create_invoice(
         &self,
         params: &CreateInvoiceParams,
-    ) -> Result<String, LightningError> {
-        let btc = (params.amount as f64) / 100_000_000.0;
-        let params = serde_json::json!({
+    ) -> Result<CreateInvoiceResult, LightningError> {
+        let mut bolt11 = serde_json::json!({
             "amount": {
-                "amount": btc,
-                "currency": "BTC"
+                "amount": sats_to_btc_amount(params.amount_sats("strike")?),
+                "currency": "BTC",
             },
             "description": params.memo,
         });
+        if let Some(expiry) = params.expiry {
+            bolt11["expiryInSeconds"] = expiry.into();
+        }
         let body = self
-            .make_post("v1/invoices", &serde_json::to_string(&params)?)
-            .await?;
-
-        let response: serde_json::Value = serde_json::from_str(&body)?;
-        let invoice_id = response["invoiceId"]
-            .as_str()
-            .expect("invoiceId is empty")
-            .to_owned();
-
-        Ok(invoice_id)
-    }
-
-    // this is how you get the actual lightning invoice
-    pub async fn create_strike_quote(&self, invoice_id: &str) -> Result<String, LightningError> {
-        let endpoint = format!("v1/invoices/{}/quote", invoice_id);
-        let description_hash = format!(
-            "{:0>64}",
-            hex::encode(hex::decode(invoice_id.replace('-', "").as_bytes()).unwrap())
-        );
-        let params = QuoteRequest { description_hash };
-        let body = self
-            .make_post(&endpoint, &serde_json::to_string(&params)?)
+            .make_post(
+                "v1/receive-requests",
+                &serde_json::to_string(&serde_json::json!({ "bolt11": bolt11 }))?,
+            )
             .await?;
-        let response: serde_json::Value = serde_json::from_str(&body)?;
-        let payment_request = response["lnInvoice"]
-            .as_str()
-            .expect("lnInvoice is empty")
-            .to_owned();
+        let response: StrikeReceiveRequest = serde_json::from_str(&body)?;
 
-        Ok(payment_request)
+        Ok(CreateInvoiceResult {
+            payment_hash: hex::decode(response.bolt11.payment_hash)?,
+            payment_request: response.bolt11.invoice,
+        })
     }
 
-    pub async fn create_ln_payment_quote(&self, bolt11: &str) -> Result<String, LightningError> {
-        let params = serde_json::json!({
+    /// `amount` in sats is only needed for zero amount invoices
+    pub async fn create_ln_payment_quote(
+        &self,
+        bolt11: &str,
+        amount: Option<u64>,
+    ) -> Result<LnPaymentQuote, LightningError> {
+        let mut params = serde_json::json!({
             "lnInvoice": bolt11,
             "sourceCurrency": "BTC",
         });
+        if let Some(amount) = amount {
+            params["amount"] = serde_json::json!({
+                "amount": sats_to_btc_amount(amount),
+                "currency": "BTC",
+            });
+        }
         let body = self
             .make_post(
                 "v1/payment-quotes/lightning",
@@ -170,23 +233,263 @@ impl StrikeClient {
             .expect("paymentQuoteId is empty")
             .to_owned();
 
-        Ok(payment_quote_id)
+        Ok(LnPaymentQuote {
+            id: payment_quote_id,
+            fee_msat: btc_amount_to_msat(&response["lightningNetworkFee"]),
+        })
     }
 
-    pub async fn execute_ln_payment_quote(&self, quote_id: &str) -> Result<bool, LightningError> {
+    pub async fn execute_ln_payment_quote(
+        &self,
+        quote_id: &str,
+    ) -> Result<LnPaymentExecution, LightningError> {
         let endpoint = format!("v1/payment-quotes/{}/execute", quote_id);
         let body = self
             .make_patch(&endpoint, &serde_json::to_string(&serde_json::json!({}))?)
             .await?;
         let response: serde_json::Value = serde_json::from_str(&body)?;
 
-        Ok(response["state"].as_str().unwrap_or("") == "COMPLETED")
+        Ok(LnPaymentExecution {
+            state: response["state"].as_str().unwrap_or_default().to_owned(),
+            amount_msat: btc_amount_to_msat(&response["amount"]),
+            fee_msat: btc_amount_to_msat(&response["lightningNetworkFee"]),
+        })
+    }
+
+    pub async fn payment_status(
+        &self,
+        payment_hash: &str,
+    ) -> Result<PaymentStatus, LightningError> {
+        let payment = self
+            .find_by_payment_hash("v1/payments", "lightning/paymentHash", payment_hash)
+            .await?
+            .ok_or(LightningError::NotFound)?;
+
+        // strike never exposes the preimage of payments it makes
+        Ok(match payment["state"].as_str().unwrap_or("") {
+            "COMPLETED" => PaymentStatus::Succeeded {
+                preimage: None,
+                fee_msat: btc_amount_to_msat(&payment["lightningNetworkFee"]),
+            },
+            "FAILED" => PaymentStatus::Failed {
+                reason: "Strike reports the payment as failed".to_owned(),
+            },
+            _ => PaymentStatus::Pending,
+        })
+    }
+
+    pub async fn invoice_status(
+        &self,
+        payment_hash: &str,
+    ) -> Result<InvoiceStatus, LightningError> {
+        let request = self
+            .find_by_payment_hash("v1/receive-requests", "bolt11/paymentHash", payment_hash)
+            .await?
+            .ok_or(LightningError::NotFound)?;
+        let request_id = request["receiveRequestId"].as_str().unwrap_or_default();
+
+        let body = self
+            .make_get(&format!(
+                "v1/receive-requests/receives?receiveRequestId={request_id}"
+            ))
+            .await?;
+        let receives: serde_json::Value = serde_json::from_str(&body)?;
+        let paid = receives["items"].as_array().is_some_and(|receives| {
+            receives
+                .iter()
+                .any(|receive| receive["state"].as_str() == Some("COMPLETED"))
+        });
+        if paid {
+            return Ok(InvoiceStatus::Paid);
+        }
+
+        let expired = request["bolt11"]["invoice"]
+            .as_str()
+            .and_then(|invoice| decode_invoice(invoice).ok())
+            .is_some_and(|invoice| invoice.is_expired());
+        Ok(if expired {
+            InvoiceStatus::Expired
+        } else {
+            InvoiceStatus::Pending
+        })
     }
 
-    pub async fn is_invoice_paid(&self, invoice_id: &str) -> Result<bool, LightningError> {
-        let body = self.make_get(&format!("v1/invoices/{invoice_id}")).await?;
+    pub async fn get_balance(&self) -> Result<BalanceResult, LightningError> {
+        let body = self.make_get("v1/balances").await?;
         let response = serde_json::from_str::<serde_json::Value>(&body)?;
 
-        Ok(response["state"].as_str().unwrap_or("") == "PAID")
+        // strike returns one entry per currency with the amount as a decimal string
+        let balance_msat = response
+            .as_array()
+            .and_then(|balances| {
+                balances
+                    .iter()
+                    .find(|balance| balance["currency"].as_str() == Some("BTC"))
+            })
+            .and_then(|balance| {
+                btc_amount_to_msat(&serde_json::json!({
+                    "amount": balance["available"],
+                    "currency": "BTC",
+                }))
+            })
+            .unwrap_or(0);
+
+        Ok(BalanceResult { balance_msat })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::lightning::testing::{self, build_invoice, respond, serve, InvoiceSpec};
+
+    fn query(path: &str) -> String {
+        url::form_urlencoded::parse(path.split_once('?').unwrap_or_default().1.as_bytes())
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    #[tokio::test]
+    async fn payment_status_looks_payments_up_by_hash() {
+        let paid = testing::invoice(Some(10_000));
+        let url = serve({
+            let paid = paid.clone();
+            move |request| {
+                let query = query(&request.path);
+                assert!(
+                    request.path.starts_with("/v1/payments?"),
+                    "{}",
+                    request.path
+                );
+                if !query.contains(&paid.payment_hash) {
+                    return respond(200, r#"{"items":[],"count":0}"#);
+                }
+                // another payment, as if the filter was ignored
+                let payments = json!({"items": [
+                    {
+                        "state": "FAILED",
+                        "lightning": {"paymentHash": "00".repeat(32)},
+                    },
+                    {
+                        "state": "COMPLETED",
+                        "lightning": {"paymentHash": paid.payment_hash},
+                        "lightningNetworkFee": {"amount": "0.00000003", "currency": "BTC"},
+                    },
+                ]});
+                respond(200, payments.to_string())
+            }
+        })
+        .await;
+        let client = StrikeClient::with_url("key", url).unwrap();
+
+        assert_eq!(
+            client.payment_status(&paid.payment_hash).await.unwrap(),
+            PaymentStatus::Succeeded {
+                preimage: None,
+                fee_msat: Some(3000),
+            }
+        );
+        let unknown = testing::invoice(Some(10_000));
+        assert!(matches!(
+            client.payment_status(&unknown.payment_hash).await,
+            Err(LightningError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn invoices_are_created_and_looked_up_through_receive_requests() {
+        let created = testing::invoice(Some(21_000));
+        let expired = build_invoice(InvoiceSpec {
+            created_at: std::time::SystemTime::now() - std::time::Duration::from_secs(7200),
+            ..Default::default()
+        });
+        let unpaid = testing::invoice(Some(21_000));
+        let url = serve({
+            let (created, expired, unpaid) = (created.clone(), expired.clone(), unpaid.clone());
+            move |request| {
+                if request.path == "/v1/receive-requests" {
+                    let body = request.json();
+                    assert_eq!(body["bolt11"]["amount"]["amount"], "0.00000021");
+                    assert_eq!(body["bolt11"]["expiryInSeconds"], 600);
+                    return respond(
+                        201,
+                        json!({
+                            "receiveRequestId": "paid",
+                            "bolt11": {
+                                "invoice": created.payment_request,
+                                "paymentHash": created.payment_hash,
+                            },
+                        })
+                        .to_string(),
+                    );
+                }
+                if let Some(request_id) = request
+                    .path
+                    .strip_prefix("/v1/receive-requests/receives?receiveRequestId=")
+                {
+                    let receives = match request_id {
+                        "paid" => json!({"items": [{"state": "COMPLETED"}]}),
+                        _ => json!({"items": []}),
+                    };
+                    return respond(200, receives.to_string());
+                }
+
+                let query = query(&request.path);
+                let requests = [
+                    ("paid", &created),
+                    ("expired", &expired),
+                    ("unpaid", &unpaid),
+                ]
+                .into_iter()
+                .filter(|(_, invoice)| query.contains(&invoice.payment_hash))
+                .map(|(id, invoice)| {
+                    json!({
+                        "receiveRequestId": id,
+                        "bolt11": {
+                            "invoice": invoice.payment_request,
+                            "paymentHash": invoice.payment_hash,
+                        },
+                    })
+                })
+                .collect::<Vec<_>>();
+                respond(200, json!({ "items": requests }).to_string())
+            }
+        })
+        .await;
+        let client = StrikeClient::with_url("key", url).unwrap();
+
+        let invoice = client
+            .create_invoice(&CreateInvoiceParams {
+                amount: 21,
+                unit: "sat".to_owned(),
+                memo: None,
+                expiry: Some(600),
+                webhook: None,
+                internal: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(invoice.payment_request, created.payment_request);
+        assert_eq!(hex::encode(invoice.payment_hash), created.payment_hash);
+
+        let cases = [
+            (&created, InvoiceStatus::Paid),
+            (&expired, InvoiceStatus::Expired),
+            (&unpaid, InvoiceStatus::Pending),
+        ];
+        for (invoice, expected) in cases {
+            assert_eq!(
+                client.invoice_status(&invoice.payment_hash).await.unwrap(),
+                expected
+            );
+        }
+        let unknown = testing::invoice(Some(10_000));
+        assert!(matches!(
+            client.invoice_status(&unknown.payment_hash).await,
+            Err(LightningError::NotFound)
+        ));
     }
 }
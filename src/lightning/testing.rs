@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, HeaderMap, Method, Response, Server, StatusCode};
+use lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixListener;
+use url::Url;
+
+use super::error::LightningError;
+use super::model::{
+    BalanceResult, CreateInvoiceParams, CreateInvoiceResult, InvoiceStatus, PayInvoiceResult,
+    PayOptions, PaymentStatus,
+};
+use super::utils::{decode_invoice, unix_timestamp};
+use super::Lightning;
+
+pub const PAYEE_KEY: [u8; 32] = [0x42; 32];
+
+/// A signed invoice along with the secrets a test needs to settle it
+#[derive(Debug, Clone)]
+pub struct TestInvoice {
+    pub payment_request: String,
+    pub payment_hash: String,
+    pub preimage: String,
+}
+
+pub struct InvoiceSpec {
+    pub currency: Currency,
+    pub amount_msat: Option<u64>,
+    pub created_at: SystemTime,
+    pub expiry: Duration,
+    pub payee_key: [u8; 32],
+}
+
+impl Default for InvoiceSpec {
+    fn default() -> Self {
+        Self {
+            currency: Currency::Bitcoin,
+            amount_msat: Some(10_000),
+            created_at: SystemTime::now(),
+            expiry: Duration::from_secs(3600),
+            payee_key: PAYEE_KEY,
+        }
+    }
+}
+
+/// Hex node id of the node signing with `payee_key`
+pub fn payee_pubkey(payee_key: [u8; 32]) -> String {
+    let key = SecretKey::from_slice(&payee_key).unwrap();
+    PublicKey::from_secret_key(&Secp256k1::new(), &key).to_string()
+}
+
+pub fn invoice(amount_msat: Option<u64>) -> TestInvoice {
+    build_invoice(InvoiceSpec {
+        amount_msat,
+        ..Default::default()
+    })
+}
+
+pub fn build_invoice(spec: InvoiceSpec) -> TestInvoice {
+    let preimage: [u8; 32] = secp256k1::rand::random();
+    let payment_hash = sha256::Hash::hash(&preimage);
+    let secp = Secp256k1::new();
+    let key = SecretKey::from_slice(&spec.payee_key).unwrap();
+
+    let builder = InvoiceBuilder::new(spec.currency)
+        .description("test".to_owned())
+        .payment_hash(payment_hash)
+        .payment_secret(PaymentSecret([7; 32]))
+        .timestamp(spec.created_at)
+        .expiry_time(spec.expiry)
+        .min_final_cltv_expiry_delta(18);
+    let builder = match spec.amount_msat {
+        Some(amount_msat) => builder.amount_milli_satoshis(amount_msat),
+        None => builder,
+    };
+    let invoice = builder
+        .build_signed(|hash| secp.sign_ecdsa_recoverable(hash, &key))
+        .unwrap();
+
+    TestInvoice {
+        payment_request: invoice.to_string(),
+        payment_hash: payment_hash.to_string(),
+        preimage: hex::encode(preimage),
+    }
+}
+
+/// What `FakeLightning` does when asked to pay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pay {
+    Succeed {
+        fee_msat: u64,
+    },
+    /// The payment fails and the backend records it as failed
+    Fail,
+    /// The call errors, e.g. a dropped connection, but the payment went
+    /// through
+    Lose,
+    /// The payment is sent but the call never returns
+    Hang,
+}
+
+/// A backend scripted by the test, which pays invoices it created with
+/// `invoice` instantly
+pub struct FakeLightning {
+    pub pay: Mutex<Pay>,
+    /// What `payment_status` reports, `NotFound` for hashes missing here
+    pub payments: Mutex<HashMap<String, PaymentStatus>>,
+    pub invoices: Mutex<HashMap<String, InvoiceStatus>>,
+    /// Payment hashes of every pay call that reached the backend
+    pub paid: Mutex<Vec<String>>,
+    /// Makes `payment_status` error as if the backend was unreachable
+    pub lookups_fail: Mutex<bool>,
+    preimages: Mutex<HashMap<String, String>>,
+}
+
+impl Default for FakeLightning {
+    fn default() -> Self {
+        Self {
+            pay: Mutex::new(Pay::Succeed { fee_msat: 0 }),
+            payments: Mutex::new(HashMap::new()),
+            invoices: Mutex::new(HashMap::new()),
+            paid: Mutex::new(vec![]),
+            lookups_fail: Mutex::new(false),
+            preimages: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl FakeLightning {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn paying(pay: Pay) -> Self {
+        let lightning = Self::default();
+        *lightning.pay.lock().unwrap() = pay;
+        lightning
+    }
+
+    /// An invoice this backend knows the preimage of
+    pub fn invoice(&self, amount_msat: Option<u64>) -> TestInvoice {
+        let invoice = invoice(amount_msat);
+        self.preimages
+            .lock()
+            .unwrap()
+            .insert(invoice.payment_hash.clone(), invoice.preimage.clone());
+        invoice
+    }
+
+    pub fn preimage(&self, payment_hash: &str) -> Option<String> {
+        self.preimages.lock().unwrap().get(payment_hash).cloned()
+    }
+
+    /// Makes paying `payment_hash` hand back `preimage`, e.g. a wrong one
+    pub fn set_preimage(&self, payment_hash: &str, preimage: &str) {
+        self.preimages
+            .lock()
+            .unwrap()
+            .insert(payment_hash.to_owned(), preimage.to_owned());
+    }
+
+    pub fn paid(&self) -> Vec<String> {
+        self.paid.lock().unwrap().clone()
+    }
+
+    pub fn set_payment(&self, payment_hash: &str, status: PaymentStatus) {
+        self.payments
+            .lock()
+            .unwrap()
+            .insert(payment_hash.to_owned(), status);
+    }
+}
+
+#[async_trait]
+impl Lightning for FakeLightning {
+    async fn pay_invoice_with_options(
+        &self,
+        payment_request: String,
+        options: PayOptions,
+    ) -> Result<PayInvoiceResult, anyhow::Error> {
+        let invoice = decode_invoice(&payment_request)?;
+        let payment_hash = invoice.payment_hash.clone();
+        let preimage = self.preimages.lock().unwrap().get(&payment_hash).cloned();
+        self.paid.lock().unwrap().push(payment_hash.clone());
+
+        let pay = *self.pay.lock().unwrap();
+        match pay {
+            Pay::Succeed { fee_msat } => {
+                self.set_payment(
+                    &payment_hash,
+                    PaymentStatus::Succeeded {
+                        preimage: preimage.clone(),
+                        fee_msat: Some(fee_msat),
+                    },
+                );
+                Ok(PayInvoiceResult {
+                    payment_hash,
+                    preimage,
+                    fee_msat: Some(fee_msat),
+                    amount_msat: options.amount_msat(&invoice.invoice),
+                    settled_at: unix_timestamp(),
+                })
+            }
+            Pay::Fail => {
+                self.set_payment(
+                    &payment_hash,
+                    PaymentStatus::Failed {
+                        reason: "no route".to_owned(),
+                    },
+                );
+                Err(anyhow::anyhow!("Failed to pay invoice: no route"))
+            }
+            Pay::Lose => {
+                self.set_payment(
+                    &payment_hash,
+                    PaymentStatus::Succeeded {
+                        preimage,
+                        fee_msat: Some(0),
+                    },
+                );
+                Err(anyhow::anyhow!("connection reset"))
+            }
+            Pay::Hang => {
+                self.set_payment(&payment_hash, PaymentStatus::Pending);
+                std::future::pending().await
+            }
+        }
+    }
+
+    async fn create_invoice(
+        &self,
+        params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        let invoice = self.invoice(Some(params.amount_msat()?));
+        self.invoices
+            .lock()
+            .unwrap()
+            .insert(invoice.payment_hash.clone(), InvoiceStatus::Pending);
+
+        Ok(CreateInvoiceResult {
+            payment_hash: hex::decode(&invoice.payment_hash)?,
+            payment_request: invoice.payment_request,
+        })
+    }
+
+    async fn invoice_status(&self, payment_hash: &str) -> Result<InvoiceStatus, anyhow::Error> {
+        Ok(self
+            .invoices
+            .lock()
+            .unwrap()
+            .get(payment_hash)
+            .copied()
+            .ok_or(LightningError::NotFound)?)
+    }
+
+    async fn payment_status(&self, payment_hash: &str) -> Result<PaymentStatus, anyhow::Error> {
+        if *self.lookups_fail.lock().unwrap() {
+            return Err(anyhow::anyhow!("backend unreachable"));
+        }
+        Ok(self
+            .payments
+            .lock()
+            .unwrap()
+            .get(payment_hash)
+            .cloned()
+            .ok_or(LightningError::NotFound)?)
+    }
+
+    async fn balance(&self) -> Result<BalanceResult, anyhow::Error> {
+        Ok(BalanceResult { balance_msat: 0 })
+    }
+}
+
+/// A request received by a `serve` handler
+#[derive(Debug, Clone)]
+pub struct FakeRequest {
+    pub method: Method,
+    /// Path and query
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl FakeRequest {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap_or_default()
+    }
+}
+
+pub fn respond(status: u16, body: impl Into<String>) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.into()));
+    *response.status_mut() = StatusCode::from_u16(status).unwrap();
+    response
+}
+
+/// Serves `handler` on a local port for the rest of the test, standing in
+/// for a backend's http api. Returns the base url.
+pub async fn serve<F>(handler: F) -> Url
+where
+    F: Fn(FakeRequest) -> Response<Body> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let make_service = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: hyper::Request<Body>| {
+                let handler = handler.clone();
+                async move {
+                    let (parts, body) = request.into_parts();
+                    let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+                    let request = FakeRequest {
+                        method: parts.method,
+                        path: parts
+                            .uri
+                            .path_and_query()
+                            .map_or_else(|| parts.uri.path().to_owned(), ToString::to_string),
+                        headers: parts.headers,
+                        body: String::from_utf8_lossy(&body).into_owned(),
+                    };
+                    Ok::<_, Infallible>(handler(request))
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let url = Url::parse(&format!("http://{}/", server.local_addr())).unwrap();
+    tokio::spawn(server);
+    url
+}
+
+/// Serves `handler` as a CLN JSON-RPC socket, called with the method and
+/// params of every request. Returns the socket path along with the directory
+/// holding it, which has to outlive the test.
+pub fn serve_cln<F>(handler: F) -> (TempDir, PathBuf)
+where
+    F: Fn(&str, Value) -> Result<Value, String> + Send + Sync + 'static,
+{
+    let dir = tempfile::tempdir().unwrap();
+    let rpc_path = dir.path().join("lightning-rpc");
+    let listener = UnixListener::bind(&rpc_path).unwrap();
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let mut buf = Vec::new();
+                loop {
+                    // requests and responses are separated by a blank line
+                    while let Some(end) = buf.windows(2).position(|window| window == b"\n\n") {
+                        let request: Value = serde_json::from_slice(&buf[..end]).unwrap();
+                        buf.drain(..end + 2);
+
+                        let method = request["method"].as_str().unwrap_or_default();
+                        let response = match handler(method, request["params"].clone()) {
+                            Ok(result) => {
+                                json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+                            }
+                            Err(message) => json!({
+                                "jsonrpc": "2.0",
+                                "id": request["id"],
+                                "error": {"code": -1, "message": message},
+                            }),
+                        };
+                        let response = format!("{response}\n\n");
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+
+                    let mut chunk = [0; 4096];
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => buf.extend_from_slice(&chunk[..read]),
+                    }
+                }
+            });
+        }
+    });
+
+    (dir, rpc_path)
+}
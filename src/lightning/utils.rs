@@ -1,5 +1,35 @@
-use lightning_invoice::{Bolt11Invoice, SignedRawBolt11Invoice};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn decode_invoice(payment_request: String) -> Bolt11Invoice {
-    Bolt11Invoice::from_signed(payment_request.parse::<SignedRawBolt11Invoice>().unwrap()).unwrap()
+use super::error::LightningError;
+use super::invoice::{DecodedInvoice, InvoiceError};
+
+pub fn decode_invoice(payment_request: &str) -> Result<DecodedInvoice, InvoiceError> {
+    DecodedInvoice::decode(payment_request)
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Converts an amount for backends that only take sats, erroring on sub-sat
+/// amounts rather than rounding them
+pub fn whole_sats(amount_msat: Option<u64>, backend: &str) -> Result<Option<u64>, LightningError> {
+    match amount_msat {
+        Some(amount_msat) if amount_msat % 1000 != 0 => Err(LightningError::InvalidAmount(
+            format!("{backend} only handles whole sats, got {amount_msat} msat"),
+        )),
+        amount_msat => Ok(amount_msat.map(|amount_msat| amount_msat / 1000)),
+    }
+}
+
+/// Whether a pay error rules out that the payment went through. Anything
+/// else, e.g. a dropped connection, may have paid.
+pub fn rejected_before_paying(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<InvoiceError>().is_some()
+        || err
+            .downcast_ref::<LightningError>()
+            .is_some_and(LightningError::is_rejected_before_paying)
 }
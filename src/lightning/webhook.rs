@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{info, warn};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use super::model::{CreateInvoiceParams, CreateInvoiceResult, InvoiceEvent, InvoiceStatus};
+use super::utils::{decode_invoice, unix_timestamp};
+use super::Lightning;
+
+const WEBHOOK_PATH: &str = "webhook/";
+/// Expiry assumed for invoices whose payment request we can't decode
+const DEFAULT_INVOICE_EXPIRY: u64 = 3600;
+/// How long after an invoice expires its webhook is still honored, for
+/// callbacks about a payment made just before that arrive late
+const EXPIRY_GRACE: u64 = 600;
+
+/// Receives invoice settlement callbacks, e.g. LNbits webhooks. Invoices
+/// created through it get a webhook url with an unguessable token, and every
+/// callback is checked against the backend before an `InvoiceEvent` is sent,
+/// so a forged callback can't mark an invoice paid. Stops listening when
+/// dropped.
+pub struct WebhookReceiver {
+    state: Arc<WebhookState>,
+    public_url: Url,
+    local_addr: SocketAddr,
+    server: JoinHandle<()>,
+}
+
+struct WebhookState {
+    lightning: Arc<dyn Lightning>,
+    // webhook token -> the invoice it was handed out for
+    invoices: Mutex<HashMap<String, Registration>>,
+    events: mpsc::UnboundedSender<InvoiceEvent>,
+}
+
+struct Registration {
+    payment_hash: String,
+    /// Unix timestamp, the entry is dropped a grace period after it
+    expires_at: u64,
+}
+
+impl WebhookReceiver {
+    /// Listens on `addr`. `public_url` is where the backend can reach the
+    /// listener, e.g. `https://example.com/bullpen/` behind a reverse proxy.
+    pub async fn bind(
+        addr: SocketAddr,
+        public_url: Url,
+        lightning: Arc<dyn Lightning>,
+    ) -> Result<(Self, mpsc::UnboundedReceiver<InvoiceEvent>), anyhow::Error> {
+        let (events, receiver) = mpsc::unbounded_channel();
+        let state = Arc::new(WebhookState {
+            lightning,
+            invoices: Mutex::new(HashMap::new()),
+            events,
+        });
+
+        let make_service = {
+            let state = state.clone();
+            make_service_fn(move |_| {
+                let state = state.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        let state = state.clone();
+                        async move { Ok::<_, Infallible>(state.handle(request).await) }
+                    }))
+                }
+            })
+        };
+        let server = Server::try_bind(&addr)?.serve(make_service);
+        let local_addr = server.local_addr();
+        info!("Listening for invoice webhooks on {local_addr}");
+        let server = tokio::spawn(async move {
+            if let Err(err) = server.await {
+                warn!("Invoice webhook listener stopped: {err}");
+            }
+        });
+
+        Ok((
+            Self {
+                state,
+                public_url,
+                local_addr,
+                server,
+            },
+            receiver,
+        ))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Creates the invoice with a webhook pointing back at this listener
+    pub async fn create_invoice(
+        &self,
+        mut params: CreateInvoiceParams,
+    ) -> Result<CreateInvoiceResult, anyhow::Error> {
+        let token = hex::encode(secp256k1::rand::random::<[u8; 16]>());
+        params.webhook = Some(self.webhook_url(&token)?.to_string());
+        let expiry = params.expiry.map_or(DEFAULT_INVOICE_EXPIRY, u64::from);
+
+        let invoice = self.state.lightning.create_invoice(params).await?;
+        let expires_at = decode_invoice(&invoice.payment_request)
+            .map_or(unix_timestamp() + expiry, |decoded| decoded.expires_at);
+        self.register(&token, &hex::encode(&invoice.payment_hash), expires_at);
+
+        Ok(invoice)
+    }
+
+    /// Registers an invoice created elsewhere, returning the webhook url to
+    /// hand to the backend
+    pub fn register_invoice(&self, payment_request: &str) -> Result<Url, anyhow::Error> {
+        let decoded = decode_invoice(payment_request)?;
+        let token = hex::encode(secp256k1::rand::random::<[u8; 16]>());
+        let url = self.webhook_url(&token)?;
+        self.register(&token, &decoded.payment_hash, decoded.expires_at);
+        Ok(url)
+    }
+
+    fn webhook_url(&self, token: &str) -> Result<Url, url::ParseError> {
+        self.public_url.join(&format!("{WEBHOOK_PATH}{token}"))
+    }
+
+    fn register(&self, token: &str, payment_hash: &str, expires_at: u64) {
+        let mut invoices = self.state.invoices.lock().unwrap();
+        prune_expired(&mut invoices);
+        invoices.insert(
+            token.to_owned(),
+            Registration {
+                payment_hash: payment_hash.to_lowercase(),
+                expires_at,
+            },
+        );
+    }
+}
+
+impl Drop for WebhookReceiver {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl WebhookState {
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        if request.method() != Method::POST {
+            return respond(StatusCode::METHOD_NOT_ALLOWED, "only POST is supported");
+        }
+        // match on the end of the path, a reverse proxy may have added a prefix
+        let token = match request
+            .uri()
+            .path()
+            .rsplit_once(&format!("/{WEBHOOK_PATH}"))
+        {
+            Some((_, token)) => token.to_owned(),
+            None => return respond(StatusCode::NOT_FOUND, "not found"),
+        };
+        let payment_hash = {
+            let mut invoices = self.invoices.lock().unwrap();
+            prune_expired(&mut invoices);
+            match invoices.get(&token) {
+                Some(registration) => registration.payment_hash.clone(),
+                None => return respond(StatusCode::NOT_FOUND, "unknown webhook"),
+            }
+        };
+
+        // the body is only sanity checked, the backend has the final say
+        let body = match hyper::body::to_bytes(request.into_body()).await {
+            Ok(body) => body,
+            Err(err) => return respond(StatusCode::BAD_REQUEST, &err.to_string()),
+        };
+        let claimed_hash = serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|body| body["payment_hash"].as_str().map(str::to_lowercase));
+        if claimed_hash.is_some_and(|claimed_hash| claimed_hash != payment_hash) {
+            return respond(StatusCode::BAD_REQUEST, "payment hash does not match");
+        }
+
+        let status = match self.lightning.invoice_status(&payment_hash).await {
+            Ok(status) => status,
+            Err(err) => {
+                warn!("Can not verify webhook for invoice {payment_hash}: {err}");
+                return respond(StatusCode::BAD_GATEWAY, "can not verify invoice");
+            }
+        };
+        if status == InvoiceStatus::Pending {
+            return respond(StatusCode::CONFLICT, "invoice is not settled");
+        }
+
+        // each invoice settles once, later callbacks for it are unknown
+        self.invoices.lock().unwrap().remove(&token);
+        if self
+            .events
+            .send(InvoiceEvent::new(&payment_hash, status))
+            .is_err()
+        {
+            warn!("Invoice event receiver dropped, discarding event for {payment_hash}");
+        }
+
+        respond(StatusCode::OK, "ok")
+    }
+}
+
+// Invoices that expired unpaid never get a callback, so they'd stay forever
+fn prune_expired(invoices: &mut HashMap<String, Registration>) {
+    let now = unix_timestamp();
+    invoices.retain(|_, registration| registration.expires_at + EXPIRY_GRACE > now);
+}
+
+fn respond(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(message.to_owned()));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::lightning::testing::{self, respond as reply, serve, InvoiceSpec};
+    use crate::lightning::LnbitsLightning;
+
+    struct FakeInvoice {
+        webhook: Option<String>,
+        paid: bool,
+    }
+
+    type Invoices = Arc<Mutex<HashMap<String, FakeInvoice>>>;
+
+    /// An LNbits wallet whose invoices are paid by setting `paid`
+    async fn fake_lnbits() -> (Arc<dyn Lightning>, Invoices) {
+        let invoices = Invoices::default();
+        let url = serve({
+            let invoices = invoices.clone();
+            move |request| {
+                if request.path == "/api/v1/payments" {
+                    let body = request.json();
+                    let invoice = testing::invoice(body["amount"].as_u64().map(|sat| sat * 1000));
+                    invoices.lock().unwrap().insert(
+                        invoice.payment_hash.clone(),
+                        FakeInvoice {
+                            webhook: body["webhook"].as_str().map(str::to_owned),
+                            paid: false,
+                        },
+                    );
+                    return reply(
+                        201,
+                        json!({
+                            "payment_hash": invoice.payment_hash,
+                            "payment_request": invoice.payment_request,
+                        })
+                        .to_string(),
+                    );
+                }
+                let payment_hash = request.path.trim_start_matches("/api/v1/payments/");
+                match invoices.lock().unwrap().get(payment_hash) {
+                    Some(invoice) => reply(
+                        200,
+                        json!({"paid": invoice.paid, "details": {"status": "pending"}}).to_string(),
+                    ),
+                    None => reply(404, r#"{"detail":"Payment does not exist."}"#),
+                }
+            }
+        })
+        .await;
+        let lightning = LnbitsLightning::new("key".to_owned(), url.to_string()).unwrap();
+        (Arc::new(lightning), invoices)
+    }
+
+    fn params() -> CreateInvoiceParams {
+        CreateInvoiceParams {
+            amount: 10,
+            unit: "sat".to_owned(),
+            memo: None,
+            expiry: None,
+            webhook: None,
+            internal: None,
+        }
+    }
+
+    /// Delivers a callback to `webhook` the way a reverse proxy in front of
+    /// the receiver would
+    async fn callback(
+        receiver: &WebhookReceiver,
+        webhook: &str,
+        body: serde_json::Value,
+    ) -> reqwest::StatusCode {
+        let path = Url::parse(webhook).unwrap().path().to_owned();
+        reqwest::Client::new()
+            .post(format!("http://{}{path}", receiver.local_addr()))
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+            .status()
+    }
+
+    async fn bind(
+        lightning: Arc<dyn Lightning>,
+    ) -> (WebhookReceiver, mpsc::UnboundedReceiver<InvoiceEvent>) {
+        WebhookReceiver::bind(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            Url::parse("https://example.com/bullpen/").unwrap(),
+            lightning,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn settled_invoice_callback_becomes_an_event() {
+        let (lightning, invoices) = fake_lnbits().await;
+        let (receiver, mut events) = bind(lightning).await;
+
+        let invoice = receiver.create_invoice(params()).await.unwrap();
+        let payment_hash = hex::encode(&invoice.payment_hash);
+        let webhook = invoices.lock().unwrap()[&payment_hash]
+            .webhook
+            .clone()
+            .unwrap();
+        assert!(webhook.starts_with("https://example.com/bullpen/webhook/"));
+
+        invoices
+            .lock()
+            .unwrap()
+            .get_mut(&payment_hash)
+            .unwrap()
+            .paid = true;
+        let body = json!({"payment_hash": payment_hash, "amount": 10000});
+        assert_eq!(
+            callback(&receiver, &webhook, body.clone()).await,
+            reqwest::StatusCode::OK
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            InvoiceEvent::new(&payment_hash, InvoiceStatus::Paid)
+        );
+
+        // the invoice settled already, a replayed callback is unknown
+        assert_eq!(
+            callback(&receiver, &webhook, body).await,
+            reqwest::StatusCode::NOT_FOUND
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn forged_callbacks_are_rejected() {
+        let (lightning, invoices) = fake_lnbits().await;
+        let (receiver, mut events) = bind(lightning).await;
+
+        let invoice = receiver.create_invoice(params()).await.unwrap();
+        let payment_hash = hex::encode(&invoice.payment_hash);
+        let webhook = invoices.lock().unwrap()[&payment_hash]
+            .webhook
+            .clone()
+            .unwrap();
+
+        // LNbits has not seen the invoice paid
+        assert_eq!(
+            callback(&receiver, &webhook, json!({"payment_hash": payment_hash})).await,
+            reqwest::StatusCode::CONFLICT
+        );
+        // a callback for another invoice
+        let other = testing::invoice(Some(10_000));
+        assert_eq!(
+            callback(
+                &receiver,
+                &webhook,
+                json!({"payment_hash": other.payment_hash})
+            )
+            .await,
+            reqwest::StatusCode::BAD_REQUEST
+        );
+        // a made up webhook token
+        assert_eq!(
+            callback(
+                &receiver,
+                "https://example.com/bullpen/webhook/00",
+                json!({"payment_hash": payment_hash})
+            )
+            .await,
+            reqwest::StatusCode::NOT_FOUND
+        );
+        assert!(events.try_recv().is_err());
+
+        // the genuine callback still goes through afterwards
+        invoices
+            .lock()
+            .unwrap()
+            .get_mut(&payment_hash)
+            .unwrap()
+            .paid = true;
+        assert_eq!(
+            callback(&receiver, &webhook, json!({"payment_hash": payment_hash})).await,
+            reqwest::StatusCode::OK
+        );
+        assert_eq!(events.recv().await.unwrap().status, InvoiceStatus::Paid);
+    }
+
+    #[tokio::test]
+    async fn unverifiable_callback_is_left_for_a_retry() {
+        let (lightning, invoices) = fake_lnbits().await;
+        let (receiver, mut events) = bind(lightning).await;
+
+        // registered for an invoice LNbits doesn't know
+        let other = testing::invoice(Some(10_000));
+        let webhook = receiver.register_invoice(&other.payment_request).unwrap();
+        assert_eq!(
+            callback(&receiver, webhook.as_str(), json!({})).await,
+            reqwest::StatusCode::BAD_GATEWAY
+        );
+        assert!(events.try_recv().is_err());
+        assert!(invoices.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_invoices_are_dropped() {
+        let (lightning, invoices) = fake_lnbits().await;
+        let (receiver, mut events) = bind(lightning).await;
+
+        // expired well over the grace period ago
+        let expired = testing::build_invoice(InvoiceSpec {
+            created_at: SystemTime::now() - Duration::from_secs(2 * 3600),
+            expiry: Duration::from_secs(60),
+            ..Default::default()
+        });
+        let webhook = receiver.register_invoice(&expired.payment_request).unwrap();
+        assert_eq!(receiver.state.invoices.lock().unwrap().len(), 1);
+
+        // registering another invoice prunes it
+        let invoice = receiver.create_invoice(params()).await.unwrap();
+        let payment_hash = hex::encode(&invoice.payment_hash);
+        {
+            let registered = receiver.state.invoices.lock().unwrap();
+            assert_eq!(registered.len(), 1);
+            assert!(registered
+                .values()
+                .all(|registration| registration.payment_hash == payment_hash));
+        }
+        assert_eq!(
+            callback(
+                &receiver,
+                webhook.as_str(),
+                json!({"payment_hash": expired.payment_hash})
+            )
+            .await,
+            reqwest::StatusCode::NOT_FOUND
+        );
+
+        // and so does a callback, even for another invoice
+        receiver.register_invoice(&expired.payment_request).unwrap();
+        assert_eq!(
+            callback(
+                &receiver,
+                "https://example.com/bullpen/webhook/00",
+                json!({})
+            )
+            .await,
+            reqwest::StatusCode::NOT_FOUND
+        );
+        assert_eq!(receiver.state.invoices.lock().unwrap().len(), 1);
+        assert!(events.try_recv().is_err());
+        assert_eq!(invoices.lock().unwrap().len(), 1);
+    }
+}
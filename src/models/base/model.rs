@@ -39,12 +39,18 @@ impl Model {
                 client: Box::new(ReplitClient::new()),
             })
         } else {
-            Ok(Self {
-                server_url: server_url
-                    .map_or_else(|| config.matador_url.clone(), ToString::to_string),
-                auth: Box::new(L402TokenManager),
-                client: Box::new(L402Client::new()),
-            })
+            Ok(Self::with_client(server_url, Box::new(L402Client::new()?)))
+        }
+    }
+
+    /// Sends requests through `client`, e.g. an `L402Client::with_lightning`
+    /// paying with a backend of the caller's choosing
+    pub fn with_client(server_url: Option<&str>, client: Box<dyn HttpClient>) -> Self {
+        let config = crate::config::get_config();
+        Self {
+            server_url: server_url.map_or_else(|| config.matador_url.clone(), ToString::to_string),
+            auth: Box::new(L402TokenManager),
+            client,
         }
     }
 
@@ -1,6 +1,7 @@
 use futures_util::stream::{self, StreamExt};
 use serde::{Deserialize, Serialize};
 
+use crate::http::HttpClient;
 use crate::models::base::structs::{Metadata, PinBoxStream, TokenCountMetadata};
 use crate::models::base::Model;
 use crate::models::chat::chat_model::ChatModelTrait;
@@ -16,9 +17,21 @@ pub struct PerplexityChatModel {
 
 impl PerplexityChatModel {
     pub fn new(model_name: &str, server_url: Option<&str>) -> Result<Self, anyhow::Error> {
-        let base = Model::new(server_url)?;
+        Ok(Self::with_base(model_name, Model::new(server_url)?))
+    }
+
+    /// Sends requests through `client` instead of the one `new` sets up
+    pub fn with_client(
+        model_name: &str,
+        server_url: Option<&str>,
+        client: Box<dyn HttpClient>,
+    ) -> Self {
+        Self::with_base(model_name, Model::with_client(server_url, client))
+    }
+
+    pub fn with_base(model_name: &str, base: Model) -> Self {
         let model_name = model_name.to_string();
-        Ok(PerplexityChatModel { base, model_name })
+        PerplexityChatModel { base, model_name }
     }
 
     pub fn build_request_payload(
@@ -116,7 +129,7 @@ impl ChatModelTrait for PerplexityChatModel {
             }
         };
 
-        let req = self
+        let req = match self
             .base
             .client // Use the client from base
             .post(&format!(
@@ -125,7 +138,10 @@ impl ChatModelTrait for PerplexityChatModel {
             ))
             .json(&payload)
             .build()
-            .unwrap();
+        {
+            Ok(req) => req,
+            Err(e) => return Box::pin(stream::once(async move { Err(e.into()) })),
+        };
 
         let res = self.base.client.execute_stream(req).await;
         Box::pin(res.map(|res| {
@@ -1,8 +1,9 @@
 use std::collections::HashMap;
 
-use futures_util::stream::StreamExt;
+use futures_util::stream::{self, StreamExt};
 use serde_json::Value;
 
+use crate::http::HttpClient;
 use crate::models::base::structs::PinBoxStream;
 use crate::models::base::Model;
 use crate::models::chat::chat_model::ChatModelTrait;
@@ -48,7 +49,7 @@ impl ChatModelTrait for ReplitChatModel {
     ) -> PinBoxStream<ChatModelResponse> {
         let payload = self.build_request_payload(&prompts, max_output_tokens, temperature);
 
-        let req = self
+        let req = match self
             .base
             .client // Use the client from base
             .post(&format!(
@@ -57,7 +58,10 @@ impl ChatModelTrait for ReplitChatModel {
             ))
             .json(&payload)
             .build()
-            .unwrap();
+        {
+            Ok(req) => req,
+            Err(e) => return Box::pin(stream::once(async move { Err(e.into()) })),
+        };
 
         let res = self.base.client.execute_stream(req).await; // Use the client from base
 
@@ -71,9 +75,21 @@ impl ChatModelTrait for ReplitChatModel {
 
 impl ReplitChatModel {
     pub fn new(model_name: &str, server_url: Option<&str>) -> Result<Self, anyhow::Error> {
-        let base = Model::new(server_url)?;
+        Ok(Self::with_base(model_name, Model::new(server_url)?))
+    }
+
+    /// Sends requests through `client` instead of the one `new` sets up
+    pub fn with_client(
+        model_name: &str,
+        server_url: Option<&str>,
+        client: Box<dyn HttpClient>,
+    ) -> Self {
+        Self::with_base(model_name, Model::with_client(server_url, client))
+    }
+
+    pub fn with_base(model_name: &str, base: Model) -> Self {
         let model_name = model_name.to_string();
-        Ok(ReplitChatModel { base, model_name })
+        ReplitChatModel { base, model_name }
     }
 
     pub fn build_request_payload(
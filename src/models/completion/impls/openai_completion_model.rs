@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 
-use futures_util::stream::StreamExt;
+use futures_util::stream::{self, StreamExt};
 use log::info;
 use serde_json::Value;
 
+use crate::http::HttpClient;
 use crate::models::base::structs::PinBoxStream;
 use crate::models::base::Model;
 use crate::models::completion::completion_model::CompletionModelTrait;
@@ -16,9 +17,21 @@ pub struct OpenAICompletionModel {
 
 impl OpenAICompletionModel {
     pub fn new(model_name: &str, server_url: Option<&str>) -> Result<Self, anyhow::Error> {
-        let base = Model::new(server_url)?;
+        Ok(Self::with_base(model_name, Model::new(server_url)?))
+    }
+
+    /// Sends requests through `client` instead of the one `new` sets up
+    pub fn with_client(
+        model_name: &str,
+        server_url: Option<&str>,
+        client: Box<dyn HttpClient>,
+    ) -> Self {
+        Self::with_base(model_name, Model::with_client(server_url, client))
+    }
+
+    pub fn with_base(model_name: &str, base: Model) -> Self {
         let model_name = model_name.to_string();
-        Ok(OpenAICompletionModel { base, model_name })
+        OpenAICompletionModel { base, model_name }
     }
 
     pub fn build_request_payload(
@@ -81,15 +94,18 @@ impl CompletionModelTrait for OpenAICompletionModel {
     ) -> PinBoxStream<CompletionModelResponse> {
         let payload = self.build_request_payload(&prompts, max_output_tokens, temperature);
 
-        let req = self
+        let req = match self
             .base
             .client
             .post(&format!("{}/v1beta/completion", &self.base.server_url))
             .json(&payload)
             .build()
-            .unwrap();
+        {
+            Ok(req) => req,
+            Err(e) => return Box::pin(stream::once(async move { Err(e.into()) })),
+        };
 
-        let res = self.base.client.execute(req).await.unwrap().bytes_stream();
+        let res = self.base.client.execute_stream(req).await;
 
         Box::pin(res.map(|res| {
             let res = res?;
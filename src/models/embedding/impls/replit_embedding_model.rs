@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 use serde_json::Value;
 
+use crate::http::HttpClient;
 use crate::models::base::Model;
 use crate::models::embedding::embedding_model::EmbeddingModelTrait;
 use crate::models::embedding::structs::EmbeddingModelResponse;
@@ -13,9 +14,21 @@ pub struct ReplitEmbeddingModel {
 
 impl ReplitEmbeddingModel {
     pub fn new(model_name: &str, server_url: Option<&str>) -> Result<Self, anyhow::Error> {
-        let base = Model::new(server_url)?;
+        Ok(Self::with_base(model_name, Model::new(server_url)?))
+    }
+
+    /// Sends requests through `client` instead of the one `new` sets up
+    pub fn with_client(
+        model_name: &str,
+        server_url: Option<&str>,
+        client: Box<dyn HttpClient>,
+    ) -> Self {
+        Self::with_base(model_name, Model::with_client(server_url, client))
+    }
+
+    pub fn with_base(model_name: &str, base: Model) -> Self {
         let model_name = model_name.to_string();
-        Ok(ReplitEmbeddingModel { base, model_name })
+        ReplitEmbeddingModel { base, model_name }
     }
 
     fn build_request_payload(
@@ -0,0 +1,31 @@
+use crate::http::MacaroonError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum L402ServerError {
+    #[error("missing L402 Authorization header")]
+    MissingAuthorization,
+
+    #[error("malformed L402 Authorization header")]
+    MalformedAuthorization,
+
+    #[error("invalid macaroon: {0}")]
+    InvalidMacaroon(#[from] MacaroonError),
+
+    #[error("macaroon signature does not verify")]
+    InvalidSignature,
+
+    #[error("macaroon identifier is not an L402 identifier")]
+    InvalidIdentifier,
+
+    #[error("preimage does not match the macaroon payment hash")]
+    InvalidPreimage,
+
+    #[error("unsupported caveat {0}")]
+    UnsupportedCaveat(String),
+
+    #[error("caveat {0} is not satisfied")]
+    CaveatNotSatisfied(String),
+
+    #[error("token was already used")]
+    TokenAlreadyUsed,
+}
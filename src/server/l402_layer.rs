@@ -0,0 +1,283 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use http::{HeaderValue, Request, Response, StatusCode};
+use log::{info, warn};
+use tower::{Layer, Service};
+
+use super::error::L402ServerError;
+use super::minter::{has_path_prefix, L402Minter};
+
+/// Prices in sats for path prefixes, matched on whole path segments. The
+/// longest matching prefix wins, paths without a price are served for free.
+#[derive(Debug, Clone, Default)]
+pub struct RoutePricing {
+    routes: Vec<(String, u64)>,
+}
+
+impl RoutePricing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_route(mut self, path_prefix: &str, amount: u64) -> Self {
+        self.routes.push((path_prefix.to_owned(), amount));
+        self
+    }
+
+    pub fn price(&self, path: &str) -> Option<(&str, u64)> {
+        self.routes
+            .iter()
+            .filter(|(path_prefix, _)| has_path_prefix(path, path_prefix))
+            .max_by_key(|(path_prefix, _)| path_prefix.len())
+            .map(|(path_prefix, amount)| (path_prefix.as_str(), *amount))
+    }
+}
+
+/// Tower layer charging for routes with L402, e.g.
+/// `Router::new().route(...).layer(L402Layer::new(minter, pricing))` in axum
+#[derive(Clone)]
+pub struct L402Layer {
+    minter: Arc<L402Minter>,
+    pricing: Arc<RoutePricing>,
+}
+
+impl L402Layer {
+    pub fn new(minter: L402Minter, pricing: RoutePricing) -> Self {
+        Self {
+            minter: Arc::new(minter),
+            pricing: Arc::new(pricing),
+        }
+    }
+}
+
+impl<S> Layer<S> for L402Layer {
+    type Service = L402Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        L402Service {
+            inner,
+            minter: self.minter.clone(),
+            pricing: self.pricing.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct L402Service<S> {
+    inner: S,
+    minter: Arc<L402Minter>,
+    pricing: Arc<RoutePricing>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for L402Service<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    ResBody: Default + Send + 'static,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let path = request.uri().path();
+        let Some((route, amount)) = self.pricing.price(path) else {
+            return Box::pin(self.inner.call(request));
+        };
+
+        if let Some(authorization) = request.headers().get(AUTHORIZATION) {
+            match authorization
+                .to_str()
+                .map_err(|_| L402ServerError::MalformedAuthorization)
+                .and_then(|authorization| self.minter.verify(authorization, route, path))
+            {
+                Ok(_) => return Box::pin(self.inner.call(request)),
+                // a genuine token that doesn't cover this call, sell another
+                Err(
+                    err @ (L402ServerError::MissingAuthorization
+                    | L402ServerError::CaveatNotSatisfied(_)
+                    | L402ServerError::TokenAlreadyUsed),
+                ) => info!("Rejected L402 token for {path}: {err}"),
+                Err(err) => {
+                    info!("Rejected L402 token for {path}: {err}");
+                    return Box::pin(async { Ok(status_response(StatusCode::UNAUTHORIZED)) });
+                }
+            }
+        }
+
+        let minter = self.minter.clone();
+        let route = route.to_owned();
+        Box::pin(async move {
+            let challenge = match minter.mint(amount, &route).await {
+                Ok(challenge) => challenge,
+                Err(err) => {
+                    warn!("Can not mint L402 challenge: {err}");
+                    return Ok(status_response(StatusCode::INTERNAL_SERVER_ERROR));
+                }
+            };
+
+            let mut response = status_response(StatusCode::PAYMENT_REQUIRED);
+            match HeaderValue::from_str(&challenge.www_authenticate()) {
+                Ok(header) => {
+                    response.headers_mut().insert(WWW_AUTHENTICATE, header);
+                    Ok(response)
+                }
+                Err(err) => {
+                    warn!("Can not encode L402 challenge: {err}");
+                    Ok(status_response(StatusCode::INTERNAL_SERVER_ERROR))
+                }
+            }
+        })
+    }
+}
+
+fn status_response<B: Default>(status: StatusCode) -> Response<B> {
+    let mut response = Response::new(B::default());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::http::L402Challenge;
+    use crate::lightning::testing::FakeLightning;
+
+    type Body = String;
+
+    fn service(
+        lightning: &Arc<FakeLightning>,
+    ) -> impl Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone {
+        let minter = L402Minter::new(b"root key".to_vec(), "bullpen", lightning.clone());
+        let pricing = RoutePricing::new()
+            .with_route("/v1", 10)
+            .with_route("/v1/images", 100);
+        L402Layer::new(minter, pricing).layer(service_fn(|request: Request<Body>| async move {
+            Ok::<_, Infallible>(Response::new(format!("served {}", request.uri().path())))
+        }))
+    }
+
+    fn request(path: &str, authorization: Option<&str>) -> Request<Body> {
+        let mut request = Request::get(path);
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        request.body(Body::new()).unwrap()
+    }
+
+    // Buys a token for `path`, returning the paid `Authorization` header
+    async fn buy(
+        service: impl Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+        lightning: &FakeLightning,
+        path: &str,
+    ) -> String {
+        let response = service.oneshot(request(path, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        let header = response.headers()[WWW_AUTHENTICATE].to_str().unwrap();
+        let challenge = L402Challenge::parse(header).unwrap();
+        let payment_hash = challenge.invoice.payment_hash().to_string();
+        let preimage = lightning.preimage(&payment_hash).unwrap();
+        format!("L402 {}:{preimage}", challenge.macaroon)
+    }
+
+    #[tokio::test]
+    async fn challenges_priced_routes() {
+        let lightning = Arc::new(FakeLightning::new());
+        let response = service(&lightning)
+            .oneshot(request("/v1/images/new", None))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        let header = response.headers()[WWW_AUTHENTICATE].to_str().unwrap();
+        assert!(header.starts_with("L402 macaroon=\""), "{header}");
+        assert!(header.contains(", invoice=\"lnbc"), "{header}");
+        let challenge = L402Challenge::parse(header).unwrap();
+        assert_eq!(challenge.invoice.amount_milli_satoshis(), Some(100_000));
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn serves_unpriced_routes() {
+        let lightning = Arc::new(FakeLightning::new());
+        // `/v1beta` is not under `/v1`
+        for path in ["/", "/health", "/v1beta/chat"] {
+            let response = service(&lightning)
+                .oneshot(request(path, None))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{path}");
+            assert_eq!(response.body(), &format!("served {path}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_a_paid_call_once() {
+        let lightning = Arc::new(FakeLightning::new());
+        let service = service(&lightning);
+        let authorization = buy(service.clone(), &lightning, "/v1/chat").await;
+
+        let response = service
+            .clone()
+            .oneshot(request("/v1/chat", Some(&authorization)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), "served /v1/chat");
+
+        // the same token again is charged again
+        let response = service
+            .oneshot(request("/v1/chat", Some(&authorization)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_bad_preimage() {
+        let lightning = Arc::new(FakeLightning::new());
+        let service = service(&lightning);
+        let authorization = buy(service.clone(), &lightning, "/v1/chat").await;
+        let (macaroon, _) = authorization.split_once(':').unwrap();
+
+        let response = service
+            .oneshot(request(
+                "/v1/chat",
+                Some(&format!("{macaroon}:{}", "00".repeat(32))),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_token_bought_for_another_route() {
+        let lightning = Arc::new(FakeLightning::new());
+        let service = service(&lightning);
+        let authorization = buy(service.clone(), &lightning, "/v1/chat").await;
+
+        // the cheaper `/v1` route's token doesn't unlock `/v1/images`
+        let response = service
+            .oneshot(request("/v1/images", Some(&authorization)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        let header = response.headers()[WWW_AUTHENTICATE].to_str().unwrap();
+        let challenge = L402Challenge::parse(header).unwrap();
+        assert_eq!(challenge.invoice.amount_milli_satoshis(), Some(100_000));
+    }
+}
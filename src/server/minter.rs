@@ -0,0 +1,441 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use super::error::L402ServerError;
+use crate::http::Macaroon;
+use crate::lightning::model::CreateInvoiceParams;
+use crate::lightning::utils::unix_timestamp;
+use crate::lightning::Lightning;
+
+/// Caveat binding a token to the priced route it was bought for, so a cheap
+/// route's token doesn't unlock a pricier route nested under it
+const ROUTE_CAVEAT: &str = "route";
+/// Caveat clients can add to restrict a token to some paths
+const PATH_PREFIX_CAVEAT: &str = "path_prefix";
+
+/// A macaroon bound to an unpaid invoice, sent to the client in a 402
+#[derive(Debug, Clone)]
+pub struct MintedChallenge {
+    pub macaroon: Macaroon,
+    pub invoice: String,
+}
+
+impl MintedChallenge {
+    pub fn www_authenticate(&self) -> String {
+        format!(
+            "L402 macaroon=\"{}\", invoice=\"{}\"",
+            self.macaroon.encode(),
+            self.invoice
+        )
+    }
+}
+
+/// Mints L402 macaroons for our own endpoints and verifies the tokens clients
+/// send back. The macaroon identifier commits to the invoice payment hash, so
+/// a matching preimage proves payment. Tokens are single use: each paid
+/// invoice buys one call, a token sent again is charged again.
+pub struct L402Minter {
+    root_key: Vec<u8>,
+    service: String,
+    lightning: Arc<dyn Lightning>,
+    token_lifetime: Option<Duration>,
+    invoice_expiry: u32,
+    // payment hashes of tokens already used
+    spent: Mutex<HashSet<[u8; 32]>>,
+}
+
+impl L402Minter {
+    pub fn new(root_key: impl Into<Vec<u8>>, service: &str, lightning: Arc<dyn Lightning>) -> Self {
+        Self {
+            root_key: root_key.into(),
+            service: service.to_owned(),
+            lightning,
+            token_lifetime: None,
+            invoice_expiry: 3600,
+            spent: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Adds a `<service>_valid_until` caveat to minted macaroons
+    pub fn with_token_lifetime(mut self, token_lifetime: Duration) -> Self {
+        self.token_lifetime = Some(token_lifetime);
+        self
+    }
+
+    pub fn with_invoice_expiry(mut self, invoice_expiry: Duration) -> Self {
+        self.invoice_expiry = invoice_expiry.as_secs().try_into().unwrap_or(u32::MAX);
+        self
+    }
+
+    fn valid_until_caveat(&self) -> String {
+        format!("{}_valid_until", self.service)
+    }
+
+    /// Creates an invoice for `amount` sats and a macaroon for it, restricted
+    /// to the route priced at `route`
+    pub async fn mint(&self, amount: u64, route: &str) -> Result<MintedChallenge, anyhow::Error> {
+        let invoice = self
+            .lightning
+            .create_invoice(CreateInvoiceParams {
+                amount,
+                unit: "sat".to_owned(),
+                memo: Some(format!("{} {}", self.service, route)),
+                expiry: Some(self.invoice_expiry),
+                webhook: None,
+                internal: None,
+            })
+            .await?;
+        if invoice.payment_hash.len() != 32 {
+            anyhow::bail!(
+                "Backend returned a {} byte payment hash",
+                invoice.payment_hash.len()
+            );
+        }
+
+        // version 0 identifier: u16 version, payment hash, token id
+        let mut identifier = vec![0, 0];
+        identifier.extend_from_slice(&invoice.payment_hash);
+        identifier.extend_from_slice(&secp256k1::rand::random::<[u8; 32]>());
+
+        let mut macaroon = Macaroon::new(&self.root_key, identifier, Some(self.service.clone()));
+        macaroon.add_first_party_caveat(&format!("{ROUTE_CAVEAT}={route}"));
+        if let Some(token_lifetime) = self.token_lifetime {
+            macaroon.add_first_party_caveat(&format!(
+                "{}={}",
+                self.valid_until_caveat(),
+                unix_timestamp() + token_lifetime.as_secs()
+            ));
+        }
+
+        Ok(MintedChallenge {
+            macaroon,
+            invoice: invoice.payment_request,
+        })
+    }
+
+    /// Verifies an `Authorization: L402 <macaroon>:<preimage>` header value
+    /// for a request to `path` under the priced `route`, returning the macaroon
+    /// and marking the token used
+    pub fn verify(
+        &self,
+        authorization: &str,
+        route: &str,
+        path: &str,
+    ) -> Result<Macaroon, L402ServerError> {
+        let (scheme, credentials) = authorization
+            .trim()
+            .split_once(' ')
+            .ok_or(L402ServerError::MalformedAuthorization)?;
+        if !scheme.eq_ignore_ascii_case("L402") && !scheme.eq_ignore_ascii_case("LSAT") {
+            return Err(L402ServerError::MissingAuthorization);
+        }
+        let (macaroon, preimage) = credentials
+            .trim()
+            .split_once(':')
+            .ok_or(L402ServerError::MalformedAuthorization)?;
+
+        let macaroon = Macaroon::decode(macaroon)?;
+        if !macaroon.verify_signature(&self.root_key) {
+            return Err(L402ServerError::InvalidSignature);
+        }
+
+        let payment_hash = macaroon
+            .payment_hash()
+            .ok_or(L402ServerError::InvalidIdentifier)?;
+        let preimage = hex::decode(preimage).map_err(|_| L402ServerError::InvalidPreimage)?;
+        if Sha256::digest(preimage).as_slice() != payment_hash {
+            return Err(L402ServerError::InvalidPreimage);
+        }
+
+        self.check_caveats(&macaroon, route, path)?;
+
+        if !self.spent.lock().unwrap().insert(payment_hash) {
+            return Err(L402ServerError::TokenAlreadyUsed);
+        }
+
+        Ok(macaroon)
+    }
+
+    // Every caveat has to hold, including any the client added. Caveats we
+    // don't understand fail closed.
+    fn check_caveats(
+        &self,
+        macaroon: &Macaroon,
+        route: &str,
+        path: &str,
+    ) -> Result<(), L402ServerError> {
+        let valid_until_caveat = self.valid_until_caveat();
+        let now = unix_timestamp();
+
+        for caveat in &macaroon.caveats {
+            let condition = caveat.condition().unwrap_or_default().to_owned();
+            let (key, value) = caveat
+                .key_value()
+                .ok_or_else(|| L402ServerError::UnsupportedCaveat(condition.clone()))?;
+
+            let satisfied = if key == ROUTE_CAVEAT {
+                value == route
+            } else if key == PATH_PREFIX_CAVEAT {
+                has_path_prefix(path, value)
+            } else if key == valid_until_caveat {
+                value
+                    .parse::<u64>()
+                    .is_ok_and(|valid_until| now < valid_until)
+            } else {
+                return Err(L402ServerError::UnsupportedCaveat(condition));
+            };
+
+            if !satisfied {
+                return Err(L402ServerError::CaveatNotSatisfied(condition));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `path` is `prefix` or below it, so `/v1` covers `/v1/chat` but not
+/// `/v1beta`
+pub(crate) fn has_path_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::L402Challenge;
+    use crate::lightning::testing::FakeLightning;
+
+    const ROOT_KEY: &[u8] = b"root key";
+
+    async fn minted(minter: &L402Minter, lightning: &FakeLightning) -> (Macaroon, String) {
+        let challenge = minter.mint(10, "/v1/chat").await.unwrap();
+        let payment_hash = hex::encode(challenge.macaroon.payment_hash().unwrap());
+        let preimage = lightning.preimage(&payment_hash).unwrap();
+        (challenge.macaroon, preimage)
+    }
+
+    fn minter(lightning: &Arc<FakeLightning>) -> L402Minter {
+        L402Minter::new(ROOT_KEY, "bullpen", lightning.clone())
+    }
+
+    #[tokio::test]
+    async fn challenge_is_a_parseable_l402_challenge() {
+        let lightning = Arc::new(FakeLightning::new());
+        let challenge = minter(&lightning).mint(10, "/v1/chat").await.unwrap();
+
+        let parsed = L402Challenge::parse(&challenge.www_authenticate()).unwrap();
+        assert_eq!(parsed.macaroon, challenge.macaroon.encode());
+        assert_eq!(parsed.invoice.to_string(), challenge.invoice);
+        assert_eq!(parsed.invoice.amount_milli_satoshis(), Some(10_000));
+        assert_eq!(
+            parsed.invoice.payment_hash().to_string(),
+            hex::encode(challenge.macaroon.payment_hash().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_paid_token() {
+        let lightning = Arc::new(FakeLightning::new());
+        let minter = minter(&lightning);
+        let (macaroon, preimage) = minted(&minter, &lightning).await;
+
+        let verified = minter
+            .verify(
+                &format!("L402 {}:{preimage}", macaroon.encode()),
+                "/v1/chat",
+                "/v1/chat/completions",
+            )
+            .unwrap();
+        assert_eq!(verified, macaroon);
+
+        let (macaroon, preimage) = minted(&minter, &lightning).await;
+        minter
+            .verify(
+                &format!("LSAT {}:{preimage}", macaroon.encode()),
+                "/v1/chat",
+                "/v1/chat",
+            )
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_a_used_token() {
+        let lightning = Arc::new(FakeLightning::new());
+        let minter = minter(&lightning);
+        let (macaroon, preimage) = minted(&minter, &lightning).await;
+        let authorization = format!("L402 {}:{preimage}", macaroon.encode());
+
+        minter
+            .verify(&authorization, "/v1/chat", "/v1/chat")
+            .unwrap();
+        let err = minter
+            .verify(&authorization, "/v1/chat", "/v1/chat")
+            .unwrap_err();
+        assert!(matches!(err, L402ServerError::TokenAlreadyUsed), "{err}");
+
+        // a client adding caveats doesn't make it a new token
+        let mut restricted = macaroon.clone();
+        restricted.add_first_party_caveat("path_prefix=/v1/chat");
+        let err = minter
+            .verify(
+                &format!("L402 {}:{preimage}", restricted.encode()),
+                "/v1/chat",
+                "/v1/chat",
+            )
+            .unwrap_err();
+        assert!(matches!(err, L402ServerError::TokenAlreadyUsed), "{err}");
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_preimage() {
+        let lightning = Arc::new(FakeLightning::new());
+        let minter = minter(&lightning);
+        let (macaroon, preimage) = minted(&minter, &lightning).await;
+        let (other, _) = minted(&minter, &lightning).await;
+
+        for preimage in ["00".repeat(32), "not hex".to_owned(), String::new()] {
+            let err = minter
+                .verify(
+                    &format!("L402 {}:{preimage}", macaroon.encode()),
+                    "/v1/chat",
+                    "/v1/chat",
+                )
+                .unwrap_err();
+            assert!(matches!(err, L402ServerError::InvalidPreimage), "{err}");
+        }
+        // a preimage paid for another macaroon
+        let err = minter
+            .verify(
+                &format!("L402 {}:{preimage}", other.encode()),
+                "/v1/chat",
+                "/v1/chat",
+            )
+            .unwrap_err();
+        assert!(matches!(err, L402ServerError::InvalidPreimage), "{err}");
+    }
+
+    #[tokio::test]
+    async fn rejects_failed_caveats() {
+        let lightning = Arc::new(FakeLightning::new());
+        let minter = minter(&lightning);
+        let (macaroon, preimage) = minted(&minter, &lightning).await;
+        let verify = |macaroon: &Macaroon, route: &str, path: &str| {
+            minter.verify(
+                &format!("L402 {}:{preimage}", macaroon.encode()),
+                route,
+                path,
+            )
+        };
+
+        // bought for another route
+        let err = verify(&macaroon, "/v1/images", "/v1/images").unwrap_err();
+        assert!(
+            matches!(&err, L402ServerError::CaveatNotSatisfied(caveat) if caveat == "route=/v1/chat"),
+            "{err}"
+        );
+
+        // restricted by the client to a path prefix
+        let mut restricted = macaroon.clone();
+        restricted.add_first_party_caveat("path_prefix=/v1/chat/completions");
+        verify(&restricted, "/v1/chat", "/v1/chat/completions").unwrap();
+        let err = verify(&restricted, "/v1/chat", "/v1/chat/history").unwrap_err();
+        assert!(
+            matches!(err, L402ServerError::CaveatNotSatisfied(_)),
+            "{err}"
+        );
+        let err = verify(&restricted, "/v1/chat", "/v1/chat/completions2").unwrap_err();
+        assert!(
+            matches!(err, L402ServerError::CaveatNotSatisfied(_)),
+            "{err}"
+        );
+
+        let mut expired = macaroon.clone();
+        expired.add_first_party_caveat("bullpen_valid_until=1");
+        let err = verify(&expired, "/v1/chat", "/v1/chat").unwrap_err();
+        assert!(
+            matches!(err, L402ServerError::CaveatNotSatisfied(_)),
+            "{err}"
+        );
+
+        let mut unknown = macaroon.clone();
+        unknown.add_first_party_caveat("ipaddr=10.0.0.1");
+        let err = verify(&unknown, "/v1/chat", "/v1/chat").unwrap_err();
+        assert!(
+            matches!(err, L402ServerError::UnsupportedCaveat(_)),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_token_lifetime() {
+        let lightning = Arc::new(FakeLightning::new());
+        let minter = minter(&lightning).with_token_lifetime(Duration::ZERO);
+        let (macaroon, preimage) = minted(&minter, &lightning).await;
+
+        let err = minter
+            .verify(
+                &format!("L402 {}:{preimage}", macaroon.encode()),
+                "/v1/chat",
+                "/v1/chat",
+            )
+            .unwrap_err();
+        assert!(
+            matches!(err, L402ServerError::CaveatNotSatisfied(_)),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_forged_and_malformed_tokens() {
+        let lightning = Arc::new(FakeLightning::new());
+        let minter = minter(&lightning);
+        let (macaroon, preimage) = minted(&minter, &lightning).await;
+
+        // minted with another root key
+        let forger = L402Minter::new(b"other key".to_vec(), "bullpen", lightning.clone());
+        let (forged, forged_preimage) = minted(&forger, &lightning).await;
+        let err = minter
+            .verify(
+                &format!("L402 {}:{forged_preimage}", forged.encode()),
+                "/v1/chat",
+                "/v1/chat",
+            )
+            .unwrap_err();
+        assert!(matches!(err, L402ServerError::InvalidSignature), "{err}");
+
+        // a caveat stripped off
+        let mut stripped = macaroon.clone();
+        stripped.caveats.clear();
+        let err = minter
+            .verify(
+                &format!("L402 {}:{preimage}", stripped.encode()),
+                "/v1/images",
+                "/v1/images",
+            )
+            .unwrap_err();
+        assert!(matches!(err, L402ServerError::InvalidSignature), "{err}");
+
+        let cases = [
+            (format!("Bearer {}", macaroon.encode()), "missing"),
+            (format!("L402 {}", macaroon.encode()), "malformed"),
+            ("L402".to_owned(), "malformed"),
+            (format!("L402 !!!:{preimage}"), "macaroon"),
+        ];
+        for (authorization, expected) in cases {
+            let err = minter
+                .verify(&authorization, "/v1/chat", "/v1/chat")
+                .unwrap_err();
+            let matched = match expected {
+                "missing" => matches!(err, L402ServerError::MissingAuthorization),
+                "malformed" => matches!(err, L402ServerError::MalformedAuthorization),
+                _ => matches!(err, L402ServerError::InvalidMacaroon(_)),
+            };
+            assert!(matched, "{authorization}: {err}");
+        }
+    }
+}
@@ -0,0 +1,7 @@
+pub mod error;
+pub mod l402_layer;
+pub mod minter;
+
+pub use error::L402ServerError;
+pub use l402_layer::{L402Layer, L402Service, RoutePricing};
+pub use minter::{L402Minter, MintedChallenge};